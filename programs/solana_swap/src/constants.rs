@@ -1 +1,25 @@
 pub const POOL_SEED: &[u8] = b"pool";
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const SWAP_VAULT_SEED: &[u8] = b"swap_vault";
+pub const GASLESS_VAULT_SEED: &[u8] = b"gasless_vault";
+pub const TREASURY_SEED: &[u8] = b"treasury";
+pub const TICK_ARRAY_SEED: &[u8] = b"tick_array";
+pub const POSITION_SEED: &[u8] = b"position";
+pub const POSITION_MINT_SEED: &[u8] = b"position_mint";
+pub const RANGE_ORDER_SEED: &[u8] = b"range_order";
+pub const ORDER_SEED: &[u8] = b"order";
+pub const ORDER_VAULT_SEED: &[u8] = b"order_vault";
+pub const DCA_SEED: &[u8] = b"dca";
+pub const DCA_VAULT_SEED: &[u8] = b"dca_vault";
+pub const LONG_TERM_ORDER_SEED: &[u8] = b"long_term_order";
+pub const RFQ_NONCE_SEED: &[u8] = b"rfq_nonce";
+pub const RFQ_AUTHORITY_SEED: &[u8] = b"rfq_authority";
+pub const LP_SHARES_SEED: &[u8] = b"lp_shares";
+pub const FARM_SEED: &[u8] = b"farm";
+pub const FARM_REWARD_VAULT_SEED: &[u8] = b"farm_reward_vault";
+
+pub const BPS_DENOMINATOR: u64 = 10_000; // 100% expressed in basis points
+pub const TWAMM_INTERVAL_SLOTS: u64 = 150; // Long-term orders end on multiples of this many slots
+pub const MAX_LOCK_SECONDS: i64 = 365 * 24 * 60 * 60; // Longest liquidity lock
+pub const MAX_LOCK_BOOST_BPS: u64 = 15_000; // Extra farm weight of shares locked for the longest time
+pub const EARLY_UNLOCK_PENALTY_BPS: u64 = 2_000; // Shares burned when leaving a lock right away
@@ -1,30 +1,657 @@
+use crate::constants::BPS_DENOMINATOR;
 use crate::errors::SwapError;
 
+// Constants
+const FEE: u64 = 3; // 0.3% fee as 3 parts of 1000
+const FEE_DENOMINATOR: u64 = 1000;
+const PMM_PRECISION: u128 = 1_000_000_000_000; // Fixed point scale of the PMM slippage factor
+pub const FLAT_FEE_BPS: u16 = (FEE * BPS_DENOMINATOR / FEE_DENOMINATOR) as u16;
+
 pub fn calculate_swap_amount(
     input_amount: u64,
     input_reserve: u64,
     output_reserve: u64,
 ) -> Result<(u64, u64), SwapError> {
-    // Constants
-    const FEE: u64 = 3; // 0.3% fee as 3 parts of 1000
-    const FEE_DENOMINATOR: u64 = 1000;
+    calculate_swap_amount_with_fee(input_amount, input_reserve, output_reserve, FLAT_FEE_BPS)
+}
 
+pub fn calculate_swap_amount_with_fee(
+    input_amount: u64,
+    input_reserve: u64,
+    output_reserve: u64,
+    fee_bps: u16,
+) -> Result<(u64, u64), SwapError> {
     // Calculate the fee
-    let fee_amount = input_amount * FEE / FEE_DENOMINATOR;
+    let fee_amount = calculate_swap_fee(input_amount, fee_bps)?;
 
     // Calculate the input amount after applying the fee
     let input_amount_with_fee = input_amount - fee_amount;
 
     // Calculate the new reserves after adding the input amount
-    let new_input_reserve = input_reserve + input_amount_with_fee;
+    let new_input_reserve = input_reserve
+        .checked_add(input_amount_with_fee)
+        .ok_or(SwapError::MathError)?;
 
-    // Calculate the output amount based on the constant product formula
-    let output_amount = output_reserve
-        .checked_mul(input_reserve)
+    // Calculate the output amount based on the constant product formula,
+    // x * y = (x + dx) * (y - dy) so dy = y * dx / (x + dx)
+    let output_amount = (output_reserve as u128)
+        .checked_mul(input_amount_with_fee as u128)
         .ok_or(SwapError::MathError)?
-        .checked_div(new_input_reserve)
+        .checked_div(new_input_reserve as u128)
         .ok_or(SwapError::MathError)?;
+    let output_amount = u64::try_from(output_amount).map_err(|_| SwapError::MathError)?;
 
     // Return both the output amount and the fee
     Ok((output_amount, fee_amount))
 }
+
+// Input the PMM curve needs to release output_amount, given the oracle price as
+// input per output unit. The price starts at the oracle price and slips by
+// (1 - k + k * target^2 / (reserve * (reserve - output))) as the output side
+// drains below its target, mirroring DODO's general integral.
+fn pmm_input_for_output(
+    output_amount: u64,
+    output_reserve: u64,
+    output_target: u64,
+    price_numerator: u128,
+    price_denominator: u128,
+    k_bps: u16,
+) -> Option<u128> {
+    let reserve_after = output_reserve.checked_sub(output_amount)?;
+    if reserve_after == 0 {
+        return None;
+    }
+
+    // A balance above target trades at the oracle price
+    let target = output_target.max(output_reserve) as u128;
+    let ratio = target
+        .checked_mul(PMM_PRECISION)?
+        .checked_div(output_reserve as u128)?
+        .checked_mul(target)?
+        .checked_div(reserve_after as u128)?;
+
+    let k = k_bps as u128;
+    let bps = BPS_DENOMINATOR as u128;
+    let factor = (bps - k)
+        .checked_mul(PMM_PRECISION)?
+        .checked_add(k.checked_mul(ratio)?)?;
+
+    let numerator = (output_amount as u128)
+        .checked_mul(price_numerator)?
+        .checked_mul(factor)?;
+    let denominator = price_denominator
+        .checked_mul(PMM_PRECISION)?
+        .checked_mul(bps)?;
+    Some(numerator.div_ceil(denominator))
+}
+
+pub fn calculate_pmm_swap_amount(
+    input_amount: u64,
+    output_reserve: u64,
+    output_target: u64,
+    price_numerator: u128,
+    price_denominator: u128,
+    k_bps: u16,
+    fee_bps: u16,
+) -> Result<(u64, u64), SwapError> {
+    if k_bps as u64 > BPS_DENOMINATOR || price_denominator == 0 {
+        return Err(SwapError::MathError);
+    }
+
+    let fee_amount = calculate_swap_fee(input_amount, fee_bps)?;
+    let input_amount_with_fee = (input_amount - fee_amount) as u128;
+
+    // The input needed grows with the output, so search for the largest output the
+    // input pays for. Overflow means the output is out of reach.
+    let (mut low, mut high) = (0u64, output_reserve.saturating_sub(1));
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        let affordable = pmm_input_for_output(
+            mid,
+            output_reserve,
+            output_target,
+            price_numerator,
+            price_denominator,
+            k_bps,
+        )
+        .is_some_and(|input_needed| input_needed <= input_amount_with_fee);
+        if affordable {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    Ok((low, fee_amount))
+}
+
+pub fn check_constant_product(
+    input_amount: u64,
+    output_amount: u64,
+    input_reserve: u64,
+    output_reserve: u64,
+    fee_bps: u16,
+) -> Result<(), SwapError> {
+    // Only the input left after the fee counts towards the invariant
+    let input_amount_with_fee = (input_amount - calculate_swap_fee(input_amount, fee_bps)?) as u128;
+
+    let new_input_reserve = (input_reserve as u128)
+        .checked_add(input_amount_with_fee)
+        .ok_or(SwapError::MathError)?;
+    let new_output_reserve = (output_reserve as u128)
+        .checked_sub(output_amount as u128)
+        .ok_or(SwapError::InsufficientLiquidity)?;
+
+    // k = x * y must not decrease
+    let k_before = (input_reserve as u128)
+        .checked_mul(output_reserve as u128)
+        .ok_or(SwapError::MathError)?;
+    let k_after = new_input_reserve
+        .checked_mul(new_output_reserve)
+        .ok_or(SwapError::MathError)?;
+
+    if k_after < k_before {
+        return Err(SwapError::InvariantViolated);
+    }
+
+    Ok(())
+}
+
+pub fn calculate_price_impact_bps(
+    input_amount: u64,
+    output_amount: u64,
+    input_reserve: u64,
+    output_reserve: u64,
+) -> Result<u64, SwapError> {
+    // Compare the execution price (output / input) against the spot price
+    // (output_reserve / input_reserve) by cross multiplying
+    let execution = (output_amount as u128)
+        .checked_mul(input_reserve as u128)
+        .ok_or(SwapError::MathError)?
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(SwapError::MathError)?;
+    let spot = (input_amount as u128)
+        .checked_mul(output_reserve as u128)
+        .ok_or(SwapError::MathError)?;
+    if spot == 0 {
+        return Err(SwapError::InsufficientLiquidity);
+    }
+
+    // An execution price at or above the spot price has no impact
+    let execution_bps = execution / spot;
+    Ok((BPS_DENOMINATOR as u128).saturating_sub(execution_bps) as u64)
+}
+
+pub fn calculate_price_move_bps(
+    reference_input_reserve: u64,
+    reference_output_reserve: u64,
+    input_reserve: u64,
+    output_reserve: u64,
+) -> Result<u64, SwapError> {
+    // Prices are output / input, compared by cross multiplying
+    let reference = (reference_output_reserve as u128)
+        .checked_mul(input_reserve as u128)
+        .ok_or(SwapError::MathError)?;
+    let current = (output_reserve as u128)
+        .checked_mul(reference_input_reserve as u128)
+        .ok_or(SwapError::MathError)?;
+    if reference == 0 {
+        return Ok(0); // No reference price yet
+    }
+
+    let move_bps = reference
+        .abs_diff(current)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(SwapError::MathError)?
+        / reference;
+    Ok(u64::try_from(move_bps).unwrap_or(u64::MAX))
+}
+
+// Fee the constant product, PMM and weighted curves take from a swap input, rounded
+// down on every curve alike
+pub fn calculate_swap_fee(input_amount: u64, fee_bps: u16) -> Result<u64, SwapError> {
+    Ok(input_amount
+        .checked_mul(fee_bps as u64)
+        .ok_or(SwapError::MathError)?
+        / BPS_DENOMINATOR)
+}
+
+pub fn calculate_fee(amount: u64, fee_bps: u16) -> Result<u64, SwapError> {
+    // Round the fee up so a non-zero fee rate never charges nothing
+    let fee_amount = (amount as u128)
+        .checked_mul(fee_bps as u128)
+        .ok_or(SwapError::MathError)?
+        .checked_add(BPS_DENOMINATOR as u128 - 1)
+        .ok_or(SwapError::MathError)?
+        / BPS_DENOMINATOR as u128;
+
+    u64::try_from(fee_amount).map_err(|_| SwapError::MathError)
+}
+
+// Virtual trade of the long-term orders of a TWAMM interval. Opposing sales are
+// matched against each other at the spot price, and only the excess trades along
+// the constant product curve. Returns the token B bought by the sellers of token A
+// and the token A bought by the sellers of token B.
+pub fn calculate_twamm_trade(
+    reserve_a: u64,
+    reserve_b: u64,
+    sold_a: u64,
+    sold_b: u64,
+) -> Result<(u64, u64), SwapError> {
+    // An empty side has no price, so the sales just join the reserves
+    if reserve_a == 0 || reserve_b == 0 {
+        return Ok((0, 0));
+    }
+
+    let (reserve_a, reserve_b) = (reserve_a as u128, reserve_b as u128);
+    let (sold_a, sold_b) = (sold_a as u128, sold_b as u128);
+    let to_u64 = |amount: u128| u64::try_from(amount).map_err(|_| SwapError::MathError);
+    let curve_output = |excess: u128, reserve_in: u128, reserve_out: u128| {
+        excess * reserve_out / (reserve_in + excess)
+    };
+
+    if sold_a * reserve_b >= sold_b * reserve_a {
+        // All of token B is matched, and the rest of token A is sold on the curve
+        let matched_a = sold_b * reserve_a / reserve_b;
+        let output_b = curve_output(sold_a - matched_a, reserve_a, reserve_b);
+        Ok((to_u64(sold_b + output_b)?, to_u64(matched_a)?))
+    } else {
+        let matched_b = sold_a * reserve_b / reserve_a;
+        let output_a = curve_output(sold_b - matched_b, reserve_b, reserve_a);
+        Ok((to_u64(matched_b)?, to_u64(sold_a + output_a)?))
+    }
+}
+
+// Weighted pool output, as in Balancer: the reserves keep
+// input_reserve^input_weight * output_reserve^output_weight constant, so the output is
+// output_reserve * (1 - (input_reserve / (input_reserve + input))^(input_weight / output_weight)).
+// The power is rounded up, so rounding favors the pool.
+pub fn calculate_weighted_swap_amount(
+    input_amount: u64,
+    input_reserve: u64,
+    output_reserve: u64,
+    input_weight_bps: u16,
+    output_weight_bps: u16,
+    fee_bps: u16,
+) -> Result<(u64, u64), SwapError> {
+    if input_reserve == 0 || output_weight_bps == 0 {
+        return Err(SwapError::InsufficientLiquidity);
+    }
+    let fee_amount = calculate_swap_fee(input_amount, fee_bps)?;
+    let input_amount_with_fee = input_amount - fee_amount;
+
+    let ratio_x64 = mul_div(
+        input_reserve as u128 + input_amount_with_fee as u128,
+        Q64,
+        input_reserve as u128,
+        false,
+    )?;
+    let exponent_x64 = mul_div(
+        log2_x64(ratio_x64)?,
+        input_weight_bps as u128,
+        output_weight_bps as u128,
+        false,
+    )?;
+    let power_x64 = exp2_neg_x64(exponent_x64)?;
+    let output_amount = mul_div(output_reserve as u128, Q64 - power_x64, Q64, false)?;
+
+    Ok((
+        u64::try_from(output_amount).map_err(|_| SwapError::MathError)?,
+        fee_amount,
+    ))
+}
+
+// Fractional bits of the weighted pool powers
+const LOG2_FRACTION_BITS: usize = 32;
+
+// 2^64 * 2^(-2^-i) for i = 1..=32, rounded up
+const EXP2_FACTORS: [u128; LOG2_FRACTION_BITS] = [
+    0xb504f333f9de6485,
+    0xd744fccad69d6af5,
+    0xeac0c6e7dd24392f,
+    0xf5257d152486cc2d,
+    0xfa83b2db722a033b,
+    0xfd3e0c0cf486c175,
+    0xfe9e115c7b8f884c,
+    0xff4ecb59511ec8a6,
+    0xffa756521c8daed2,
+    0xffd3a751c0f7e10c,
+    0xffe9d2b2f7db2756,
+    0xfff4e91bff1b8c3e,
+    0xfffa747ea0040665,
+    0xfffd3a3b7814eb54,
+    0xfffe9d1cc60ddab2,
+    0xffff4e8e25879bfb,
+    0xffffa7470363f452,
+    0xffffd3a37dda0314,
+    0xffffe9d1bdf703af,
+    0xfffff4e8debe025f,
+    0xfffffa746f4fa151,
+    0xfffffd3a37a3f8b1,
+    0xfffffe9d1bd1065b,
+    0xffffff4e8de845ae,
+    0xffffffa746f41377,
+    0xffffffd3a37a05e4,
+    0xffffffe9d1bd01fc,
+    0xfffffff4e8de80c1,
+    0xfffffffa746f4051,
+    0xfffffffd3a37a025,
+    0xfffffffe9d1bd012,
+    0xffffffff4e8de809,
+];
+
+// log2 of a Q64.64 value of at least one, rounded down
+fn log2_x64(x: u128) -> Result<u128, SwapError> {
+    if x < Q64 {
+        return Err(SwapError::MathError);
+    }
+
+    let integer = 63 - x.leading_zeros();
+    let mut y = x >> integer;
+    let mut log = (integer as u128) << 64;
+    // Squaring y in [1, 2) yields the fractional bits one at a time
+    for bit in 1..=LOG2_FRACTION_BITS {
+        y = mul_div(y, y, Q64, false)?;
+        if y >= 2 * Q64 {
+            y >>= 1;
+            log |= 1 << (64 - bit);
+        }
+    }
+    Ok(log)
+}
+
+// 2^-z of a Q64.64 value, rounded up and never zero
+fn exp2_neg_x64(z: u128) -> Result<u128, SwapError> {
+    let integer = z >> 64;
+    if integer >= 64 {
+        return Ok(1);
+    }
+
+    // Fraction bits below the table would only lower the result
+    let mut result = Q64;
+    for (index, factor) in EXP2_FACTORS.iter().enumerate() {
+        if z & (1 << (63 - index)) != 0 {
+            result = mul_div(result, *factor, Q64, true)?;
+        }
+    }
+    Ok(result.div_ceil(1 << integer))
+}
+
+// Concentrated liquidity math. Prices are sqrt(token B per token A) in Q64.64 and
+// tick i has a price of 1.0001^i, following Uniswap v3.
+pub const MIN_TICK: i32 = -443_636;
+pub const MAX_TICK: i32 = 443_636;
+pub const Q64: u128 = 1 << 64;
+
+// 2^64 / sqrt(1.0001)^(2^i), multiplied together for each bit of the tick
+const SQRT_PRICE_FACTORS: [u128; 19] = [
+    0xfffcb933bd6fad37,
+    0xfff97272373d4132,
+    0xfff2e50f5f656932,
+    0xffe5caca7e10e4e6,
+    0xffcb9843d60f6159,
+    0xff973b41fa98c081,
+    0xff2ea16466c96a38,
+    0xfe5dee046a99a2a8,
+    0xfcbe86c7900a88ae,
+    0xf987a7253ac41317,
+    0xf3392b0822b70005,
+    0xe7159475a2c29b74,
+    0xd097f3bdfd2022b8,
+    0xa9f746462d870fdf,
+    0x70d869a156d2a1b8,
+    0x31be135f97d08fd9,
+    0x9aa508b5b7a84e1,
+    0x5d6af8dedb8119,
+    0x2216e584f5fa,
+];
+
+// a * b / denominator with a 256 bit intermediate product
+pub fn mul_div(a: u128, b: u128, denominator: u128, round_up: bool) -> Result<u128, SwapError> {
+    if denominator == 0 {
+        return Err(SwapError::MathError);
+    }
+
+    const MASK: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & MASK);
+    let (b_high, b_low) = (b >> 64, b & MASK);
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let middle = (low_low >> 64) + (high_low & MASK) + (low_high & MASK);
+    let low = (low_low & MASK) | (middle << 64);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (middle >> 64);
+
+    let (quotient, remainder) = if high == 0 {
+        (low / denominator, low % denominator)
+    } else {
+        // The quotient has to fit in 128 bits
+        if high >= denominator {
+            return Err(SwapError::MathError);
+        }
+        let mut remainder = high;
+        let mut quotient = 0u128;
+        for bit in (0..128).rev() {
+            let carry = remainder >> 127;
+            remainder = (remainder << 1) | ((low >> bit) & 1);
+            quotient <<= 1;
+            if carry == 1 || remainder >= denominator {
+                remainder = remainder.wrapping_sub(denominator);
+                quotient |= 1;
+            }
+        }
+        (quotient, remainder)
+    };
+
+    if round_up && remainder > 0 {
+        quotient.checked_add(1).ok_or(SwapError::MathError)
+    } else {
+        Ok(quotient)
+    }
+}
+
+pub fn sqrt_price_at_tick(tick: i32) -> Result<u128, SwapError> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(SwapError::InvalidTick);
+    }
+
+    let abs_tick = tick.unsigned_abs();
+    let mut ratio = Q64;
+    for (bit, factor) in SQRT_PRICE_FACTORS.iter().enumerate() {
+        if abs_tick & (1 << bit) != 0 {
+            ratio = (ratio * factor) >> 64;
+        }
+    }
+
+    // The factors give the price of a negative tick, invert it for a positive one
+    if tick > 0 {
+        ratio = u128::MAX / ratio;
+    }
+    Ok(ratio)
+}
+
+// Largest tick whose sqrt price is at or below the given sqrt price
+pub fn tick_at_sqrt_price(sqrt_price_x64: u128) -> Result<i32, SwapError> {
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    if sqrt_price_x64 < sqrt_price_at_tick(low)? {
+        return Err(SwapError::InvalidTick);
+    }
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid)? <= sqrt_price_x64 {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low)
+}
+
+// Token A between two sqrt prices: L * (upper - lower) / (upper * lower)
+pub fn amount_a_delta(
+    sqrt_price_a: u128,
+    sqrt_price_b: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128, SwapError> {
+    let (lower, upper) = (
+        sqrt_price_a.min(sqrt_price_b),
+        sqrt_price_a.max(sqrt_price_b),
+    );
+    if lower == 0 {
+        return Err(SwapError::MathError);
+    }
+    let numerator = mul_div(liquidity, upper - lower, upper, round_up)?;
+    mul_div(numerator, Q64, lower, round_up)
+}
+
+// Token B between two sqrt prices: L * (upper - lower)
+pub fn amount_b_delta(
+    sqrt_price_a: u128,
+    sqrt_price_b: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<u128, SwapError> {
+    let (lower, upper) = (
+        sqrt_price_a.min(sqrt_price_b),
+        sqrt_price_a.max(sqrt_price_b),
+    );
+    mul_div(liquidity, upper - lower, Q64, round_up)
+}
+
+// Liquidity a token A deposit provides over a range above the price:
+// amount * lower * upper / (upper - lower)
+pub fn liquidity_for_amount_a(
+    sqrt_price_lower: u128,
+    sqrt_price_upper: u128,
+    amount: u64,
+) -> Result<u128, SwapError> {
+    if sqrt_price_upper <= sqrt_price_lower {
+        return Err(SwapError::InvalidTick);
+    }
+    let product = mul_div(sqrt_price_lower, sqrt_price_upper, Q64, false)?;
+    mul_div(
+        amount as u128,
+        product,
+        sqrt_price_upper - sqrt_price_lower,
+        false,
+    )
+}
+
+// Liquidity a token B deposit provides over a range below the price:
+// amount / (upper - lower)
+pub fn liquidity_for_amount_b(
+    sqrt_price_lower: u128,
+    sqrt_price_upper: u128,
+    amount: u64,
+) -> Result<u128, SwapError> {
+    if sqrt_price_upper <= sqrt_price_lower {
+        return Err(SwapError::InvalidTick);
+    }
+    mul_div(
+        amount as u128,
+        Q64,
+        sqrt_price_upper - sqrt_price_lower,
+        false,
+    )
+}
+
+// Token amounts backing liquidity in a range at the current price
+pub fn liquidity_amounts(
+    sqrt_price_x64: u128,
+    sqrt_price_lower: u128,
+    sqrt_price_upper: u128,
+    liquidity: u128,
+    round_up: bool,
+) -> Result<(u64, u64), SwapError> {
+    let (amount_a, amount_b) = if sqrt_price_x64 <= sqrt_price_lower {
+        (
+            amount_a_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+            0,
+        )
+    } else if sqrt_price_x64 >= sqrt_price_upper {
+        (
+            0,
+            amount_b_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up)?,
+        )
+    } else {
+        (
+            amount_a_delta(sqrt_price_x64, sqrt_price_upper, liquidity, round_up)?,
+            amount_b_delta(sqrt_price_lower, sqrt_price_x64, liquidity, round_up)?,
+        )
+    };
+
+    Ok((
+        u64::try_from(amount_a).map_err(|_| SwapError::MathError)?,
+        u64::try_from(amount_b).map_err(|_| SwapError::MathError)?,
+    ))
+}
+
+pub struct SwapStep {
+    pub sqrt_price_next: u128,
+    pub amount_in: u64, // Excluding the fee
+    pub amount_out: u64,
+    pub fee_amount: u64,
+}
+
+// Swaps an exact input within a single tick range, stopping at the target price
+pub fn compute_swap_step(
+    sqrt_price_x64: u128,
+    sqrt_price_target: u128,
+    liquidity: u128,
+    amount_remaining: u64,
+    fee_bps: u16,
+    a_to_b: bool,
+) -> Result<SwapStep, SwapError> {
+    let fee_rate = fee_bps as u128;
+    let bps = BPS_DENOMINATOR as u128;
+    let amount_less_fee = mul_div(amount_remaining as u128, bps - fee_rate, bps, false)?;
+
+    let max_amount_in = if a_to_b {
+        amount_a_delta(sqrt_price_target, sqrt_price_x64, liquidity, true)?
+    } else {
+        amount_b_delta(sqrt_price_x64, sqrt_price_target, liquidity, true)?
+    };
+
+    let (sqrt_price_next, amount_in) = if amount_less_fee >= max_amount_in {
+        (sqrt_price_target, max_amount_in)
+    } else if a_to_b {
+        // Adding token A moves the price down: L * P / (L + amount * P)
+        let product = mul_div(amount_less_fee, sqrt_price_x64, Q64, true)?;
+        let denominator = liquidity.checked_add(product).ok_or(SwapError::MathError)?;
+        (
+            mul_div(liquidity, sqrt_price_x64, denominator, true)?,
+            amount_less_fee,
+        )
+    } else {
+        // Adding token B moves the price up: P + amount / L
+        let delta = mul_div(amount_less_fee, Q64, liquidity, false)?;
+        (
+            sqrt_price_x64
+                .checked_add(delta)
+                .ok_or(SwapError::MathError)?,
+            amount_less_fee,
+        )
+    };
+
+    let amount_out = if a_to_b {
+        amount_b_delta(sqrt_price_next, sqrt_price_x64, liquidity, false)?
+    } else {
+        amount_a_delta(sqrt_price_x64, sqrt_price_next, liquidity, false)?
+    };
+
+    // The whole remainder is fee once the input no longer moves the price further
+    let fee_amount = if sqrt_price_next == sqrt_price_target {
+        mul_div(amount_in, fee_rate, bps - fee_rate, true)?
+            .min(amount_remaining as u128 - amount_in)
+    } else {
+        amount_remaining as u128 - amount_in
+    };
+
+    Ok(SwapStep {
+        sqrt_price_next,
+        amount_in: u64::try_from(amount_in).map_err(|_| SwapError::MathError)?,
+        amount_out: u64::try_from(amount_out).map_err(|_| SwapError::MathError)?,
+        fee_amount: u64::try_from(fee_amount).map_err(|_| SwapError::MathError)?,
+    })
+}
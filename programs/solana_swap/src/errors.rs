@@ -25,6 +25,129 @@ pub enum SwapError {
 
     #[msg("Incorrect Owner")]
     IncorrectOwner,
+
+    #[msg("Invalid fee.")]
+    InvalidFee,
+
+    #[msg("Unauthorized.")]
+    Unauthorized,
+
+    #[msg("Constant product invariant violated.")]
+    InvariantViolated,
+
+    #[msg("Only Jupiter route instructions are allowed.")]
+    JupiterRouteNotAllowed,
+
+    #[msg("Invalid Jupiter route data.")]
+    InvalidRouteData,
+
+    #[msg("Jupiter route spends more than the declared input.")]
+    RouteAmountMismatch,
+
+    #[msg("Token vault is not empty.")]
+    VaultNotEmpty,
+
+    #[msg("Swap output does not cover the fees.")]
+    InsufficientOutput,
+
+    #[msg("Treasury would fall below its reserve floor.")]
+    TreasuryReserveFloor,
+
+    #[msg("Treasury has outstanding flash loans.")]
+    OutstandingLoans,
+
+    #[msg("Transaction deadline exceeded.")]
+    DeadlineExceeded,
+
+    #[msg("Price impact too high.")]
+    PriceImpactTooHigh,
+
+    #[msg("Swap moves the price past the limit price.")]
+    PriceLimitExceeded,
+
+    #[msg("Pool is halted by its circuit breaker.")]
+    PoolHalted,
+
+    #[msg("Circuit breaker tripped.")]
+    CircuitBreakerTripped,
+
+    #[msg("Execution price outside the slot price band.")]
+    PriceBandExceeded,
+
+    #[msg("Invalid oracle price account.")]
+    InvalidOracle,
+
+    #[msg("Oracle price is stale.")]
+    OracleStale,
+
+    #[msg("Pool price diverges from the oracle.")]
+    OraclePriceDivergence,
+
+    #[msg("Not supported by the pool curve.")]
+    UnsupportedCurve,
+
+    #[msg("Invalid tick.")]
+    InvalidTick,
+
+    #[msg("Tick array is full.")]
+    TickArrayFull,
+
+    #[msg("Position still holds liquidity or fees.")]
+    PositionNotEmpty,
+
+    #[msg("Range order not filled yet.")]
+    RangeOrderNotFilled,
+
+    #[msg("Order has expired.")]
+    OrderExpired,
+
+    #[msg("Order trigger price not reached.")]
+    OrderNotTriggered,
+
+    #[msg("DCA interval has not elapsed yet.")]
+    DcaIntervalNotElapsed,
+
+    #[msg("Wrong route for the DCA schedule.")]
+    DcaRouteMismatch,
+
+    #[msg("Too many long-term order end slots.")]
+    TwammExpiriesFull,
+
+    #[msg("Long-term orders are still open.")]
+    TwammOrdersOpen,
+
+    #[msg("Missing or invalid ed25519 quote signature.")]
+    InvalidQuoteSignature,
+
+    #[msg("Quote has expired.")]
+    QuoteExpired,
+
+    #[msg("Not enough pool shares.")]
+    InsufficientShares,
+
+    #[msg("Pool liquidity is owned by shareholders.")]
+    SharesOutstanding,
+
+    #[msg("Farm holds the maximum number of rewards.")]
+    FarmRewardsFull,
+
+    #[msg("Farm reward not found.")]
+    RewardNotFound,
+
+    #[msg("Pool holds reserves no shares are backed by.")]
+    UnbackedReserves,
+
+    #[msg("Reward vault does not cover the emissions.")]
+    RewardVaultUnderfunded,
+
+    #[msg("The pool's farm is required.")]
+    FarmRequired,
+
+    #[msg("Invalid lock duration.")]
+    InvalidLockDuration,
+
+    #[msg("Shares are already locked.")]
+    LockActive,
 }
 
 #[error_code]
@@ -43,4 +166,18 @@ pub enum FlashFillError {
     CannotBorrowBeforeRepay,
     #[msg("Unknown Instruction")]
     UnknownInstruction,
+    #[msg("Missing Token Account")]
+    MissingTokenAccount,
+    #[msg("Invalid Mint")]
+    InvalidMint,
+    #[msg("Repay Mismatch")]
+    RepayMismatch,
+    #[msg("Missing Borrow")]
+    MissingBorrow,
+    #[msg("Borrower Mismatch")]
+    BorrowerMismatch,
+    #[msg("Amount Mismatch")]
+    AmountMismatch,
+    #[msg("Loan Index Mismatch")]
+    LoanIndexMismatch,
 }
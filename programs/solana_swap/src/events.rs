@@ -0,0 +1,75 @@
+use anchor_lang::prelude::*;
+
+#[event]
+pub struct SwapEvent {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub fee_amount: u64, // Fee taken from the input
+    pub fee_bps: u16,    // Flat or dynamic fee rate charged
+}
+
+#[event]
+pub struct JupiterSwapEvent {
+    pub user: Pubkey,
+    pub output_mint: Pubkey,
+    pub gross_amount: u64, // Output measured across the Jupiter CPI
+    pub fee_amount: u64,   // Platform fee sent to the treasury
+    pub net_amount: u64,   // Output received by the user
+}
+
+#[event]
+pub struct GaslessSwapEvent {
+    pub user: Pubkey,
+    pub relayer: Pubkey,
+    pub gross_amount: u64, // Output measured across the Jupiter CPI
+    pub fee_amount: u64,   // Platform fee kept by the treasury
+    pub relayer_fee: u64,  // Paid to the relayer on top of the vault rent
+    pub net_amount: u64,   // Lamports received by the user
+}
+
+#[event]
+pub struct TreasuryEvent {
+    pub mint: Pubkey, // Native mint for SOL
+    pub funded: u64,
+    pub withdrawn: u64,
+    pub balance: u64, // Treasury balance after the change
+}
+
+#[event]
+pub struct PositionEvent {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub liquidity_delta: i128, // Negative when liquidity is withdrawn
+    pub amount_a: u64,         // Tokens moved in or out, including collected fees
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct DcaEvent {
+    pub schedule: Pubkey,
+    pub owner: Pubkey,
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub remaining_amount: u64, // Input left for later intervals
+}
+
+#[event]
+pub struct RfqFillEvent {
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub maker_mint: Pubkey,
+    pub taker_mint: Pubkey,
+    pub maker_amount: u64, // Paid by the maker to the taker
+    pub taker_amount: u64, // Paid by the taker to the maker
+    pub nonce: u64,
+}
+
+#[event]
+pub struct LiquidityUnlockEvent {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub shares: u64,  // Returned to the owner
+    pub penalty: u64, // Burned for leaving the lock early
+}
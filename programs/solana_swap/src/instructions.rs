@@ -1,7 +1,48 @@
-use crate::curve::calculate_swap_amount;
+use crate::constants::{
+    BPS_DENOMINATOR, CONFIG_SEED, DCA_SEED, DCA_VAULT_SEED, FARM_REWARD_VAULT_SEED, FARM_SEED,
+    GASLESS_VAULT_SEED, LONG_TERM_ORDER_SEED, LP_SHARES_SEED, MAX_LOCK_BOOST_BPS, MAX_LOCK_SECONDS,
+    ORDER_SEED, ORDER_VAULT_SEED, POOL_SEED, POSITION_MINT_SEED, POSITION_SEED, RANGE_ORDER_SEED,
+    RFQ_AUTHORITY_SEED, RFQ_NONCE_SEED, SWAP_VAULT_SEED, TICK_ARRAY_SEED, TREASURY_SEED,
+    TWAMM_INTERVAL_SLOTS,
+};
+use crate::curve::{
+    calculate_fee, calculate_pmm_swap_amount, calculate_price_impact_bps, calculate_price_move_bps,
+    calculate_swap_amount_with_fee, calculate_weighted_swap_amount, check_constant_product,
+    compute_swap_step, liquidity_amounts, liquidity_for_amount_a, liquidity_for_amount_b, mul_div,
+    sqrt_price_at_tick, tick_at_sqrt_price, FLAT_FEE_BPS, MAX_TICK, MIN_TICK, Q64,
+};
 use crate::errors::{FlashFillError, SwapError};
+use crate::events::{
+    DcaEvent, GaslessSwapEvent, JupiterSwapEvent, LiquidityUnlockEvent, PositionEvent,
+    RfqFillEvent, SwapEvent, TreasuryEvent,
+};
+use crate::jupiter;
+use crate::oracle::{
+    calculate_oracle_divergence_bps, oracle_price_ratio, parse_price_feed, PriceFeed,
+};
+use crate::state::ConcentratedLiquidity;
+use crate::state::CurveType;
+use crate::state::DcaRoute;
+use crate::state::DcaSchedule;
+use crate::state::Farm;
+use crate::state::FarmReward;
+use crate::state::LiquidityShares;
+use crate::state::LongTermOrder;
+use crate::state::OracleGuard;
+use crate::state::Order;
+use crate::state::OrderKind;
 use crate::state::Pool;
+use crate::state::Position;
+use crate::state::ProgramConfig;
+use crate::state::RangeOrder;
+use crate::state::RfqNonce;
+use crate::state::RfqQuote;
+use crate::state::Tick;
+use crate::state::TickArray;
 use crate::state::TokenAmount;
+use crate::state::Twamm;
+use crate::state::TwammExpiry;
+use crate::state::WeightSchedule;
 use anchor_lang::solana_program::sysvar::instructions::{
     load_current_index_checked, load_instruction_at_checked,
 };
@@ -9,19 +50,15 @@ use anchor_lang::Discriminator;
 use anchor_lang::{
     prelude::*,
     solana_program::{
-        entrypoint::ProgramResult, instruction::Instruction, program::invoke_signed, sysvar,
+        bpf_loader_upgradeable, ed25519_program, instruction::Instruction, program::invoke_signed,
+        sysvar,
     },
     system_program,
 };
-use anchor_spl::token::{self, Mint, Token, TokenAccount};
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token::{self, spl_token, Mint, Token, TokenAccount};
 
 pub const AUTHORITY_SEED: &[u8] = b"authority";
-pub const WSOL_SEED: &[u8] = b"wsol";
-
-mod jupiter {
-    use anchor_lang::declare_id;
-    declare_id!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
-}
 
 #[derive(Clone)]
 pub struct Jupiter;
@@ -53,7 +90,8 @@ pub struct Deposit<'info> {
     pub pool: Account<'info, Pool>,
     #[account(mut)]
     pub user_token: Account<'info, TokenAccount>,
-    #[account(mut)]
+    // Holds the reserve of the token, paid out with the pool's signature
+    #[account(mut, token::authority = pool)]
     pub pool_token: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
@@ -63,6 +101,19 @@ pub struct Deposit<'info> {
 pub fn deposit(ctx: Context<Deposit>, token_account: Pubkey, amount: u64) -> Result<()> {
     let pool = &mut ctx.accounts.pool;
     let cpi_program = ctx.accounts.token_program.to_account_info();
+    pool.settle_twamm(Clock::get()?.slot)?;
+
+    // Concentrated liquidity is only provided through positions
+    require!(
+        !matches!(pool.curve, CurveType::Concentrated { .. }),
+        SwapError::UnsupportedCurve
+    );
+
+    // Weighted pools take liquidity only from their authority during the sale
+    require!(
+        pool.can_provide_liquidity(ctx.accounts.user.key(), Clock::get()?.unix_timestamp),
+        SwapError::Unauthorized
+    );
 
     // Ensure the user is depositing to the correct token account
     require!(
@@ -85,7 +136,20 @@ pub fn deposit(ctx: Context<Deposit>, token_account: Pubkey, amount: u64) -> Res
     // Check if the token is already in the pool's tokens vector
     for token in &mut pool.tokens {
         if token.token_account == token_account {
-            token.amount += amount;
+            // Deposits go to the vault recorded for the token
+            require_keys_eq!(
+                ctx.accounts.pool_token.key(),
+                token.vault,
+                SwapError::TokenNotFound
+            );
+            token.amount = token
+                .amount
+                .checked_add(amount)
+                .ok_or(SwapError::MathError)?;
+            token.target_amount = token
+                .target_amount
+                .checked_add(amount)
+                .ok_or(SwapError::MathError)?;
             found = true;
             break;
         }
@@ -93,9 +157,19 @@ pub fn deposit(ctx: Context<Deposit>, token_account: Pubkey, amount: u64) -> Res
 
     // If not found, add the new token to the pool's tokens vector
     if !found {
+        // Only the pool authority lists new tokens
+        require_keys_eq!(
+            ctx.accounts.user.key(),
+            pool.authority,
+            SwapError::Unauthorized
+        );
         pool.tokens.push(TokenAmount {
             token_account,
+            vault: ctx.accounts.pool_token.key(),
             amount,
+            reference_amount: amount,
+            slot_start_amount: amount,
+            target_amount: amount,
         });
     }
 
@@ -104,11 +178,12 @@ pub fn deposit(ctx: Context<Deposit>, token_account: Pubkey, amount: u64) -> Res
 
 #[derive(Accounts)]
 pub struct InitializePoolToken<'info> {
-    #[account(mut)]
+    #[account(mut, has_one = authority @ SwapError::Unauthorized)]
     pub pool: Account<'info, Pool>,
-    #[account(mut)]
+    // Holds the reserve of the token, paid out with the pool's signature
+    #[account(mut, token::authority = pool)]
     pub pool_token: Account<'info, TokenAccount>,
-    pub user: Signer<'info>,
+    pub authority: Signer<'info>,
     pub token_program: Program<'info, Token>,
 }
 
@@ -130,38 +205,124 @@ pub fn initialize_pool_token(
     // Add the token account to the pool with an initial amount of 0
     pool.tokens.push(TokenAmount {
         token_account,
+        vault: ctx.accounts.pool_token.key(),
         amount: 0,
+        reference_amount: 0,
+        slot_start_amount: 0,
+        target_amount: 0,
     });
 
     Ok(())
 }
 
+#[derive(Accounts)]
+pub struct OpenLiquidityShares<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = owner,
+        space = LiquidityShares::LEN,
+        seeds = [LP_SHARES_SEED, pool.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn open_liquidity_shares(ctx: Context<OpenLiquidityShares>) -> Result<()> {
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    liquidity_shares.pool = ctx.accounts.pool.key();
+    liquidity_shares.owner = ctx.accounts.owner.key();
+    liquidity_shares.bump = ctx.bumps.liquidity_shares;
+    Ok(())
+}
+
 #[derive(Accounts)]
 pub struct AddLiquidity<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
+    // Omitted to deposit native SOL through the temporary wSOL account
     #[account(mut)]
-    pub user_token: Account<'info, TokenAccount>,
+    pub user_token: Option<Account<'info, TokenAccount>>,
     #[account(mut)]
     pub pool_token: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
     pub token_program: Program<'info, Token>,
+    #[account(address = spl_token::native_mint::ID)]
+    pub sol_mint: Option<Account<'info, Mint>>,
+    /// CHECK: Temporary wSOL account, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [SWAP_VAULT_SEED, user.key().as_ref(), spl_token::native_mint::ID.as_ref()],
+        bump
+    )]
+    pub wsol_account: Option<UncheckedAccount<'info>>,
+    pub system_program: Option<Program<'info, System>>,
 }
 
 pub fn add_liquidity(ctx: Context<AddLiquidity>, token_account: Pubkey, amount: u64) -> Result<()> {
     let authority = ctx.accounts.user.to_account_info();
-    let pool = &mut ctx.accounts.pool;
+    let user_key = ctx.accounts.user.key();
+
+    // Concentrated liquidity is only provided through positions
+    require!(
+        !matches!(ctx.accounts.pool.curve, CurveType::Concentrated { .. }),
+        SwapError::UnsupportedCurve
+    );
+    let clock = Clock::get()?;
+    // Weighted pools take liquidity only from their authority during the sale
+    require!(
+        ctx.accounts
+            .pool
+            .can_provide_liquidity(user_key, clock.unix_timestamp),
+        SwapError::Unauthorized
+    );
+    ctx.accounts.pool.settle_twamm(clock.slot)?;
+
+    // Wrap native SOL when the user has no token account for this side
+    let native = match &ctx.accounts.user_token {
+        Some(_) => None,
+        None => {
+            let native = NativeSol::resolve(
+                &ctx.accounts.sol_mint,
+                &ctx.accounts.wsol_account,
+                &ctx.accounts.system_program,
+            )?;
+            let wsol_bump = [ctx.bumps.wsol_account.ok_or(SwapError::BumpNotFound)?];
+            native.wrap(
+                authority.clone(),
+                ctx.accounts.token_program.clone(),
+                &[&[
+                    SWAP_VAULT_SEED,
+                    user_key.as_ref(),
+                    spl_token::native_mint::ID.as_ref(),
+                    &wsol_bump,
+                ]],
+                amount,
+            )?;
+            Some(native)
+        }
+    };
+    let user_token = NativeSol::token_account(&ctx.accounts.user_token, &native)?;
 
     // Transfer tokens from user to pool for the provided token
     let cpi_accounts = token::Transfer {
-        from: ctx.accounts.user_token.to_account_info(),
+        from: user_token,
         to: ctx.accounts.pool_token.to_account_info(),
-        authority: authority,
+        authority: authority.clone(),
     };
     let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
     token::transfer(cpi_ctx, amount)?;
 
+    if let Some(native) = &native {
+        native.unwrap(authority, ctx.accounts.token_program.clone())?;
+    }
+
+    let pool = &mut ctx.accounts.pool;
+
     // Update the corresponding token's amount in the pool
     if let Some(pool_token) = pool
         .tokens
@@ -172,6 +333,10 @@ pub fn add_liquidity(ctx: Context<AddLiquidity>, token_account: Pubkey, amount:
             .amount
             .checked_add(amount)
             .ok_or(SwapError::MathError)?;
+        pool_token.target_amount = pool_token
+            .target_amount
+            .checked_add(amount)
+            .ok_or(SwapError::MathError)?;
     } else {
         return Err(SwapError::TokenNotFound.into()); // Handle token not found in pool
     }
@@ -183,13 +348,24 @@ pub fn add_liquidity(ctx: Context<AddLiquidity>, token_account: Pubkey, amount:
 pub struct RemoveLiquidity<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
+    // Omitted to receive native SOL through the temporary wSOL account
     #[account(mut)]
-    pub user_token: Account<'info, TokenAccount>,
+    pub user_token: Option<Account<'info, TokenAccount>>,
     #[account(mut)]
     pub pool_token: Account<'info, TokenAccount>,
     #[account(mut)]
     pub user: Signer<'info>,
     pub token_program: Program<'info, Token>,
+    #[account(address = spl_token::native_mint::ID)]
+    pub sol_mint: Option<Account<'info, Mint>>,
+    /// CHECK: Temporary wSOL account, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [SWAP_VAULT_SEED, user.key().as_ref(), spl_token::native_mint::ID.as_ref()],
+        bump
+    )]
+    pub wsol_account: Option<UncheckedAccount<'info>>,
+    pub system_program: Option<Program<'info, System>>,
 }
 
 pub fn remove_liquidity(
@@ -198,8 +374,55 @@ pub fn remove_liquidity(
     amount: u64,
 ) -> Result<()> {
     let authority = ctx.accounts.pool.to_account_info();
+    let user = ctx.accounts.user.to_account_info();
+    let user_key = ctx.accounts.user.key();
+
+    // Concentrated liquidity is only withdrawn through positions
+    require!(
+        !matches!(ctx.accounts.pool.curve, CurveType::Concentrated { .. }),
+        SwapError::UnsupportedCurve
+    );
+    let clock = Clock::get()?;
+    // Weighted pools take liquidity only from their authority during the sale
+    require!(
+        ctx.accounts
+            .pool
+            .can_provide_liquidity(user_key, clock.unix_timestamp),
+        SwapError::Unauthorized
+    );
+    ctx.accounts.pool.settle_twamm(clock.slot)?;
+
+    // Open a temporary wSOL account to unwrap into when the user wants native SOL
+    let native = match &ctx.accounts.user_token {
+        Some(_) => None,
+        None => {
+            let native = NativeSol::resolve(
+                &ctx.accounts.sol_mint,
+                &ctx.accounts.wsol_account,
+                &ctx.accounts.system_program,
+            )?;
+            let wsol_bump = [ctx.bumps.wsol_account.ok_or(SwapError::BumpNotFound)?];
+            native.wrap(
+                user.clone(),
+                ctx.accounts.token_program.clone(),
+                &[&[
+                    SWAP_VAULT_SEED,
+                    user_key.as_ref(),
+                    spl_token::native_mint::ID.as_ref(),
+                    &wsol_bump,
+                ]],
+                0,
+            )?;
+            Some(native)
+        }
+    };
+    let user_token = NativeSol::token_account(&ctx.accounts.user_token, &native)?;
+
     let pool = &mut ctx.accounts.pool;
 
+    // Once the pool has shareholders liquidity only leaves by burning shares
+    require!(pool.total_shares == 0, SwapError::SharesOutstanding);
+
     // Find the token in the pool and validate if there is enough liquidity
     if let Some(pool_token) = pool
         .tokens
@@ -214,8 +437,8 @@ pub fn remove_liquidity(
         // Transfer tokens from the pool to the user for the given token
         let cpi_accounts = token::Transfer {
             from: ctx.accounts.pool_token.to_account_info(),
-            to: ctx.accounts.user_token.to_account_info(),
-            authority: authority,
+            to: user_token,
+            authority,
         };
         let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
         token::transfer(cpi_ctx, amount)?;
@@ -225,47 +448,419 @@ pub fn remove_liquidity(
             .amount
             .checked_sub(amount)
             .ok_or(SwapError::MathError)?;
+        pool_token.target_amount = pool_token.target_amount.saturating_sub(amount);
     } else {
         return Err(SwapError::TokenNotFound.into()); // Handle token not found in pool
     }
 
+    if let Some(native) = &native {
+        native.unwrap(user, ctx.accounts.token_program.clone())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MintLiquidityShares<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, has_one = pool, has_one = owner @ SwapError::Unauthorized)]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    // Remaining accounts: the user token account and the pool vault of every pool
+    // token, in pool token order
+}
+
+// Mints shares for a deposit of every pool token in proportion to the reserves,
+// rounded up against the user. The first shares are minted into empty reserves at
+// the amounts the user chose.
+pub fn mint_liquidity_shares<'info>(
+    ctx: Context<'_, '_, '_, 'info, MintLiquidityShares<'info>>,
+    shares: u64,
+    max_amounts: Vec<u64>,
+) -> Result<()> {
+    require!(shares > 0, SwapError::InvalidAmount);
+    let clock = Clock::get()?;
+    let pool = &mut ctx.accounts.pool;
+    check_share_liquidity(pool, ctx.accounts.owner.key(), &clock)?;
+    require!(
+        max_amounts.len() == pool.tokens.len(),
+        SwapError::InvalidAmount
+    );
+
+    let amounts = if pool.total_shares == 0 {
+        // Reserves nobody holds shares of would go to the first shareholder
+        require!(
+            pool.tokens.iter().all(|token| token.amount == 0),
+            SwapError::UnbackedReserves
+        );
+        require!(
+            max_amounts.iter().all(|&amount| amount > 0),
+            SwapError::InvalidAmount
+        );
+        max_amounts
+    } else {
+        let amounts = pool.amounts_for_shares(shares, true)?;
+        require!(
+            amounts
+                .iter()
+                .zip(&max_amounts)
+                .all(|(amount, max)| amount <= max),
+            SwapError::SlippageError
+        );
+        amounts
+    };
+
+    let token_accounts = pool_token_accounts(pool, ctx.remaining_accounts)?;
+    for ((user_token, pool_token), amount) in token_accounts.into_iter().zip(&amounts) {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: user_token.clone(),
+                    to: pool_token.clone(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            *amount,
+        )?;
+    }
+
+    for (token, amount) in pool.tokens.iter_mut().zip(amounts) {
+        token.amount = token
+            .amount
+            .checked_add(amount)
+            .ok_or(SwapError::MathError)?;
+        token.target_amount = token
+            .target_amount
+            .checked_add(amount)
+            .ok_or(SwapError::MathError)?;
+    }
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    liquidity_shares.shares = liquidity_shares
+        .shares
+        .checked_add(shares)
+        .ok_or(SwapError::MathError)?;
+    pool.total_shares = pool
+        .total_shares
+        .checked_add(shares)
+        .ok_or(SwapError::MathError)?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct BurnLiquidityShares<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    // The pool keypair owns the vaults
+    #[account(address = pool.key() @ SwapError::Unauthorized)]
+    pub pool_authority: Signer<'info>,
+    #[account(mut, has_one = pool, has_one = owner @ SwapError::Unauthorized)]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    // Remaining accounts: the user token account and the pool vault of every pool
+    // token, in pool token order
+}
+
+// Burns free shares for their part of every pool token, rounded down against the user
+pub fn burn_liquidity_shares<'info>(
+    ctx: Context<'_, '_, '_, 'info, BurnLiquidityShares<'info>>,
+    shares: u64,
+    min_amounts: Vec<u64>,
+) -> Result<()> {
+    require!(shares > 0, SwapError::InvalidAmount);
+    let clock = Clock::get()?;
+    let pool = &mut ctx.accounts.pool;
+    check_share_liquidity(pool, ctx.accounts.owner.key(), &clock)?;
+    require!(
+        min_amounts.len() == pool.tokens.len(),
+        SwapError::InvalidAmount
+    );
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    require!(
+        liquidity_shares.shares >= shares,
+        SwapError::InsufficientShares
+    );
+
+    let amounts = pool.amounts_for_shares(shares, false)?;
+    require!(
+        amounts
+            .iter()
+            .zip(&min_amounts)
+            .all(|(amount, min)| amount >= min),
+        SwapError::SlippageError
+    );
+
+    let token_accounts = pool_token_accounts(pool, ctx.remaining_accounts)?;
+    for ((user_token, pool_token), amount) in token_accounts.into_iter().zip(&amounts) {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: pool_token.clone(),
+                    to: user_token.clone(),
+                    authority: ctx.accounts.pool_authority.to_account_info(),
+                },
+            ),
+            *amount,
+        )?;
+    }
+
+    for (token, amount) in pool.tokens.iter_mut().zip(amounts) {
+        token.amount = token
+            .amount
+            .checked_sub(amount)
+            .ok_or(SwapError::MathError)?;
+        token.target_amount = token.target_amount.saturating_sub(amount);
+    }
+    liquidity_shares.shares -= shares;
+    pool.total_shares = pool
+        .total_shares
+        .checked_sub(shares)
+        .ok_or(SwapError::MathError)?;
+
     Ok(())
 }
 
+// Concentrated pools hold no shares, and a weighted pool only takes liquidity from
+// its authority until its sale has ended
+fn check_share_liquidity(pool: &mut Pool, user: Pubkey, clock: &Clock) -> Result<()> {
+    require!(
+        !matches!(pool.curve, CurveType::Concentrated { .. }),
+        SwapError::UnsupportedCurve
+    );
+    require!(
+        pool.can_provide_liquidity(user, clock.unix_timestamp),
+        SwapError::Unauthorized
+    );
+    pool.settle_twamm(clock.slot)
+}
+
+// Pairs the user token account and the pool vault of every pool token, passed as
+// remaining accounts in pool token order
+fn pool_token_accounts<'a, 'info>(
+    pool: &Pool,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> Result<Vec<(&'a AccountInfo<'info>, &'a AccountInfo<'info>)>> {
+    require!(
+        remaining_accounts.len() == pool.tokens.len() * 2,
+        SwapError::TokenNotFound
+    );
+    remaining_accounts
+        .chunks(2)
+        .zip(&pool.tokens)
+        .map(|(accounts, token)| {
+            require_keys_eq!(accounts[1].key(), token.vault, SwapError::TokenNotFound);
+            Ok((&accounts[0], &accounts[1]))
+        })
+        .collect()
+}
+
 #[derive(Accounts)]
 pub struct Swap<'info> {
     #[account(mut)]
     pub pool: Account<'info, Pool>,
     #[account(signer)]
     pub pool_authority: Signer<'info>,
+    // Either user token account may be omitted to trade native SOL on that side
     #[account(mut)]
-    pub user_token_in: Account<'info, TokenAccount>,
+    pub user_token_in: Option<Account<'info, TokenAccount>>,
     #[account(mut)]
-    pub user_token_out: Account<'info, TokenAccount>,
+    pub user_token_out: Option<Account<'info, TokenAccount>>,
     #[account(mut)]
     pub pool_token_in: Account<'info, TokenAccount>,
     #[account(mut)]
     pub pool_token_out: Account<'info, TokenAccount>,
     #[account(mut)]
     pub admin_token_account: Account<'info, TokenAccount>,
-    #[account(signer)]
+    #[account(mut, signer)]
     pub user: Signer<'info>,
     pub token_program: Program<'info, Token>,
+    #[account(address = spl_token::native_mint::ID)]
+    pub sol_mint: Option<Account<'info, Mint>>,
+    /// CHECK: Temporary wSOL account, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [SWAP_VAULT_SEED, user.key().as_ref(), spl_token::native_mint::ID.as_ref()],
+        bump
+    )]
+    pub wsol_account: Option<UncheckedAccount<'info>>,
+    pub system_program: Option<Program<'info, System>>,
+    /// CHECK: Price account configured on the pool, matched by address and parsed in the handler.
+    pub price_feed: Option<UncheckedAccount<'info>>,
+    // Initialized ticks of a concentrated liquidity pool
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Option<Account<'info, TickArray>>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn swap(
     ctx: Context<Swap>,
     input_token_account: Pubkey,
     output_token_account: Pubkey,
     input_amount: u64,
     min_output_amount: u64,
+    deadline: Option<i64>,
+    max_price_impact_bps: Option<u16>,
+    limit_price_x64: Option<u128>,
 ) -> Result<()> {
-    let pool = &mut ctx.accounts.pool;
+    let user_key = ctx.accounts.user.key();
+    let pool_key = ctx.accounts.pool.key();
+    let clock = Clock::get()?;
+
+    // Reject transactions that landed too late
+    if let Some(deadline) = deadline {
+        require!(
+            clock.unix_timestamp <= deadline,
+            SwapError::DeadlineExceeded
+        );
+    }
+
+    let quote = apply_swap(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &ctx.accounts.price_feed,
+        (
+            ctx.accounts.pool_token_in.key(),
+            ctx.accounts.pool_token_out.key(),
+        ),
+        input_token_account,
+        output_token_account,
+        input_amount,
+        min_output_amount,
+        (max_price_impact_bps, limit_price_x64),
+        &clock,
+    )?;
+    let (output_amount, fee_amount) = (quote.output_amount, quote.fee_amount);
+
+    // Native SOL is wrapped on the way in and unwrapped on the way out
+    let native = match (&ctx.accounts.user_token_in, &ctx.accounts.user_token_out) {
+        (Some(_), Some(_)) => None,
+        (None, None) => return err!(FlashFillError::MissingTokenAccount),
+        (user_token_in, _) => {
+            let native = NativeSol::resolve(
+                &ctx.accounts.sol_mint,
+                &ctx.accounts.wsol_account,
+                &ctx.accounts.system_program,
+            )?;
+            let wsol_bump = [ctx.bumps.wsol_account.ok_or(SwapError::BumpNotFound)?];
+            let wrap_amount = if user_token_in.is_none() {
+                input_amount
+            } else {
+                0
+            };
+            native.wrap(
+                ctx.accounts.user.to_account_info(),
+                ctx.accounts.token_program.clone(),
+                &[&[
+                    SWAP_VAULT_SEED,
+                    user_key.as_ref(),
+                    spl_token::native_mint::ID.as_ref(),
+                    &wsol_bump,
+                ]],
+                wrap_amount,
+            )?;
+            Some(native)
+        }
+    };
+    let user_token_in = NativeSol::token_account(&ctx.accounts.user_token_in, &native)?;
+    let user_token_out = NativeSol::token_account(&ctx.accounts.user_token_out, &native)?;
+
+    // Transfer fee to the admin account
+    let cpi_accounts_fee = token::Transfer {
+        from: user_token_in.clone(),
+        to: ctx.accounts.admin_token_account.to_account_info(),
+        authority: ctx.accounts.user.to_account_info(),
+    };
+    let cpi_ctx_fee = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        cpi_accounts_fee,
+    );
+    token::transfer(cpi_ctx_fee, fee_amount)?;
+
+    // Transfer tokens from user to pool
+    let cpi_accounts_in = token::Transfer {
+        from: user_token_in,
+        to: ctx.accounts.pool_token_in.to_account_info(),
+        authority: ctx.accounts.user.to_account_info(), // User must authorize this transfer
+    };
+    let cpi_ctx_in = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        cpi_accounts_in,
+    );
+    token::transfer(cpi_ctx_in, input_amount - fee_amount)?;
+
+    // Transfer tokens from pool to user (pool_authority must authorize this)
+    let cpi_accounts_out = token::Transfer {
+        from: ctx.accounts.pool_token_out.to_account_info(),
+        to: user_token_out,
+        authority: ctx.accounts.pool_authority.to_account_info(), // Pool authority must authorize this transfer
+    };
+    let cpi_ctx_out = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        cpi_accounts_out,
+    );
+    token::transfer(cpi_ctx_out, output_amount)?;
+
+    if let Some(native) = &native {
+        native.unwrap(
+            ctx.accounts.user.to_account_info(),
+            ctx.accounts.token_program.clone(),
+        )?;
+    }
+
+    emit!(SwapEvent {
+        pool: pool_key,
+        user: user_key,
+        input_amount,
+        output_amount,
+        fee_amount: fee_amount + quote.lp_fee_amount,
+        fee_bps: quote.fee_bps,
+    });
+
+    Ok(())
+}
+
+// Outcome of a swap applied to the pool state, before any tokens move
+struct SwapQuote {
+    output_amount: u64,
+    fee_amount: u64,    // Taken from the input for the admin
+    lp_fee_amount: u64, // Left in a concentrated pool for the positions in range
+    fee_bps: u16,
+}
+
+// Prices a swap against the pool and applies it to the pool state.
+// A swap that would trip the circuit breaker is rejected.
+// The trade may move the price by at most max_price_impact_bps against the spot
+// price, and may not leave the input priced below limit_price_x64 output units.
+#[allow(clippy::too_many_arguments)]
+fn apply_swap(
+    pool: &mut Account<Pool>,
+    tick_array: &mut Option<Account<TickArray>>,
+    price_feed: &Option<UncheckedAccount>,
+    (pool_vault_in, pool_vault_out): (Pubkey, Pubkey),
+    input_token_account: Pubkey,
+    output_token_account: Pubkey,
+    input_amount: u64,
+    min_output_amount: u64,
+    (max_price_impact_bps, limit_price_x64): (Option<u16>, Option<u128>),
+    clock: &Clock,
+) -> Result<SwapQuote> {
+    let pool: &mut Pool = pool;
 
     // Validate input amounts
     require!(input_amount > 0, SwapError::InvalidAmount);
     require!(min_output_amount > 0, SwapError::InvalidAmount);
 
+    require!(!pool.circuit_breaker.halted, SwapError::PoolHalted);
+    pool.settle_twamm(clock.slot)?;
+    pool.refresh_circuit_breaker(clock.slot);
+    pool.refresh_price_band(clock.slot);
+    let fee_bps = pool.dynamic_fee_bps(clock.slot).unwrap_or(FLAT_FEE_BPS);
+
     // Find the input and output tokens in the pool using input_token_account and output_token_account
     let (pool_token_in, pool_token_out) = {
         let mut in_token = None;
@@ -293,12 +888,118 @@ pub fn swap(
     };
 
     // Calculate the output amount based on the pool’s reserves and input amount
-    let (output_amount, fee_amount) =
-        calculate_swap_amount(input_amount, pool_token_in.amount, pool_token_out.amount)?;
+    let mut concentrated_update = None;
+    let (mut output_amount, fee_amount) = match pool.curve {
+        CurveType::ConstantProduct => calculate_swap_amount_with_fee(
+            input_amount,
+            pool_token_in.amount,
+            pool_token_out.amount,
+            fee_bps,
+        )?,
+        CurveType::Pmm { k_bps } => {
+            let guard = &pool.oracle;
+            let base_is_input = guard
+                .base_is_input(input_token_account, output_token_account)
+                .ok_or(SwapError::TokenNotFound)?;
+            let feed = load_price_feed(guard, price_feed, clock)?;
+            let (quote_per_base, base_unit) =
+                oracle_price_ratio(&feed, guard.base_decimals, guard.quote_decimals)?;
+
+            // Price of one output unit in input units
+            let (price_numerator, price_denominator) = if base_is_input {
+                (base_unit, quote_per_base)
+            } else {
+                (quote_per_base, base_unit)
+            };
+            calculate_pmm_swap_amount(
+                input_amount,
+                pool_token_out.amount,
+                pool_token_out.target_amount,
+                price_numerator,
+                price_denominator,
+                k_bps,
+                fee_bps,
+            )?
+        }
+        CurveType::Weighted => {
+            let (input_weight_bps, output_weight_bps) = pool
+                .weights
+                .weights_bps(
+                    input_token_account,
+                    output_token_account,
+                    clock.unix_timestamp,
+                )
+                .ok_or(SwapError::TokenNotFound)?;
+            calculate_weighted_swap_amount(
+                input_amount,
+                pool_token_in.amount,
+                pool_token_out.amount,
+                input_weight_bps,
+                output_weight_bps,
+                fee_bps,
+            )?
+        }
+        CurveType::Concentrated { .. } => {
+            let tick_array = tick_array.as_ref().ok_or(SwapError::UnsupportedCurve)?;
+            let a_is_input = pool
+                .concentrated
+                .a_is_input(input_token_account, output_token_account)
+                .ok_or(SwapError::TokenNotFound)?;
+            let (vault_in, vault_out) = if a_is_input {
+                (pool.concentrated.vault_a, pool.concentrated.vault_b)
+            } else {
+                (pool.concentrated.vault_b, pool.concentrated.vault_a)
+            };
+            require_keys_eq!(pool_vault_in, vault_in, SwapError::TokenNotFound);
+            require_keys_eq!(pool_vault_out, vault_out, SwapError::TokenNotFound);
+
+            // Swap on copies, committed once the swap is sure to execute
+            let mut state = pool.concentrated.clone();
+            let mut ticks = tick_array.ticks.clone();
+            let (output_amount, lp_fee_amount) =
+                swap_concentrated(&mut state, &mut ticks, input_amount, fee_bps, a_is_input)?;
+            concentrated_update = Some((state, ticks, lp_fee_amount));
+
+            // The fee stays in the vault and accrues to the positions in range
+            (output_amount, 0)
+        }
+    };
+
+    // Swaps priced too far from the start of slot price are typical of sandwiches.
+    // They pay a surcharge that stays in the pool for LPs, or fail without one.
+    let band = &pool.price_band;
+    if band.band_bps > 0 {
+        let deviation_bps = calculate_price_move_bps(
+            pool_token_in.slot_start_amount,
+            pool_token_out.slot_start_amount,
+            input_amount,
+            output_amount,
+        )?;
+        if deviation_bps > band.band_bps as u64 {
+            require!(band.surcharge_bps > 0, SwapError::PriceBandExceeded);
+            output_amount = output_amount
+                .checked_sub(calculate_fee(output_amount, band.surcharge_bps)?)
+                .ok_or(SwapError::MathError)?;
+        }
+    }
 
     // Ensure the output amount meets the minimum output amount requirement
     require!(output_amount >= min_output_amount, SwapError::SlippageError);
 
+    // Ensure the trade does not move too far from the spot price
+    if let Some(max_price_impact_bps) = max_price_impact_bps {
+        let price_impact_bps = calculate_price_impact_bps(
+            input_amount,
+            output_amount,
+            pool_token_in.amount,
+            pool_token_out.amount,
+        )?;
+        require!(
+            price_impact_bps <= max_price_impact_bps as u64,
+            SwapError::PriceImpactTooHigh
+        );
+    }
+
     // Validate pool liquidity
     require!(
         pool_token_in.amount >= input_amount,
@@ -308,145 +1009,905 @@ pub fn swap(
         pool_token_out.amount >= output_amount,
         SwapError::InsufficientLiquidity
     );
-
-    // Transfer fee to the admin account
-    let cpi_accounts_fee = token::Transfer {
-        from: ctx.accounts.user_token_in.to_account_info(),
-        to: ctx.accounts.admin_token_account.to_account_info(),
-        authority: ctx.accounts.user.to_account_info(),
-    };
-    let cpi_ctx_fee = CpiContext::new(
-        ctx.accounts.token_program.to_account_info(),
-        cpi_accounts_fee,
-    );
-    token::transfer(cpi_ctx_fee, fee_amount)?;
-
-    // Transfer tokens from user to pool
-    let cpi_accounts_in = token::Transfer {
-        from: ctx.accounts.user_token_in.to_account_info(),
-        to: ctx.accounts.pool_token_in.to_account_info(),
-        authority: ctx.accounts.user.to_account_info(), // User must authorize this transfer
-    };
-    let cpi_ctx_in = CpiContext::new(
-        ctx.accounts.token_program.to_account_info(),
-        cpi_accounts_in,
-    );
-    token::transfer(cpi_ctx_in, input_amount - fee_amount)?;
-
-    // Transfer tokens from pool to user (pool_authority must authorize this)
-    let cpi_accounts_out = token::Transfer {
-        from: ctx.accounts.pool_token_out.to_account_info(),
-        to: ctx.accounts.user_token_out.to_account_info(),
-        authority: ctx.accounts.pool_authority.to_account_info(), // Pool authority must authorize this transfer
-    };
-    let cpi_ctx_out = CpiContext::new(
-        ctx.accounts.token_program.to_account_info(),
-        cpi_accounts_out,
-    );
-    token::transfer(cpi_ctx_out, output_amount)?;
-
-    // Update the pool state
-    pool_token_in.amount = pool_token_in
+    let input_after = pool_token_in
         .amount
         .checked_add(input_amount)
-        .ok_or(SwapError::MathError)?
-        .checked_sub(fee_amount)
+        .and_then(|amount| amount.checked_sub(fee_amount))
         .ok_or(SwapError::MathError)?;
-    pool_token_out.amount = pool_token_out
+    let output_after = pool_token_out
         .amount
         .checked_sub(output_amount)
         .ok_or(SwapError::MathError)?;
 
-    Ok(())
-}
+    // PMM pools already trade at the oracle price, so their reserves carry no price
+    if pool.curve == CurveType::ConstantProduct {
+        check_oracle_price(
+            &pool.oracle,
+            price_feed,
+            clock,
+            (input_token_account, output_token_account),
+            (input_after, output_after),
+        )?;
+    }
+
+    // Reject a swap that breaches the circuit breaker
+    let price_move_bps = calculate_price_move_bps(
+        pool_token_in.reference_amount,
+        pool_token_out.reference_amount,
+        input_after,
+        output_after,
+    )?;
+    let breaker = &mut pool.circuit_breaker;
+    let slot_volume = breaker.slot_volume.saturating_add(input_amount);
+    require!(
+        breaker.max_price_move_bps == 0 || price_move_bps <= breaker.max_price_move_bps as u64,
+        SwapError::CircuitBreakerTripped
+    );
+    require!(
+        breaker.max_volume_per_slot == 0 || slot_volume <= breaker.max_volume_per_slot,
+        SwapError::CircuitBreakerTripped
+    );
+    breaker.slot_volume = slot_volume;
+
+    let mut lp_fee_amount = 0;
+    if let Some((state, ticks, lp_fee)) = concentrated_update {
+        pool.concentrated = state;
+        if let Some(tick_array) = tick_array.as_mut() {
+            tick_array.ticks = ticks;
+        }
+        lp_fee_amount = lp_fee;
+    }
+
+    // Update the pool state
+    let (input_reserve, output_reserve) = (pool_token_in.amount, pool_token_out.amount);
+    pool_token_in.amount = input_after;
+    pool_token_out.amount = output_after;
+
+    // Every swap feeds the volatility accumulator behind the dynamic fee
+    let swap_move_bps = calculate_price_move_bps(
+        input_reserve,
+        output_reserve,
+        pool_token_in.amount,
+        pool_token_out.amount,
+    )?;
+    pool.record_volatility(swap_move_bps);
+
+    // Ensure the trade does not push the price past the caller's limit
+    if let Some(limit_price_x64) = limit_price_x64 {
+        let price_x64 = pool.spot_price_x64(
+            input_token_account,
+            output_token_account,
+            clock.unix_timestamp,
+        )?;
+        require!(price_x64 >= limit_price_x64, SwapError::PriceLimitExceeded);
+    }
+
+    Ok(SwapQuote {
+        output_amount,
+        fee_amount,
+        lp_fee_amount,
+        fee_bps,
+    })
+}
+
+// Exact input swap across the initialized ticks of a concentrated liquidity pool.
+// Returns the output and the fee left in the pool for the positions in range.
+fn swap_concentrated(
+    state: &mut ConcentratedLiquidity,
+    ticks: &mut [Tick],
+    input_amount: u64,
+    fee_bps: u16,
+    a_to_b: bool,
+) -> Result<(u64, u64)> {
+    let mut amount_remaining = input_amount;
+    let mut output_amount = 0u64;
+    let mut fee_amount = 0u64;
+
+    while amount_remaining > 0 {
+        // Next initialized tick in the swap direction, or the end of the price range
+        let next_tick = if a_to_b {
+            ticks
+                .iter()
+                .rposition(|tick| tick.index <= state.tick_current)
+        } else {
+            ticks
+                .iter()
+                .position(|tick| tick.index > state.tick_current)
+        };
+        let target_tick = match next_tick {
+            Some(position) => ticks[position].index,
+            None if a_to_b => MIN_TICK,
+            None => MAX_TICK,
+        };
+        let sqrt_price_target = sqrt_price_at_tick(target_tick)?;
+
+        let step = compute_swap_step(
+            state.sqrt_price_x64,
+            sqrt_price_target,
+            state.liquidity,
+            amount_remaining,
+            fee_bps,
+            a_to_b,
+        )?;
+        amount_remaining = amount_remaining
+            .checked_sub(step.amount_in)
+            .and_then(|amount| amount.checked_sub(step.fee_amount))
+            .ok_or(SwapError::MathError)?;
+        output_amount = output_amount
+            .checked_add(step.amount_out)
+            .ok_or(SwapError::MathError)?;
+        fee_amount = fee_amount
+            .checked_add(step.fee_amount)
+            .ok_or(SwapError::MathError)?;
+
+        if state.liquidity > 0 {
+            let fee_growth = mul_div(step.fee_amount as u128, Q64, state.liquidity, false)?;
+            if a_to_b {
+                state.fee_growth_global_a_x64 =
+                    state.fee_growth_global_a_x64.wrapping_add(fee_growth);
+            } else {
+                state.fee_growth_global_b_x64 =
+                    state.fee_growth_global_b_x64.wrapping_add(fee_growth);
+            }
+        }
+
+        state.sqrt_price_x64 = step.sqrt_price_next;
+        if step.sqrt_price_next != sqrt_price_target {
+            state.tick_current = tick_at_sqrt_price(state.sqrt_price_x64)?;
+            continue;
+        }
+
+        // The input outlasted every position
+        let position = next_tick.ok_or(SwapError::InsufficientLiquidity)?;
+        let tick = &mut ticks[position];
+        tick.cross(state.fee_growth_global_a_x64, state.fee_growth_global_b_x64);
+        if a_to_b {
+            let liquidity_net = tick
+                .liquidity_net
+                .checked_neg()
+                .ok_or(SwapError::MathError)?;
+            state.liquidity = state
+                .liquidity
+                .checked_add_signed(liquidity_net)
+                .ok_or(SwapError::MathError)?;
+            state.tick_current = tick.index - 1;
+        } else {
+            state.liquidity = state
+                .liquidity
+                .checked_add_signed(tick.liquidity_net)
+                .ok_or(SwapError::MathError)?;
+            state.tick_current = tick.index;
+        }
+    }
+
+    Ok((output_amount, fee_amount))
+}
+
+// Reads the pool's price feed, rejecting a wrong account or a stale price
+fn load_price_feed(
+    guard: &OracleGuard,
+    price_feed: &Option<UncheckedAccount>,
+    clock: &Clock,
+) -> Result<PriceFeed> {
+    let price_feed = price_feed.as_ref().ok_or(SwapError::InvalidOracle)?;
+    require_keys_eq!(price_feed.key(), guard.price_feed, SwapError::InvalidOracle);
+    let feed = parse_price_feed(&price_feed.try_borrow_data()?)?;
+
+    require!(
+        guard.max_staleness_slots == 0
+            || clock.slot.saturating_sub(feed.publish_slot) <= guard.max_staleness_slots,
+        SwapError::OracleStale
+    );
+    require!(
+        guard.max_staleness_seconds == 0
+            || clock.unix_timestamp.saturating_sub(feed.timestamp) <= guard.max_staleness_seconds,
+        SwapError::OracleStale
+    );
+
+    Ok(feed)
+}
+
+// Rejects trades that leave the reserves priced away from the oracle
+fn check_oracle_price(
+    guard: &OracleGuard,
+    price_feed: &Option<UncheckedAccount>,
+    clock: &Clock,
+    (input_token_account, output_token_account): (Pubkey, Pubkey),
+    (input_after, output_after): (u64, u64),
+) -> Result<()> {
+    if guard.price_feed == Pubkey::default() {
+        return Ok(());
+    }
+    let Some(base_is_input) = guard.base_is_input(input_token_account, output_token_account) else {
+        return Ok(());
+    };
+    let (base_reserve, quote_reserve) = if base_is_input {
+        (input_after, output_after)
+    } else {
+        (output_after, input_after)
+    };
+
+    let feed = load_price_feed(guard, price_feed, clock)?;
+    let divergence_bps = calculate_oracle_divergence_bps(
+        &feed,
+        base_reserve,
+        quote_reserve,
+        guard.base_decimals,
+        guard.quote_decimals,
+    )?;
+    require!(
+        divergence_bps <= guard.tolerance_bps as u64,
+        SwapError::OraclePriceDivergence
+    );
+
+    Ok(())
+}
+
+// Accounts needed to trade native SOL through the user's temporary wSOL account
+struct NativeSol<'info> {
+    sol_mint: AccountInfo<'info>,
+    wsol_account: AccountInfo<'info>,
+    system_program: Program<'info, System>,
+}
+
+impl<'info> NativeSol<'info> {
+    fn resolve(
+        sol_mint: &Option<Account<'info, Mint>>,
+        wsol_account: &Option<UncheckedAccount<'info>>,
+        system_program: &Option<Program<'info, System>>,
+    ) -> Result<Self> {
+        match (sol_mint, wsol_account, system_program) {
+            (Some(sol_mint), Some(wsol_account), Some(system_program)) => Ok(Self {
+                sol_mint: sol_mint.to_account_info(),
+                wsol_account: wsol_account.to_account_info(),
+                system_program: system_program.clone(),
+            }),
+            _ => err!(FlashFillError::MissingTokenAccount),
+        }
+    }
+
+    // The user's own token account, or the temporary wSOL account standing in for it
+    fn token_account(
+        user_token: &Option<Account<'info, TokenAccount>>,
+        native: &Option<Self>,
+    ) -> Result<AccountInfo<'info>> {
+        match (user_token, native) {
+            (Some(user_token), _) => Ok(user_token.to_account_info()),
+            (None, Some(native)) => Ok(native.wsol_account.clone()),
+            (None, None) => err!(FlashFillError::MissingTokenAccount),
+        }
+    }
+
+    // Opens the user's wSOL account and moves `amount` lamports into it
+    fn wrap(
+        &self,
+        user: AccountInfo<'info>,
+        token_program: Program<'info, Token>,
+        wsol_signer_seeds: &[&[&[u8]]],
+        amount: u64,
+    ) -> Result<()> {
+        create_token_vault_idempotent(
+            user.clone(),
+            self.wsol_account.clone(),
+            self.sol_mint.clone(),
+            user.clone(),
+            token_program.clone(),
+            self.system_program.clone(),
+            wsol_signer_seeds,
+        )?;
+
+        if amount > 0 {
+            msg!("Wrap SOL");
+            system_program::transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: user,
+                        to: self.wsol_account.clone(),
+                    },
+                ),
+                amount,
+            )?;
+            token::sync_native(CpiContext::new(
+                token_program.to_account_info(),
+                token::SyncNative {
+                    account: self.wsol_account.clone(),
+                },
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    // Closes the user's wSOL account, returning its balance and rent as SOL
+    fn unwrap(&self, user: AccountInfo<'info>, token_program: Program<'info, Token>) -> Result<()> {
+        close_token_vault(
+            self.wsol_account.clone(),
+            user.clone(),
+            user,
+            token_program,
+            &[],
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct FlashSwap<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(signer)]
+    pub pool_authority: Signer<'info>,
+    #[account(mut, token::authority = pool_authority)]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(mut, token::authority = pool_authority)]
+    pub pool_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(signer)]
+    pub user: Signer<'info>,
+    /// CHECK: Arbitrary program called back once the output has been sent
+    #[account(executable)]
+    pub receiver_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    /// CHECK: Price account configured on the pool, matched by address and parsed in the handler.
+    pub price_feed: Option<UncheckedAccount<'info>>,
+}
+
+pub fn flash_swap(
+    ctx: Context<FlashSwap>,
+    input_token_account: Pubkey,
+    output_token_account: Pubkey,
+    output_amount: u64,
+    callback_data: Vec<u8>,
+) -> Result<()> {
+    require!(output_amount > 0, SwapError::InvalidAmount);
+    require!(
+        ctx.accounts.pool_token_in.key() != ctx.accounts.pool_token_out.key(),
+        SwapError::TokenNotFound
+    );
+
+    // The reserves checked below must be the ones the tokens move through
+    require_keys_eq!(
+        ctx.accounts.pool_token_in.key(),
+        ctx.accounts.pool.vault(input_token_account),
+        SwapError::TokenNotFound
+    );
+    require_keys_eq!(
+        ctx.accounts.pool_token_out.key(),
+        ctx.accounts.pool.vault(output_token_account),
+        SwapError::TokenNotFound
+    );
+
+    let clock = Clock::get()?;
+    let slot = clock.slot;
+    require!(
+        !ctx.accounts.pool.circuit_breaker.halted,
+        SwapError::PoolHalted
+    );
+    ctx.accounts.pool.settle_twamm(slot)?;
+    ctx.accounts.pool.refresh_circuit_breaker(slot);
+    ctx.accounts.pool.refresh_price_band(slot);
+    let fee_bps = ctx
+        .accounts
+        .pool
+        .dynamic_fee_bps(slot)
+        .unwrap_or(FLAT_FEE_BPS);
+    // The callback is checked against the constant product invariant only
+    require!(
+        ctx.accounts.pool.curve == CurveType::ConstantProduct,
+        SwapError::UnsupportedCurve
+    );
+
+    let (input_reserve, output_reserve, slot_start_input, slot_start_output) = {
+        let pool = &ctx.accounts.pool;
+        let find_token = |token_account: Pubkey| {
+            pool.tokens
+                .iter()
+                .find(|token| token.token_account == token_account)
+                .ok_or(SwapError::TokenNotFound)
+        };
+        let input_token = find_token(input_token_account)?;
+        let output_token = find_token(output_token_account)?;
+        (
+            input_token.amount,
+            output_token.amount,
+            input_token.slot_start_amount,
+            output_token.slot_start_amount,
+        )
+    };
+    require!(
+        output_reserve > output_amount,
+        SwapError::InsufficientLiquidity
+    );
+
+    let vault_in_before = ctx.accounts.pool_token_in.amount;
+
+    // Send the output first (pool_authority must authorize this)
+    let cpi_accounts_out = token::Transfer {
+        from: ctx.accounts.pool_token_out.to_account_info(),
+        to: ctx.accounts.user_token_out.to_account_info(),
+        authority: ctx.accounts.pool_authority.to_account_info(),
+    };
+    let cpi_ctx_out = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        cpi_accounts_out,
+    );
+    token::transfer(cpi_ctx_out, output_amount)?;
+
+    // Hand control to the receiver, which has to pay the pool back. Only the user's
+    // signature is forwarded so the receiver can never act as the pool authority.
+    let user_key = ctx.accounts.user.key();
+    let accounts: Vec<AccountMeta> = ctx
+        .remaining_accounts
+        .iter()
+        .map(|acc| AccountMeta {
+            pubkey: *acc.key,
+            is_signer: acc.is_signer && *acc.key == user_key,
+            is_writable: acc.is_writable,
+        })
+        .collect();
+
+    msg!("Flash swap callback");
+    invoke_signed(
+        &Instruction {
+            program_id: ctx.accounts.receiver_program.key(),
+            accounts,
+            data: callback_data,
+        },
+        ctx.remaining_accounts,
+        &[],
+    )?;
+
+    // Measure what actually reached the pool and check k did not decrease
+    ctx.accounts.pool_token_in.reload()?;
+    ctx.accounts.pool_token_out.reload()?;
+    let input_amount = ctx
+        .accounts
+        .pool_token_in
+        .amount
+        .checked_sub(vault_in_before)
+        .ok_or(SwapError::MathError)?;
+
+    check_constant_product(
+        input_amount,
+        output_amount,
+        input_reserve,
+        output_reserve,
+        fee_bps,
+    )?;
+    let input_after = input_reserve
+        .checked_add(input_amount)
+        .ok_or(SwapError::MathError)?;
+    let output_after = output_reserve
+        .checked_sub(output_amount)
+        .ok_or(SwapError::MathError)?;
+    check_oracle_price(
+        &ctx.accounts.pool.oracle,
+        &ctx.accounts.price_feed,
+        &clock,
+        (input_token_account, output_token_account),
+        (input_after, output_after),
+    )?;
+
+    // The output has already left the pool, so neither the price band nor the
+    // circuit breaker can take a surcharge or halt instead of reverting the swap
+    let band = &ctx.accounts.pool.price_band;
+    if band.band_bps > 0 {
+        let deviation_bps = calculate_price_move_bps(
+            slot_start_input,
+            slot_start_output,
+            input_amount,
+            output_amount,
+        )?;
+        require!(
+            deviation_bps <= band.band_bps as u64,
+            SwapError::PriceBandExceeded
+        );
+    }
+
+    let (reference_input, reference_output) = {
+        let pool = &ctx.accounts.pool;
+        let find_reference = |token_account: Pubkey| {
+            pool.tokens
+                .iter()
+                .find(|token| token.token_account == token_account)
+                .map(|token| token.reference_amount)
+                .ok_or(SwapError::TokenNotFound)
+        };
+        (
+            find_reference(input_token_account)?,
+            find_reference(output_token_account)?,
+        )
+    };
+    let price_move_bps =
+        calculate_price_move_bps(reference_input, reference_output, input_after, output_after)?;
+    let breaker = &mut ctx.accounts.pool.circuit_breaker;
+    let slot_volume = breaker.slot_volume.saturating_add(input_amount);
+    require!(
+        breaker.max_price_move_bps == 0 || price_move_bps <= breaker.max_price_move_bps as u64,
+        SwapError::CircuitBreakerTripped
+    );
+    require!(
+        breaker.max_volume_per_slot == 0 || slot_volume <= breaker.max_volume_per_slot,
+        SwapError::CircuitBreakerTripped
+    );
+    breaker.slot_volume = slot_volume;
+
+    // Update the pool state
+    for token in ctx.accounts.pool.tokens.iter_mut() {
+        if token.token_account == input_token_account {
+            token.amount = token
+                .amount
+                .checked_add(input_amount)
+                .ok_or(SwapError::MathError)?;
+        } else if token.token_account == output_token_account {
+            token.amount = token
+                .amount
+                .checked_sub(output_amount)
+                .ok_or(SwapError::MathError)?;
+        }
+    }
+
+    // Flash swaps feed the volatility accumulator like any other swap
+    let swap_move_bps =
+        calculate_price_move_bps(input_reserve, output_reserve, input_after, output_after)?;
+    ctx.accounts.pool.record_volatility(swap_move_bps);
+
+    Ok(())
+}
 
 #[derive(Accounts)]
-pub struct SwapToSOL<'info> {
+#[instruction(output_mint: Pubkey)]
+pub struct SwapViaJupiter<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
     #[account(mut, seeds = [AUTHORITY_SEED], bump)]
     pub program_authority: SystemAccount<'info>,
-    /// CHECK: This may not be initialized yet.
-    #[account(mut, seeds = [WSOL_SEED], bump)]
-    pub program_wsol_account: UncheckedAccount<'info>,
-    pub user_account: Signer<'info>,
-    // #[account(address = spl_token::native_mint::id())]
-    pub sol_mint: Account<'info, Mint>,
+    /// CHECK: Temporary vault, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [SWAP_VAULT_SEED, user.key().as_ref(), output_mint.as_ref()],
+        bump
+    )]
+    pub swap_vault: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(address = output_mint)]
+    pub output_token_mint: Account<'info, Mint>,
+    // Receives the output. Omitted to unwrap wSOL output to native SOL.
+    #[account(mut, token::mint = output_token_mint, token::authority = user)]
+    pub user_token_out: Option<Account<'info, TokenAccount>>,
+    // Collects the platform fee on token output
+    #[account(
+        mut,
+        token::mint = output_token_mint,
+        token::authority = program_authority
+    )]
+    pub treasury_token_out: Option<Account<'info, TokenAccount>>,
     pub jupiter_program: Program<'info, Jupiter>,
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
-pub fn swap_to_sol(
-    ctx: Context<SwapToSOL>,
+pub fn swap_via_jupiter(
+    ctx: Context<SwapViaJupiter>,
+    output_mint: Pubkey,
+    in_amount: u64,
+    min_out: u64,
     data: Vec<u8>,
-    authority_bump: u8,
-    wsol_bump: u8,
 ) -> Result<()> {
-    create_wsol_token_idempotent(
-        ctx.accounts.program_authority.clone(),
-        ctx.accounts.program_wsol_account.clone(),
-        ctx.accounts.sol_mint.clone(),
+    let user_key = ctx.accounts.user.key();
+    let authority_bump = [ctx.bumps.program_authority];
+    let vault_bump = [ctx.bumps.swap_vault];
+    let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, &authority_bump];
+    let vault_seeds: &[&[u8]] = &[
+        SWAP_VAULT_SEED,
+        user_key.as_ref(),
+        output_mint.as_ref(),
+        &vault_bump,
+    ];
+
+    // The user pays the vault rent and gets it back when the vault is closed
+    let out_amount = swap_into_vault(
+        ctx.accounts.user.to_account_info(),
+        ctx.accounts.swap_vault.to_account_info(),
+        ctx.accounts.output_token_mint.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.jupiter_program.clone(),
         ctx.accounts.token_program.clone(),
         ctx.accounts.system_program.clone(),
-        &[authority_bump],
-        &[wsol_bump],
+        ctx.remaining_accounts,
+        data,
+        &[vault_seeds],
+        None,
+        (in_amount, min_out),
+    )?;
+
+    // The platform fee comes out of the measured output
+    let fee_amount = calculate_fee(out_amount, ctx.accounts.config.platform_fee_bps)?;
+    let net_amount = out_amount
+        .checked_sub(fee_amount)
+        .ok_or(SwapError::MathError)?;
+    require!(net_amount >= min_out, SwapError::SlippageError);
+
+    match &ctx.accounts.user_token_out {
+        Some(user_token_out) => {
+            if fee_amount > 0 {
+                let treasury_token_out = ctx
+                    .accounts
+                    .treasury_token_out
+                    .as_ref()
+                    .ok_or(FlashFillError::MissingTokenAccount)?;
+
+                msg!("Transfer platform fee to treasury");
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        token::Transfer {
+                            from: ctx.accounts.swap_vault.to_account_info(),
+                            to: treasury_token_out.to_account_info(),
+                            authority: ctx.accounts.program_authority.to_account_info(),
+                        },
+                        &[authority_seeds],
+                    ),
+                    fee_amount,
+                )?;
+            }
+
+            msg!("Transfer output to user");
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.swap_vault.to_account_info(),
+                        to: user_token_out.to_account_info(),
+                        authority: ctx.accounts.program_authority.to_account_info(),
+                    },
+                    &[authority_seeds],
+                ),
+                net_amount,
+            )?;
+
+            close_token_vault(
+                ctx.accounts.swap_vault.to_account_info(),
+                ctx.accounts.user.to_account_info(),
+                ctx.accounts.program_authority.to_account_info(),
+                ctx.accounts.token_program.clone(),
+                &[authority_seeds],
+            )?;
+        }
+        None => {
+            // Closing a wSOL account releases the output together with the rent
+            require_keys_eq!(
+                output_mint,
+                spl_token::native_mint::ID,
+                FlashFillError::MissingTokenAccount
+            );
+            msg!("Unwrap output to SOL");
+            close_token_vault(
+                ctx.accounts.swap_vault.to_account_info(),
+                ctx.accounts.user.to_account_info(),
+                ctx.accounts.program_authority.to_account_info(),
+                ctx.accounts.token_program.clone(),
+                &[authority_seeds],
+            )?;
+
+            if fee_amount > 0 {
+                msg!("Transfer platform fee to treasury");
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.user.to_account_info(),
+                            to: ctx.accounts.program_authority.to_account_info(),
+                        },
+                    ),
+                    fee_amount,
+                )?;
+            }
+        }
+    }
+
+    emit!(JupiterSwapEvent {
+        user: user_key,
+        output_mint,
+        gross_amount: out_amount,
+        fee_amount,
+        net_amount,
+    });
+
+    Ok(())
+}
+
+// Runs a Jupiter route into a temporary vault of the program authority and returns
+// the output, measured as the vault balance delta across the CPI.
+#[allow(clippy::too_many_arguments)]
+fn swap_into_vault<'info>(
+    payer: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    vault_authority: AccountInfo<'info>,
+    jupiter_program: Program<'info, Jupiter>,
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+    remaining_accounts: &[AccountInfo],
+    data: Vec<u8>,
+    vault_signer_seeds: &[&[&[u8]]],
+    route_signer: Option<(Pubkey, &[&[u8]])>,
+    route_limits: (u64, u64),
+) -> Result<u64> {
+    let vault_before = create_token_vault_idempotent(
+        payer,
+        vault.clone(),
+        mint,
+        vault_authority,
+        token_program,
+        system_program,
+        vault_signer_seeds,
     )?;
 
+    let rent = Rent::get()?;
+    let space = TokenAccount::LEN;
+    let token_lamports = rent.minimum_balance(space);
+    require!(
+        vault_before.amount == 0 && vault.lamports() <= token_lamports,
+        SwapError::VaultNotEmpty
+    );
+
     msg!("Swap on Jupiter");
     swap_on_jupiter(
-        ctx.remaining_accounts,
-        ctx.accounts.jupiter_program.clone(),
+        remaining_accounts,
+        jupiter_program,
         data,
+        route_signer,
+        route_limits,
     )?;
 
-    let after_swap_lamports = ctx.accounts.program_wsol_account.lamports();
+    let vault_after = {
+        let data = vault.try_borrow_data()?;
+        TokenAccount::try_deserialize(&mut data.as_ref())?
+    };
+    let out_amount = vault_after
+        .amount
+        .checked_sub(vault_before.amount)
+        .ok_or(SwapError::MathError)?;
+
+    Ok(out_amount)
+}
+
+#[derive(Accounts)]
+pub struct GaslessSwapToSol<'info> {
+    #[account(seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    /// CHECK: Temporary wSOL vault, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [GASLESS_VAULT_SEED, user.key().as_ref(), spl_token::native_mint::ID.as_ref()],
+        bump
+    )]
+    pub swap_vault: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    // Pays the transaction fee and the vault rent
+    #[account(mut)]
+    pub relayer: Signer<'info>,
+    #[account(address = spl_token::native_mint::ID)]
+    pub sol_mint: Account<'info, Mint>,
+    pub jupiter_program: Program<'info, Jupiter>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// The relayer is reimbursed its fee plus the vault rent, which together may not
+// exceed the max_relayer_fee the user signed.
+pub fn gasless_swap_to_sol(
+    ctx: Context<GaslessSwapToSol>,
+    relayer_fee: u64,
+    max_relayer_fee: u64,
+    in_amount: u64,
+    min_lamports_out: u64,
+    data: Vec<u8>,
+) -> Result<()> {
+    let user_key = ctx.accounts.user.key();
+    let authority_bump = [ctx.bumps.program_authority];
+    let vault_bump = [ctx.bumps.swap_vault];
+    let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, &authority_bump];
+    let vault_seeds: &[&[u8]] = &[
+        GASLESS_VAULT_SEED,
+        user_key.as_ref(),
+        spl_token::native_mint::ID.as_ref(),
+        &vault_bump,
+    ];
 
-    close_program_wsol(
-        ctx.accounts.program_authority.clone(),
-        ctx.accounts.program_wsol_account.clone(),
+    let out_amount = swap_into_vault(
+        ctx.accounts.relayer.to_account_info(),
+        ctx.accounts.swap_vault.to_account_info(),
+        ctx.accounts.sol_mint.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.jupiter_program.clone(),
         ctx.accounts.token_program.clone(),
-        &[authority_bump],
+        ctx.accounts.system_program.clone(),
+        ctx.remaining_accounts,
+        data,
+        &[vault_seeds],
+        None,
+        (in_amount, min_lamports_out),
     )?;
 
+    let fee_amount = calculate_fee(out_amount, ctx.accounts.config.platform_fee_bps)?;
+    let user_amount = out_amount
+        .checked_sub(fee_amount)
+        .and_then(|amount| amount.checked_sub(relayer_fee))
+        .ok_or(SwapError::InsufficientOutput)?;
+    require!(user_amount >= min_lamports_out, SwapError::SlippageError);
+
     let rent = Rent::get()?;
-    let space = TokenAccount::LEN;
-    let token_lamports = rent.minimum_balance(space);
-    let out_amount = after_swap_lamports - token_lamports;
+    let vault_rent = rent.minimum_balance(TokenAccount::LEN);
+    let relayer_amount = relayer_fee
+        .checked_add(vault_rent)
+        .ok_or(SwapError::MathError)?;
+    require!(relayer_amount <= max_relayer_fee, SwapError::InvalidFee);
 
-    msg!("Transfer SOL to user");
-    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, &[authority_bump]]];
-    let lamports = out_amount;
+    // Unwrap into the treasury, which keeps the platform fee and pays out the rest
+    close_token_vault(
+        ctx.accounts.swap_vault.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.token_program.clone(),
+        &[authority_seeds],
+    )?;
 
+    msg!("Reimburse relayer");
     system_program::transfer(
         CpiContext::new_with_signer(
             ctx.accounts.system_program.to_account_info(),
             system_program::Transfer {
                 from: ctx.accounts.program_authority.to_account_info(),
-                to: ctx.accounts.user_account.to_account_info(),
+                to: ctx.accounts.relayer.to_account_info(),
             },
-            signer_seeds,
+            &[authority_seeds],
+        ),
+        relayer_amount,
+    )?;
+
+    msg!("Transfer SOL to user");
+    system_program::transfer(
+        CpiContext::new_with_signer(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.program_authority.to_account_info(),
+                to: ctx.accounts.user.to_account_info(),
+            },
+            &[authority_seeds],
         ),
-        lamports,
+        user_amount,
     )?;
 
+    emit!(GaslessSwapEvent {
+        user: user_key,
+        relayer: ctx.accounts.relayer.key(),
+        gross_amount: out_amount,
+        fee_amount,
+        relayer_fee,
+        net_amount: user_amount,
+    });
+
     Ok(())
 }
 
-fn create_wsol_token_idempotent<'info>(
-    program_authority: SystemAccount<'info>,
-    program_wsol_account: UncheckedAccount<'info>,
-    sol_mint: Account<'info, Mint>,
+fn create_token_vault_idempotent<'info>(
+    payer: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    vault_authority: AccountInfo<'info>,
     token_program: Program<'info, Token>,
     system_program: Program<'info, System>,
-    authority_bump: &[u8],
-    wsol_bump: &[u8],
+    vault_signer_seeds: &[&[&[u8]]],
 ) -> Result<TokenAccount> {
-    if program_wsol_account.data_is_empty() {
-        let signer_seeds: &[&[&[u8]]] = &[
-            &[AUTHORITY_SEED, authority_bump.as_ref()],
-            &[WSOL_SEED, wsol_bump.as_ref()],
-        ];
-
-        msg!("Initialize program wSOL account");
+    if vault.data_is_empty() {
+        msg!("Initialize token vault account");
         let rent = Rent::get()?;
         let space = TokenAccount::LEN;
         let lamports = rent.minimum_balance(space);
@@ -454,52 +1915,69 @@ fn create_wsol_token_idempotent<'info>(
             CpiContext::new_with_signer(
                 system_program.to_account_info(),
                 system_program::CreateAccount {
-                    from: program_authority.to_account_info(),
-                    to: program_wsol_account.to_account_info(),
+                    from: payer,
+                    to: vault.clone(),
                 },
-                signer_seeds,
+                vault_signer_seeds,
             ),
             lamports,
             space as u64,
             token_program.key,
         )?;
 
-        msg!("Initialize program wSOL token account");
+        msg!("Initialize token vault");
         token::initialize_account3(CpiContext::new(
             token_program.to_account_info(),
             token::InitializeAccount3 {
-                account: program_wsol_account.to_account_info(),
-                mint: sol_mint.to_account_info(),
-                authority: program_authority.to_account_info(),
+                account: vault.clone(),
+                mint,
+                authority: vault_authority,
             },
         ))?;
 
-        let data = program_wsol_account.try_borrow_data()?;
-        let wsol_token_account = TokenAccount::try_deserialize(&mut data.as_ref())?;
+        let data = vault.try_borrow_data()?;
+        let vault_token_account = TokenAccount::try_deserialize(&mut data.as_ref())?;
 
-        Ok(wsol_token_account)
+        Ok(vault_token_account)
     } else {
-        let data = program_wsol_account.try_borrow_data()?;
-        let wsol_token_account = TokenAccount::try_deserialize(&mut data.as_ref())?;
-        if &wsol_token_account.owner != program_authority.key {
-            // TODO: throw error
+        let data = vault.try_borrow_data()?;
+        let vault_token_account = TokenAccount::try_deserialize(&mut data.as_ref())?;
+        if &vault_token_account.owner != vault_authority.key {
             return err!(SwapError::IncorrectOwner);
         }
+        if &vault_token_account.mint != mint.key {
+            return err!(FlashFillError::InvalidMint);
+        }
 
-        Ok(wsol_token_account)
+        Ok(vault_token_account)
     }
 }
 
+// Runs a Jupiter route. A program account spending the route input signs it
+// through route_signer, given with its seeds. The route may spend at most
+// max_in_amount and has to quote at least min_out_amount.
 fn swap_on_jupiter<'info>(
     remaining_accounts: &[AccountInfo],
     jupiter_program: Program<'info, Jupiter>,
     data: Vec<u8>,
-) -> ProgramResult {
+    route_signer: Option<(Pubkey, &[&[u8]])>,
+    (max_in_amount, min_out_amount): (u64, u64),
+) -> Result<()> {
+    // Only Jupiter route instructions are allowed
+    let route_args = jupiter::parse_route(&data)?;
+    msg!(
+        "Jupiter route: in {:?}, quoted out {}, slippage {} bps",
+        route_args.in_amount,
+        route_args.quoted_out_amount,
+        route_args.slippage_bps
+    );
+    route_args.check_amounts(max_in_amount, min_out_amount)?;
+
     let accounts: Vec<AccountMeta> = remaining_accounts
         .iter()
         .map(|acc| AccountMeta {
             pubkey: *acc.key,
-            is_signer: acc.is_signer,
+            is_signer: acc.is_signer || route_signer.is_some_and(|(signer, _)| signer == *acc.key),
             is_writable: acc.is_writable,
         })
         .collect();
@@ -509,8 +1987,6 @@ fn swap_on_jupiter<'info>(
         .map(|acc| AccountInfo { ..acc.clone() })
         .collect();
 
-    // TODO: Check the first 8 bytes. Only Jupiter Route CPI allowed.
-
     invoke_signed(
         &Instruction {
             program_id: *jupiter_program.key,
@@ -518,26 +1994,3196 @@ fn swap_on_jupiter<'info>(
             data,
         },
         &accounts_infos,
-        &[],
-    )
+        &route_signer
+            .iter()
+            .map(|(_, seeds)| *seeds)
+            .collect::<Vec<_>>(),
+    )?;
+
+    Ok(())
 }
 
-fn close_program_wsol<'info>(
-    program_authority: SystemAccount<'info>,
-    program_wsol_account: UncheckedAccount<'info>,
+fn close_token_vault<'info>(
+    vault: AccountInfo<'info>,
+    destination: AccountInfo<'info>,
+    vault_authority: AccountInfo<'info>,
     token_program: Program<'info, Token>,
-    authority_bump: &[u8],
+    authority_signer_seeds: &[&[&[u8]]],
 ) -> Result<()> {
-    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, authority_bump.as_ref()]];
-
-    msg!("Close program wSOL token account");
+    msg!("Close token vault");
     token::close_account(CpiContext::new_with_signer(
         token_program.to_account_info(),
         token::CloseAccount {
-            account: program_wsol_account.to_account_info(),
-            destination: program_authority.to_account_info(),
-            authority: program_authority.to_account_info(),
+            account: vault,
+            destination,
+            authority: vault_authority,
         },
-        signer_seeds,
+        authority_signer_seeds,
     ))
 }
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(init, payer = authority, space = ProgramConfig::LEN, seeds = [CONFIG_SEED], bump)]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    // Only the program's upgrade authority may create the config and become its authority
+    #[account(
+        seeds = [crate::ID.as_ref()],
+        bump,
+        seeds::program = bpf_loader_upgradeable::ID,
+        constraint = program_data.upgrade_authority_address == Some(authority.key()) @ SwapError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_config(ctx: Context<InitializeConfig>, flash_loan_fee_bps: u16) -> Result<()> {
+    require!(
+        flash_loan_fee_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidFee
+    );
+
+    let config = &mut ctx.accounts.config;
+    config.authority = ctx.accounts.authority.key();
+    config.flash_loan_fee_bps = flash_loan_fee_bps;
+    config.bump = ctx.bumps.config;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ManagePool<'info> {
+    #[account(mut, has_one = authority @ SwapError::Unauthorized)]
+    pub pool: Account<'info, Pool>,
+    pub authority: Signer<'info>,
+}
+
+pub fn configure_circuit_breaker(
+    ctx: Context<ManagePool>,
+    max_price_move_bps: u16,
+    window_slots: u64,
+    max_volume_per_slot: u64,
+) -> Result<()> {
+    require!(
+        max_price_move_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidAmount
+    );
+
+    let slot = Clock::get()?.slot;
+    let pool = &mut ctx.accounts.pool;
+    pool.circuit_breaker.max_price_move_bps = max_price_move_bps;
+    pool.circuit_breaker.window_slots = window_slots;
+    pool.circuit_breaker.max_volume_per_slot = max_volume_per_slot;
+    pool.reset_circuit_breaker(slot);
+
+    Ok(())
+}
+
+pub fn configure_price_band(
+    ctx: Context<ManagePool>,
+    band_bps: u16,
+    surcharge_bps: u16,
+) -> Result<()> {
+    require!(band_bps as u64 <= BPS_DENOMINATOR, SwapError::InvalidAmount);
+    require!(
+        surcharge_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidFee
+    );
+
+    let pool = &mut ctx.accounts.pool;
+    pool.price_band.band_bps = band_bps;
+    pool.price_band.surcharge_bps = surcharge_bps;
+
+    Ok(())
+}
+
+pub fn configure_dynamic_fee(
+    ctx: Context<ManagePool>,
+    base_fee_bps: u16,
+    max_fee_bps: u16,
+    variable_fee_control: u16,
+    decay_slots: u64,
+) -> Result<()> {
+    require!(
+        base_fee_bps <= max_fee_bps && max_fee_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidFee
+    );
+    require!(
+        max_fee_bps == 0 || decay_slots > 0,
+        SwapError::InvalidAmount
+    );
+
+    let slot = Clock::get()?.slot;
+    let fee = &mut ctx.accounts.pool.dynamic_fee;
+    fee.base_fee_bps = base_fee_bps;
+    fee.max_fee_bps = max_fee_bps;
+    fee.variable_fee_control = variable_fee_control;
+    fee.decay_slots = decay_slots;
+    fee.volatility_accumulator = 0;
+    fee.last_update_slot = slot;
+
+    Ok(())
+}
+
+pub fn configure_oracle(ctx: Context<ManagePool>, oracle: OracleGuard) -> Result<()> {
+    require!(
+        oracle.tolerance_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidAmount
+    );
+    require!(oracle.max_staleness_seconds >= 0, SwapError::InvalidAmount);
+
+    ctx.accounts.pool.oracle = oracle;
+
+    Ok(())
+}
+
+pub fn configure_curve(ctx: Context<ManagePool>, curve: CurveType) -> Result<()> {
+    let pool = &mut ctx.accounts.pool;
+    // Concentrated pools hold their liquidity in positions and are set up through
+    // initialize_concentrated only
+    require!(
+        !matches!(pool.curve, CurveType::Concentrated { .. }),
+        SwapError::UnsupportedCurve
+    );
+    match curve {
+        CurveType::ConstantProduct => {}
+        CurveType::Pmm { k_bps } => {
+            require!(k_bps as u64 <= BPS_DENOMINATOR, SwapError::InvalidAmount);
+            // Long-term orders trade along the constant product curve
+            require!(
+                pool.twamm.token_a == Pubkey::default(),
+                SwapError::UnsupportedCurve
+            );
+            // The PMM curve prices every trade from the pool's oracle
+            require!(
+                pool.oracle.price_feed != Pubkey::default(),
+                SwapError::InvalidOracle
+            );
+        }
+        // Weighted pools need a weight schedule, set through configure_lbp
+        CurveType::Concentrated { .. } | CurveType::Weighted => {
+            return err!(SwapError::UnsupportedCurve)
+        }
+    }
+
+    pool.curve = curve;
+
+    Ok(())
+}
+
+// Turns the pool into a weighted pool of two of its tokens, with weights moving
+// along the schedule. Liquidity stays with the pool authority until the schedule ends.
+pub fn configure_lbp(ctx: Context<ManagePool>, weights: WeightSchedule) -> Result<()> {
+    let pool = &mut ctx.accounts.pool;
+    require!(
+        matches!(pool.curve, CurveType::ConstantProduct | CurveType::Weighted),
+        SwapError::UnsupportedCurve
+    );
+    // Long-term orders trade along the constant product curve
+    require!(
+        pool.twamm.token_a == Pubkey::default(),
+        SwapError::UnsupportedCurve
+    );
+    require!(
+        weights.token_a != weights.token_b
+            && [weights.token_a, weights.token_b]
+                .iter()
+                .all(|key| pool.tokens.iter().any(|token| token.token_account == *key)),
+        SwapError::TokenNotFound
+    );
+    let valid_weight = |weight_bps: u16| weight_bps > 0 && (weight_bps as u64) < BPS_DENOMINATOR;
+    require!(
+        valid_weight(weights.start_weight_a_bps) && valid_weight(weights.end_weight_a_bps),
+        SwapError::InvalidAmount
+    );
+    require!(
+        weights.start_time <= weights.end_time,
+        SwapError::InvalidAmount
+    );
+
+    pool.weights = weights;
+    pool.curve = CurveType::Weighted;
+
+    Ok(())
+}
+
+pub fn halt_pool(ctx: Context<ManagePool>) -> Result<()> {
+    ctx.accounts.pool.circuit_breaker.halted = true;
+
+    msg!("Pool halted");
+    Ok(())
+}
+
+pub fn reset_circuit_breaker(ctx: Context<ManagePool>) -> Result<()> {
+    let slot = Clock::get()?.slot;
+    ctx.accounts.pool.reset_circuit_breaker(slot);
+
+    msg!("Circuit breaker reset");
+    Ok(())
+}
+
+// Sets the pair long-term orders sell into, or disables them with default keys.
+// Only possible while no long-term order is open.
+pub fn configure_twamm(ctx: Context<ManagePool>, token_a: Pubkey, token_b: Pubkey) -> Result<()> {
+    let slot = Clock::get()?.slot;
+    let pool = &mut ctx.accounts.pool;
+    pool.settle_twamm(slot)?;
+    require!(pool.twamm.expiries.is_empty(), SwapError::TwammOrdersOpen);
+
+    if token_a != Pubkey::default() || token_b != Pubkey::default() {
+        require!(
+            pool.curve == CurveType::ConstantProduct,
+            SwapError::UnsupportedCurve
+        );
+        require!(
+            token_a != token_b
+                && [token_a, token_b]
+                    .iter()
+                    .all(|key| pool.tokens.iter().any(|token| token.token_account == *key)),
+            SwapError::TokenNotFound
+        );
+    }
+
+    pool.twamm.token_a = token_a;
+    pool.twamm.token_b = token_b;
+    pool.twamm.last_settled_slot = slot;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeConcentrated<'info> {
+    #[account(mut, has_one = authority @ SwapError::Unauthorized)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = authority,
+        space = TickArray::LEN,
+        seeds = [TICK_ARRAY_SEED, pool.key().as_ref()],
+        bump
+    )]
+    pub tick_array: Account<'info, TickArray>,
+    #[account(token::authority = pool)]
+    pub pool_token_a: Account<'info, TokenAccount>,
+    #[account(token::authority = pool)]
+    pub pool_token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_concentrated(
+    ctx: Context<InitializeConcentrated>,
+    token_a: Pubkey,
+    token_b: Pubkey,
+    tick_spacing: u16,
+    sqrt_price_x64: u128,
+) -> Result<()> {
+    require!(tick_spacing > 0, SwapError::InvalidTick);
+    require!(
+        sqrt_price_x64 >= sqrt_price_at_tick(MIN_TICK)?
+            && sqrt_price_x64 < sqrt_price_at_tick(MAX_TICK)?,
+        SwapError::InvalidTick
+    );
+    require!(
+        ctx.accounts.pool_token_a.mint != ctx.accounts.pool_token_b.mint,
+        FlashFillError::InvalidMint
+    );
+
+    let pool = &mut ctx.accounts.pool;
+    require!(
+        pool.curve == CurveType::ConstantProduct && pool.twamm.token_a == Pubkey::default(),
+        SwapError::UnsupportedCurve
+    );
+    // Both tokens have to be registered and empty, positions hold all the liquidity
+    for token_account in [token_a, token_b] {
+        let token = pool
+            .tokens
+            .iter()
+            .find(|token| token.token_account == token_account)
+            .ok_or(SwapError::TokenNotFound)?;
+        require!(token.amount == 0, SwapError::VaultNotEmpty);
+    }
+    require_keys_neq!(token_a, token_b, SwapError::TokenNotFound);
+
+    pool.curve = CurveType::Concentrated { tick_spacing };
+    pool.concentrated = ConcentratedLiquidity {
+        token_a,
+        token_b,
+        vault_a: ctx.accounts.pool_token_a.key(),
+        vault_b: ctx.accounts.pool_token_b.key(),
+        sqrt_price_x64,
+        tick_current: tick_at_sqrt_price(sqrt_price_x64)?,
+        ..Default::default()
+    };
+
+    let tick_array = &mut ctx.accounts.tick_array;
+    tick_array.pool = pool.key();
+    tick_array.bump = ctx.bumps.tick_array;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(tick_lower: i32, tick_upper: i32)]
+pub struct OpenPosition<'info> {
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = owner,
+        space = Position::LEN,
+        seeds = [
+            POSITION_SEED,
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &tick_lower.to_le_bytes(),
+            &tick_upper.to_le_bytes()
+        ],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn open_position(ctx: Context<OpenPosition>, tick_lower: i32, tick_upper: i32) -> Result<()> {
+    let CurveType::Concentrated { tick_spacing } = ctx.accounts.pool.curve else {
+        return err!(SwapError::UnsupportedCurve);
+    };
+    let tick_spacing = tick_spacing as i32;
+    require!(
+        tick_lower < tick_upper && tick_lower >= MIN_TICK && tick_upper <= MAX_TICK,
+        SwapError::InvalidTick
+    );
+    require!(
+        tick_lower % tick_spacing == 0 && tick_upper % tick_spacing == 0,
+        SwapError::InvalidTick
+    );
+
+    let position = &mut ctx.accounts.position;
+    position.pool = ctx.accounts.pool.key();
+    position.owner = ctx.accounts.owner.key();
+    position.tick_lower = tick_lower;
+    position.tick_upper = tick_upper;
+    position.bump = ctx.bumps.position;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MintPositionNft<'info> {
+    #[account(mut, has_one = owner @ SwapError::Unauthorized)]
+    pub position: Account<'info, Position>,
+    #[account(seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    #[account(
+        init,
+        payer = owner,
+        seeds = [POSITION_MINT_SEED, position.key().as_ref()],
+        bump,
+        mint::decimals = 0,
+        mint::authority = program_authority
+    )]
+    pub position_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = owner,
+        associated_token::mint = position_mint,
+        associated_token::authority = owner
+    )]
+    pub position_token: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+// Mints a single token representing the position and drops the mint authority, so
+// whoever holds the token controls the position from then on
+pub fn mint_position_nft(ctx: Context<MintPositionNft>) -> Result<()> {
+    let authority_bump = [ctx.bumps.program_authority];
+    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, &authority_bump]];
+
+    token::mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::MintTo {
+                mint: ctx.accounts.position_mint.to_account_info(),
+                to: ctx.accounts.position_token.to_account_info(),
+                authority: ctx.accounts.program_authority.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        1,
+    )?;
+    token::set_authority(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::SetAuthority {
+                account_or_mint: ctx.accounts.position_mint.to_account_info(),
+                current_authority: ctx.accounts.program_authority.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        spl_token::instruction::AuthorityType::MintTokens,
+        None,
+    )?;
+
+    ctx.accounts.position.nft_mint = ctx.accounts.position_mint.key();
+
+    Ok(())
+}
+
+// The position owner, or the holder of the position NFT once one is minted
+fn check_position_owner(
+    position: &Position,
+    owner: &Signer,
+    position_token: &Option<Account<TokenAccount>>,
+) -> Result<()> {
+    if position.nft_mint == Pubkey::default() {
+        require_keys_eq!(position.owner, owner.key(), SwapError::Unauthorized);
+        return Ok(());
+    }
+
+    let position_token = position_token
+        .as_ref()
+        .ok_or(FlashFillError::MissingTokenAccount)?;
+    require_keys_eq!(
+        position_token.mint,
+        position.nft_mint,
+        FlashFillError::InvalidMint
+    );
+    require_keys_eq!(position_token.owner, owner.key(), SwapError::Unauthorized);
+    require!(position_token.amount == 1, SwapError::Unauthorized);
+
+    Ok(())
+}
+
+// Applies a liquidity change to a position and its ticks, crediting the fees it
+// earned so far. Returns the token amounts the change is worth.
+fn modify_position(
+    pool: &mut Pool,
+    tick_array: &mut TickArray,
+    position: &mut Position,
+    liquidity_delta: i128,
+) -> Result<(u64, u64)> {
+    let state = &mut pool.concentrated;
+    let (tick_lower, tick_upper) = (position.tick_lower, position.tick_upper);
+
+    if liquidity_delta != 0 {
+        tick_array.update(tick_lower, false, liquidity_delta, state)?;
+        tick_array.update(tick_upper, true, liquidity_delta, state)?;
+    }
+    if position.liquidity > 0 || liquidity_delta != 0 {
+        let (inside_a, inside_b) = tick_array.fee_growth_inside(tick_lower, tick_upper, state)?;
+        position.update(liquidity_delta, inside_a, inside_b)?;
+    }
+    tick_array.clear_unused();
+
+    if (tick_lower..tick_upper).contains(&state.tick_current) {
+        state.liquidity = state
+            .liquidity
+            .checked_add_signed(liquidity_delta)
+            .ok_or(SwapError::MathError)?;
+    }
+
+    // Deposits round up and withdrawals round down, in favour of the pool
+    let amounts = liquidity_amounts(
+        state.sqrt_price_x64,
+        sqrt_price_at_tick(tick_lower)?,
+        sqrt_price_at_tick(tick_upper)?,
+        liquidity_delta.unsigned_abs(),
+        liquidity_delta > 0,
+    )?;
+    Ok(amounts)
+}
+
+// Keeps the pool token entries in line with the concentrated liquidity vaults
+fn update_concentrated_reserves(pool: &mut Pool, amount_a: i128, amount_b: i128) -> Result<()> {
+    let (token_a, token_b) = (pool.concentrated.token_a, pool.concentrated.token_b);
+    for token in pool.tokens.iter_mut() {
+        let delta = if token.token_account == token_a {
+            amount_a
+        } else if token.token_account == token_b {
+            amount_b
+        } else {
+            continue;
+        };
+        let amount = (token.amount as i128)
+            .checked_add(delta)
+            .ok_or(SwapError::MathError)?;
+        token.amount = u64::try_from(amount).map_err(|_| SwapError::MathError)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct IncreaseLiquidity<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Account<'info, TickArray>,
+    #[account(mut, has_one = pool)]
+    pub position: Account<'info, Position>,
+    // Holds the position NFT once one is minted
+    pub position_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_b: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_a)]
+    pub pool_token_a: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_b)]
+    pub pool_token_b: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn increase_liquidity(
+    ctx: Context<IncreaseLiquidity>,
+    liquidity: u128,
+    max_amount_a: u64,
+    max_amount_b: u64,
+) -> Result<()> {
+    require!(liquidity > 0, SwapError::InvalidAmount);
+    check_position_owner(
+        &ctx.accounts.position,
+        &ctx.accounts.owner,
+        &ctx.accounts.position_token,
+    )?;
+
+    let liquidity_delta = i128::try_from(liquidity).map_err(|_| SwapError::MathError)?;
+    let (amount_a, amount_b) = modify_position(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &mut ctx.accounts.position,
+        liquidity_delta,
+    )?;
+    require!(
+        amount_a <= max_amount_a && amount_b <= max_amount_b,
+        SwapError::SlippageError
+    );
+
+    for (from, to, amount) in [
+        (
+            &ctx.accounts.user_token_a,
+            &ctx.accounts.pool_token_a,
+            amount_a,
+        ),
+        (
+            &ctx.accounts.user_token_b,
+            &ctx.accounts.pool_token_b,
+            amount_b,
+        ),
+    ] {
+        if amount > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: from.to_account_info(),
+                        to: to.to_account_info(),
+                        authority: ctx.accounts.owner.to_account_info(),
+                    },
+                ),
+                amount,
+            )?;
+        }
+    }
+
+    update_concentrated_reserves(&mut ctx.accounts.pool, amount_a as i128, amount_b as i128)?;
+
+    emit!(PositionEvent {
+        pool: ctx.accounts.pool.key(),
+        position: ctx.accounts.position.key(),
+        liquidity_delta,
+        amount_a,
+        amount_b,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct DecreaseLiquidity<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    // The pool keypair owns the vaults
+    #[account(address = pool.key() @ SwapError::Unauthorized)]
+    pub pool_authority: Signer<'info>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Account<'info, TickArray>,
+    #[account(mut, has_one = pool)]
+    pub position: Account<'info, Position>,
+    // Holds the position NFT once one is minted
+    pub position_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_b: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_a)]
+    pub pool_token_a: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_b)]
+    pub pool_token_b: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Pays tokens out of the concentrated liquidity vaults (pool_authority must authorize this)
+fn pay_out_of_vaults<'info>(
+    token_program: &Program<'info, Token>,
+    (pool_authority, signer_seeds): (AccountInfo<'info>, &[&[&[u8]]]),
+    payouts: [(
+        &Account<'info, TokenAccount>,
+        &Account<'info, TokenAccount>,
+        u64,
+    ); 2],
+) -> Result<()> {
+    for (from, to, amount) in payouts {
+        if amount > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    token::Transfer {
+                        from: from.to_account_info(),
+                        to: to.to_account_info(),
+                        authority: pool_authority.clone(),
+                    },
+                    signer_seeds,
+                ),
+                amount,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn decrease_liquidity(
+    ctx: Context<DecreaseLiquidity>,
+    liquidity: u128,
+    min_amount_a: u64,
+    min_amount_b: u64,
+) -> Result<()> {
+    require!(
+        liquidity > 0 && liquidity <= ctx.accounts.position.liquidity,
+        SwapError::InvalidAmount
+    );
+    check_position_owner(
+        &ctx.accounts.position,
+        &ctx.accounts.owner,
+        &ctx.accounts.position_token,
+    )?;
+
+    let liquidity_delta = -i128::try_from(liquidity).map_err(|_| SwapError::MathError)?;
+    let (amount_a, amount_b) = modify_position(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &mut ctx.accounts.position,
+        liquidity_delta,
+    )?;
+    require!(
+        amount_a >= min_amount_a && amount_b >= min_amount_b,
+        SwapError::SlippageError
+    );
+
+    pay_out_of_vaults(
+        &ctx.accounts.token_program,
+        (ctx.accounts.pool_authority.to_account_info(), &[]),
+        [
+            (
+                &ctx.accounts.pool_token_a,
+                &ctx.accounts.user_token_a,
+                amount_a,
+            ),
+            (
+                &ctx.accounts.pool_token_b,
+                &ctx.accounts.user_token_b,
+                amount_b,
+            ),
+        ],
+    )?;
+    update_concentrated_reserves(
+        &mut ctx.accounts.pool,
+        -(amount_a as i128),
+        -(amount_b as i128),
+    )?;
+
+    emit!(PositionEvent {
+        pool: ctx.accounts.pool.key(),
+        position: ctx.accounts.position.key(),
+        liquidity_delta,
+        amount_a,
+        amount_b,
+    });
+
+    Ok(())
+}
+
+pub fn collect_fees(ctx: Context<DecreaseLiquidity>) -> Result<()> {
+    check_position_owner(
+        &ctx.accounts.position,
+        &ctx.accounts.owner,
+        &ctx.accounts.position_token,
+    )?;
+
+    modify_position(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &mut ctx.accounts.position,
+        0,
+    )?;
+
+    let position = &mut ctx.accounts.position;
+    let (amount_a, amount_b) = (position.tokens_owed_a, position.tokens_owed_b);
+    position.tokens_owed_a = 0;
+    position.tokens_owed_b = 0;
+
+    pay_out_of_vaults(
+        &ctx.accounts.token_program,
+        (ctx.accounts.pool_authority.to_account_info(), &[]),
+        [
+            (
+                &ctx.accounts.pool_token_a,
+                &ctx.accounts.user_token_a,
+                amount_a,
+            ),
+            (
+                &ctx.accounts.pool_token_b,
+                &ctx.accounts.user_token_b,
+                amount_b,
+            ),
+        ],
+    )?;
+    update_concentrated_reserves(
+        &mut ctx.accounts.pool,
+        -(amount_a as i128),
+        -(amount_b as i128),
+    )?;
+
+    emit!(PositionEvent {
+        pool: ctx.accounts.pool.key(),
+        position: ctx.accounts.position.key(),
+        liquidity_delta: 0,
+        amount_a,
+        amount_b,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut, close = owner)]
+    pub position: Account<'info, Position>,
+    // The position NFT is burned with the position
+    #[account(mut)]
+    pub position_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut, address = position.nft_mint)]
+    pub position_mint: Option<Account<'info, Mint>>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+    let position = &ctx.accounts.position;
+    check_position_owner(position, &ctx.accounts.owner, &ctx.accounts.position_token)?;
+    require!(
+        position.liquidity == 0 && position.tokens_owed_a == 0 && position.tokens_owed_b == 0,
+        SwapError::PositionNotEmpty
+    );
+
+    if position.nft_mint != Pubkey::default() {
+        let (position_token, position_mint) =
+            match (&ctx.accounts.position_token, &ctx.accounts.position_mint) {
+                (Some(position_token), Some(position_mint)) => (position_token, position_mint),
+                _ => return err!(FlashFillError::MissingTokenAccount),
+            };
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Burn {
+                    mint: position_mint.to_account_info(),
+                    from: position_token.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    pub authority: Signer<'info>,
+}
+
+pub fn set_platform_fee(ctx: Context<UpdateConfig>, platform_fee_bps: u16) -> Result<()> {
+    require!(
+        platform_fee_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidFee
+    );
+
+    ctx.accounts.config.platform_fee_bps = platform_fee_bps;
+    Ok(())
+}
+
+pub fn set_treasury_reserve_floor(
+    ctx: Context<UpdateConfig>,
+    treasury_reserve_floor: u64,
+) -> Result<()> {
+    ctx.accounts.config.treasury_reserve_floor = treasury_reserve_floor;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeTreasuryToken<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    #[account(
+        init,
+        payer = authority,
+        seeds = [TREASURY_SEED, mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = program_authority
+    )]
+    pub treasury_token: Account<'info, TokenAccount>,
+    pub mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_treasury_token(_ctx: Context<InitializeTreasuryToken>) -> Result<()> {
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ManageTreasury<'info> {
+    #[account(
+        seeds = [CONFIG_SEED],
+        bump = config.bump,
+        has_one = authority @ SwapError::Unauthorized
+    )]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    // Per-mint treasury account. Omitted to move native SOL.
+    #[account(mut, token::authority = program_authority)]
+    pub treasury_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut, token::authority = authority)]
+    pub authority_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn fund_treasury(ctx: Context<ManageTreasury>, amount: u64) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+
+    let (mint, balance) = match (&ctx.accounts.treasury_token, &ctx.accounts.authority_token) {
+        (Some(treasury_token), Some(authority_token)) => {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: authority_token.to_account_info(),
+                        to: treasury_token.to_account_info(),
+                        authority: ctx.accounts.authority.to_account_info(),
+                    },
+                ),
+                amount,
+            )?;
+
+            let balance = treasury_token
+                .amount
+                .checked_add(amount)
+                .ok_or(SwapError::MathError)?;
+            (treasury_token.mint, balance)
+        }
+        (None, None) => {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.authority.to_account_info(),
+                        to: ctx.accounts.program_authority.to_account_info(),
+                    },
+                ),
+                amount,
+            )?;
+
+            (
+                spl_token::native_mint::ID,
+                ctx.accounts.program_authority.lamports(),
+            )
+        }
+        _ => return err!(FlashFillError::MissingTokenAccount),
+    };
+
+    emit!(TreasuryEvent {
+        mint,
+        funded: amount,
+        withdrawn: 0,
+        balance,
+    });
+
+    Ok(())
+}
+
+pub fn withdraw_treasury(ctx: Context<ManageTreasury>, amount: u64) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+
+    let authority_bump = [ctx.bumps.program_authority];
+    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, &authority_bump]];
+
+    let (mint, balance) = match (&ctx.accounts.treasury_token, &ctx.accounts.authority_token) {
+        (Some(treasury_token), Some(authority_token)) => {
+            let balance = treasury_token
+                .amount
+                .checked_sub(amount)
+                .ok_or(SwapError::InsufficientLiquidity)?;
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: treasury_token.to_account_info(),
+                        to: authority_token.to_account_info(),
+                        authority: ctx.accounts.program_authority.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                amount,
+            )?;
+
+            (treasury_token.mint, balance)
+        }
+        (None, None) => {
+            // Lent lamports are still owed to the treasury, so nothing can be
+            // withdrawn until every loan in the transaction is repaid
+            require!(
+                ctx.accounts.config.outstanding_borrowed_lamports == 0,
+                SwapError::OutstandingLoans
+            );
+            let balance = ctx
+                .accounts
+                .program_authority
+                .lamports()
+                .checked_sub(amount)
+                .ok_or(SwapError::InsufficientLiquidity)?;
+            require!(
+                balance >= ctx.accounts.config.treasury_reserve_floor,
+                SwapError::TreasuryReserveFloor
+            );
+
+            system_program::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.program_authority.to_account_info(),
+                        to: ctx.accounts.authority.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                amount,
+            )?;
+
+            (spl_token::native_mint::ID, balance)
+        }
+        _ => return err!(FlashFillError::MissingTokenAccount),
+    };
+
+    emit!(TreasuryEvent {
+        mint,
+        funded: 0,
+        withdrawn: amount,
+        balance,
+    });
+
+    Ok(())
+}
+
+pub fn set_flash_loan_fee(ctx: Context<UpdateConfig>, flash_loan_fee_bps: u16) -> Result<()> {
+    require!(
+        flash_loan_fee_bps as u64 <= BPS_DENOMINATOR,
+        SwapError::InvalidFee
+    );
+
+    ctx.accounts.config.flash_loan_fee_bps = flash_loan_fee_bps;
+    Ok(())
+}
+
+// Accounts shared by FlashBorrow and FlashRepay at the same positions: borrower,
+// program authority, lender token and borrower token. A borrow and its repay must
+// name the same keys for all of them.
+const FLASH_SHARED_ACCOUNT_INDEXES: [usize; 4] = [0, 2, 3, 4];
+
+// Loads the instruction paired with the current flash loan instruction and checks
+// it is a top-level call into this program, of the expected kind, over the same accounts.
+fn load_flash_pair(
+    ixs: &AccountInfo,
+    current_ix: &Instruction,
+    pair_index: u16,
+    discriminator: [u8; 8],
+    missing_error: FlashFillError,
+) -> Result<Instruction> {
+    let ix = load_instruction_at_checked(pair_index as usize, ixs).map_err(|_| missing_error)?;
+    if ix.program_id != crate::id() || ix.data.get(0..8) != Some(discriminator.as_ref()) {
+        return Err(missing_error.into());
+    }
+
+    for index in FLASH_SHARED_ACCOUNT_INDEXES {
+        let pair_key = ix.accounts.get(index).map(|meta| meta.pubkey);
+        let current_key = current_ix.accounts.get(index).map(|meta| meta.pubkey);
+        if pair_key.is_none() || pair_key != current_key {
+            return Err(match index {
+                0 => FlashFillError::BorrowerMismatch,
+                2 => FlashFillError::IncorrectProgramAuthority,
+                _ => FlashFillError::RepayMismatch,
+            }
+            .into());
+        }
+    }
+
+    Ok(ix)
+}
+
+#[derive(Accounts)]
+pub struct FlashBorrow<'info> {
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    // Treasury or pool vault lending the tokens. Omitted when borrowing native SOL.
+    #[account(mut)]
+    pub lender_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub borrower_token: Option<Account<'info, TokenAccount>>,
+    // Pool vaults are owned by the pool keypair, which has to sign to lend them
+    pub pool_authority: Option<Signer<'info>>,
+    // Pool whose vault is lent, omitted for the treasury
+    pub pool: Option<Account<'info, Pool>>,
+    /// CHECK: check instructions account
+    #[account(address = sysvar::instructions::ID @FlashFillError::AddressMismatch)]
+    pub instructions: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn flash_borrow(
+    ctx: Context<FlashBorrow>,
+    mint: Pubkey,
+    amount: u64,
+    repay_index: u16,
+) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+
+    let ixs = ctx.accounts.instructions.to_account_info();
+
+    // make sure this isnt a cpi call
+    let current_index = load_current_index_checked(&ixs)?;
+    let current_ix = load_instruction_at_checked(current_index as usize, &ixs)?;
+    if current_ix.program_id != *ctx.program_id {
+        return Err(FlashFillError::ProgramMismatch.into());
+    }
+
+    // the repay has to come later in the transaction, from the same borrower to the same lender
+    require!(repay_index > current_index, FlashFillError::MissingRepay);
+    let repay_ix = load_flash_pair(
+        &ixs,
+        &current_ix,
+        repay_index,
+        crate::instruction::FlashRepay::discriminator(),
+        FlashFillError::MissingRepay,
+    )?;
+    let repay = crate::instruction::FlashRepay::try_from_slice(&repay_ix.data[8..])
+        .map_err(|_| FlashFillError::UnknownInstruction)?;
+
+    // and it has to settle exactly this loan
+    require_keys_eq!(repay.mint, mint, FlashFillError::InvalidMint);
+    require!(repay.amount == amount, FlashFillError::AmountMismatch);
+    require!(
+        repay.borrow_index == current_index,
+        FlashFillError::LoanIndexMismatch
+    );
+
+    let authority_bump: [u8; 1] = [ctx.bumps.program_authority];
+    let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, &authority_bump]];
+
+    match &ctx.accounts.lender_token {
+        // Native SOL is lent straight from the program authority's lamports, down to
+        // the reserve floor at most
+        None => {
+            require_keys_eq!(
+                mint,
+                spl_token::native_mint::ID,
+                FlashFillError::InvalidMint
+            );
+            let balance = ctx
+                .accounts
+                .program_authority
+                .lamports()
+                .checked_sub(amount)
+                .ok_or(SwapError::InsufficientLiquidity)?;
+            require!(
+                balance >= ctx.accounts.config.treasury_reserve_floor,
+                SwapError::TreasuryReserveFloor
+            );
+
+            let config = &mut ctx.accounts.config;
+            config.outstanding_borrowed_lamports = config
+                .outstanding_borrowed_lamports
+                .checked_add(amount)
+                .ok_or(SwapError::MathError)?;
+
+            system_program::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.program_authority.to_account_info(),
+                        to: ctx.accounts.borrower.to_account_info(),
+                    },
+                    signer_seeds,
+                ),
+                amount,
+            )?;
+        }
+        Some(lender_token) => {
+            require_keys_eq!(lender_token.mint, mint, FlashFillError::InvalidMint);
+            let borrower_token = ctx
+                .accounts
+                .borrower_token
+                .as_ref()
+                .ok_or(FlashFillError::MissingTokenAccount)?;
+
+            let cpi_accounts = token::Transfer {
+                from: lender_token.to_account_info(),
+                to: borrower_token.to_account_info(),
+                authority: ctx.accounts.program_authority.to_account_info(),
+            };
+
+            if lender_token.owner == ctx.accounts.program_authority.key() {
+                // Treasury token account, the program authority signs
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        cpi_accounts,
+                        signer_seeds,
+                    ),
+                    amount,
+                )?;
+            } else {
+                // Pool vault, the pool keypair signs the transaction
+                let pool = ctx
+                    .accounts
+                    .pool
+                    .as_ref()
+                    .ok_or(FlashFillError::IncorrectOwner)?;
+                let pool_authority = ctx
+                    .accounts
+                    .pool_authority
+                    .as_ref()
+                    .ok_or(FlashFillError::IncorrectOwner)?;
+                require_keys_eq!(
+                    pool_authority.key(),
+                    pool.key(),
+                    FlashFillError::IncorrectOwner
+                );
+                require_keys_eq!(
+                    lender_token.owner,
+                    pool.key(),
+                    FlashFillError::IncorrectOwner
+                );
+                require!(
+                    pool.tokens
+                        .iter()
+                        .any(|token| token.vault == lender_token.key()),
+                    SwapError::TokenNotFound
+                );
+
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        token::Transfer {
+                            authority: pool_authority.to_account_info(),
+                            ..cpi_accounts
+                        },
+                    ),
+                    amount,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct FlashRepay<'info> {
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump)]
+    pub config: Account<'info, ProgramConfig>,
+    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    #[account(mut)]
+    pub lender_token: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub borrower_token: Option<Account<'info, TokenAccount>>,
+    // Pool whose vault was lent, credited with the fee. Omitted for the treasury.
+    #[account(mut)]
+    pub pool: Option<Account<'info, Pool>>,
+    /// CHECK: check instructions account
+    #[account(address = sysvar::instructions::ID @FlashFillError::AddressMismatch)]
+    pub instructions: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn flash_repay(
+    ctx: Context<FlashRepay>,
+    mint: Pubkey,
+    amount: u64,
+    borrow_index: u16,
+) -> Result<()> {
+    let ixs = ctx.accounts.instructions.to_account_info();
+
+    // make sure this isnt a cpi call
+    let current_index = load_current_index_checked(&ixs)?;
+    let current_ix = load_instruction_at_checked(current_index as usize, &ixs)?;
+    if current_ix.program_id != *ctx.program_id {
+        return Err(FlashFillError::ProgramMismatch.into());
+    }
+
+    // a repay is only valid for an earlier borrow that points back at it
+    require!(borrow_index < current_index, FlashFillError::MissingBorrow);
+    let borrow_ix = load_flash_pair(
+        &ixs,
+        &current_ix,
+        borrow_index,
+        crate::instruction::FlashBorrow::discriminator(),
+        FlashFillError::MissingBorrow,
+    )?;
+    let borrow = crate::instruction::FlashBorrow::try_from_slice(&borrow_ix.data[8..])
+        .map_err(|_| FlashFillError::UnknownInstruction)?;
+
+    require_keys_eq!(borrow.mint, mint, FlashFillError::InvalidMint);
+    require!(borrow.amount == amount, FlashFillError::AmountMismatch);
+    require!(
+        borrow.repay_index == current_index,
+        FlashFillError::LoanIndexMismatch
+    );
+
+    // the lender keeps the fee on top of the borrowed amount
+    let fee_amount = calculate_fee(amount, ctx.accounts.config.flash_loan_fee_bps)?;
+    let repay_amount = amount.checked_add(fee_amount).ok_or(SwapError::MathError)?;
+
+    match &ctx.accounts.lender_token {
+        None => {
+            require_keys_eq!(
+                mint,
+                spl_token::native_mint::ID,
+                FlashFillError::InvalidMint
+            );
+
+            // transfer borrowed SOL back to the program authority
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.borrower.to_account_info(),
+                        to: ctx.accounts.program_authority.to_account_info(),
+                    },
+                ),
+                repay_amount,
+            )?;
+
+            let config = &mut ctx.accounts.config;
+            config.outstanding_borrowed_lamports = config
+                .outstanding_borrowed_lamports
+                .checked_sub(amount)
+                .ok_or(SwapError::MathError)?;
+        }
+        Some(lender_token) => {
+            require_keys_eq!(lender_token.mint, mint, FlashFillError::InvalidMint);
+            let borrower_token = ctx
+                .accounts
+                .borrower_token
+                .as_ref()
+                .ok_or(FlashFillError::MissingTokenAccount)?;
+
+            // transfer borrowed tokens back to the lending vault
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: borrower_token.to_account_info(),
+                        to: lender_token.to_account_info(),
+                        authority: ctx.accounts.borrower.to_account_info(),
+                    },
+                ),
+                repay_amount,
+            )?;
+
+            // a pool vault keeps the fee as part of its reserve
+            if lender_token.owner != ctx.accounts.program_authority.key() {
+                let pool = ctx
+                    .accounts
+                    .pool
+                    .as_mut()
+                    .ok_or(FlashFillError::IncorrectOwner)?;
+                require_keys_eq!(
+                    lender_token.owner,
+                    pool.key(),
+                    FlashFillError::IncorrectOwner
+                );
+                let token = pool
+                    .tokens
+                    .iter_mut()
+                    .find(|token| token.vault == lender_token.key())
+                    .ok_or(SwapError::TokenNotFound)?;
+                token.amount = token
+                    .amount
+                    .checked_add(fee_amount)
+                    .ok_or(SwapError::MathError)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(tick_lower: i32, tick_upper: i32)]
+pub struct PlaceRangeOrder<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Account<'info, TickArray>,
+    #[account(
+        init,
+        payer = owner,
+        space = Position::LEN,
+        seeds = [
+            POSITION_SEED,
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &tick_lower.to_le_bytes(),
+            &tick_upper.to_le_bytes()
+        ],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        init,
+        payer = owner,
+        space = RangeOrder::LEN,
+        seeds = [RANGE_ORDER_SEED, position.key().as_ref()],
+        bump
+    )]
+    pub range_order: Account<'info, RangeOrder>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    // Vault of the sold token, checked against the pool in the handler
+    #[account(mut)]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// A range order is a position one tick spacing wide on the far side of the price,
+// funded with the sold token only. It converts as swaps move the price through it.
+pub fn place_range_order(
+    ctx: Context<PlaceRangeOrder>,
+    tick_lower: i32,
+    tick_upper: i32,
+    sell_a: bool,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+    let CurveType::Concentrated { tick_spacing } = ctx.accounts.pool.curve else {
+        return err!(SwapError::UnsupportedCurve);
+    };
+    require!(
+        tick_lower % tick_spacing as i32 == 0
+            && tick_upper == tick_lower + tick_spacing as i32
+            && tick_lower >= MIN_TICK
+            && tick_upper <= MAX_TICK,
+        SwapError::InvalidTick
+    );
+
+    // Selling token A needs a band above the price, selling token B one below it
+    let state = &ctx.accounts.pool.concentrated;
+    let (sqrt_price_lower, sqrt_price_upper) = (
+        sqrt_price_at_tick(tick_lower)?,
+        sqrt_price_at_tick(tick_upper)?,
+    );
+    let (liquidity, vault_in) = if sell_a {
+        require!(tick_lower > state.tick_current, SwapError::InvalidTick);
+        (
+            liquidity_for_amount_a(sqrt_price_lower, sqrt_price_upper, amount)?,
+            state.vault_a,
+        )
+    } else {
+        require!(tick_upper <= state.tick_current, SwapError::InvalidTick);
+        (
+            liquidity_for_amount_b(sqrt_price_lower, sqrt_price_upper, amount)?,
+            state.vault_b,
+        )
+    };
+    require!(liquidity > 0, SwapError::InvalidAmount);
+    require_keys_eq!(
+        ctx.accounts.pool_token_in.key(),
+        vault_in,
+        SwapError::TokenNotFound
+    );
+
+    let position = &mut ctx.accounts.position;
+    position.pool = ctx.accounts.pool.key();
+    position.owner = ctx.accounts.owner.key();
+    position.tick_lower = tick_lower;
+    position.tick_upper = tick_upper;
+    position.bump = ctx.bumps.position;
+
+    let liquidity_delta = i128::try_from(liquidity).map_err(|_| SwapError::MathError)?;
+    let (amount_a, amount_b) = modify_position(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &mut ctx.accounts.position,
+        liquidity_delta,
+    )?;
+    let amount_in = if sell_a { amount_a } else { amount_b };
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.user_token_in.to_account_info(),
+                to: ctx.accounts.pool_token_in.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        ),
+        amount_in,
+    )?;
+    update_concentrated_reserves(&mut ctx.accounts.pool, amount_a as i128, amount_b as i128)?;
+
+    let range_order = &mut ctx.accounts.range_order;
+    range_order.pool = ctx.accounts.pool.key();
+    range_order.owner = ctx.accounts.owner.key();
+    range_order.position = ctx.accounts.position.key();
+    range_order.sell_a = sell_a;
+    range_order.amount_in = amount_in;
+    range_order.bump = ctx.bumps.range_order;
+
+    emit!(PositionEvent {
+        pool: ctx.accounts.pool.key(),
+        position: ctx.accounts.position.key(),
+        liquidity_delta,
+        amount_a,
+        amount_b,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClaimRangeOrder<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Program signer of the pool, delegated over its vaults.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Account<'info, TickArray>,
+    #[account(mut, has_one = pool, close = owner)]
+    pub position: Account<'info, Position>,
+    #[account(
+        mut,
+        seeds = [RANGE_ORDER_SEED, position.key().as_ref()],
+        bump = range_order.bump,
+        has_one = owner @ SwapError::Unauthorized,
+        close = owner
+    )]
+    pub range_order: Account<'info, RangeOrder>,
+    #[account(mut)]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_b: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_a)]
+    pub pool_token_a: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.concentrated.vault_b)]
+    pub pool_token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Withdraws a filled range order with the fees it earned and closes it
+pub fn claim_range_order(ctx: Context<ClaimRangeOrder>) -> Result<()> {
+    // A position handed over as an NFT is withdrawn through the position instructions
+    check_position_owner(&ctx.accounts.position, &ctx.accounts.owner, &None)?;
+    require!(
+        ctx.accounts.range_order.is_filled(
+            &ctx.accounts.position,
+            ctx.accounts.pool.concentrated.tick_current
+        ),
+        SwapError::RangeOrderNotFilled
+    );
+
+    let liquidity_delta =
+        -i128::try_from(ctx.accounts.position.liquidity).map_err(|_| SwapError::MathError)?;
+    let (amount_a, amount_b) = modify_position(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &mut ctx.accounts.position,
+        liquidity_delta,
+    )?;
+
+    let position = &mut ctx.accounts.position;
+    let amount_a = amount_a
+        .checked_add(position.tokens_owed_a)
+        .ok_or(SwapError::MathError)?;
+    let amount_b = amount_b
+        .checked_add(position.tokens_owed_b)
+        .ok_or(SwapError::MathError)?;
+    position.tokens_owed_a = 0;
+    position.tokens_owed_b = 0;
+
+    let pool_key = ctx.accounts.pool.key();
+    let pool_signer_seeds: &[&[&[u8]]] =
+        &[&[POOL_SEED, pool_key.as_ref(), &[ctx.bumps.pool_signer]]];
+    pay_out_of_vaults(
+        &ctx.accounts.token_program,
+        (
+            ctx.accounts.pool_signer.to_account_info(),
+            pool_signer_seeds,
+        ),
+        [
+            (
+                &ctx.accounts.pool_token_a,
+                &ctx.accounts.user_token_a,
+                amount_a,
+            ),
+            (
+                &ctx.accounts.pool_token_b,
+                &ctx.accounts.user_token_b,
+                amount_b,
+            ),
+        ],
+    )?;
+    update_concentrated_reserves(
+        &mut ctx.accounts.pool,
+        -(amount_a as i128),
+        -(amount_b as i128),
+    )?;
+
+    emit!(PositionEvent {
+        pool: ctx.accounts.pool.key(),
+        position: ctx.accounts.position.key(),
+        liquidity_delta,
+        amount_a,
+        amount_b,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct DelegatePoolVault<'info> {
+    pub pool: Account<'info, Pool>,
+    // The pool keypair owns the vaults
+    #[account(address = pool.key() @ SwapError::Unauthorized)]
+    pub pool_authority: Signer<'info>,
+    /// CHECK: Program signer of the pool, only approved as a delegate.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        constraint = pool.tokens.iter().any(|token| token.vault == pool_token.key())
+            @ SwapError::TokenNotFound
+    )]
+    pub pool_token: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Lets the program signer of the pool pay out of a pool vault, so range orders,
+// keeper orders and DCA intervals settle without the pool keypair
+pub fn delegate_pool_vault(ctx: Context<DelegatePoolVault>) -> Result<()> {
+    token::approve(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Approve {
+                to: ctx.accounts.pool_token.to_account_info(),
+                delegate: ctx.accounts.pool_signer.to_account_info(),
+                authority: ctx.accounts.pool_authority.to_account_info(),
+            },
+        ),
+        u64::MAX,
+    )
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: u64, input_token_account: Pubkey)]
+pub struct PlaceOrder<'info> {
+    pub pool: Account<'info, Pool>,
+    // Pool vault of the input token, the escrow has to hold the same mint
+    #[account(address = pool.vault(input_token_account) @ SwapError::TokenNotFound)]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = owner,
+        space = Order::LEN,
+        seeds = [ORDER_SEED, owner.key().as_ref(), &order_id.to_le_bytes()],
+        bump
+    )]
+    pub order: Account<'info, Order>,
+    // Escrow of the input tokens, owned by the order
+    #[account(
+        init,
+        payer = owner,
+        seeds = [ORDER_VAULT_SEED, order.key().as_ref()],
+        bump,
+        token::mint = input_mint,
+        token::authority = order
+    )]
+    pub order_vault: Account<'info, TokenAccount>,
+    #[account(
+        address = user_token_in.mint,
+        constraint = input_mint.key() == pool_token_in.mint @ SwapError::TokenNotFound
+    )]
+    pub input_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// Escrows the input of a limit or stop-loss order, along with the keeper tip
+#[allow(clippy::too_many_arguments)]
+pub fn place_order(
+    ctx: Context<PlaceOrder>,
+    order_id: u64,
+    input_token_account: Pubkey,
+    output_token_account: Pubkey,
+    input_amount: u64,
+    min_output_amount: u64,
+    kind: OrderKind,
+    expiry: i64,
+    keeper_tip: u64,
+) -> Result<()> {
+    require!(input_amount > 0, SwapError::InvalidAmount);
+    require!(min_output_amount > 0, SwapError::InvalidAmount);
+    let now = Clock::get()?.unix_timestamp;
+    require!(expiry > now, SwapError::OrderExpired);
+
+    // The pool has to price the pair for the trigger to be checked
+    ctx.accounts
+        .pool
+        .spot_price_x64(input_token_account, output_token_account, now)?;
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.user_token_in.to_account_info(),
+                to: ctx.accounts.order_vault.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        ),
+        input_amount,
+    )?;
+    if keeper_tip > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: ctx.accounts.order.to_account_info(),
+                },
+            ),
+            keeper_tip,
+        )?;
+    }
+
+    let order = &mut ctx.accounts.order;
+    order.owner = ctx.accounts.owner.key();
+    order.pool = ctx.accounts.pool.key();
+    order.input_token_account = input_token_account;
+    order.output_token_account = output_token_account;
+    order.user_token_out = ctx.accounts.user_token_out.key();
+    order.input_amount = input_amount;
+    order.min_output_amount = min_output_amount;
+    order.kind = kind;
+    order.expiry = expiry;
+    order.keeper_tip = keeper_tip;
+    order.order_id = order_id;
+    order.bump = ctx.bumps.order;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ExecuteOrder<'info> {
+    #[account(mut, address = order.pool)]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Program signer of the pool, delegated over its vaults.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [ORDER_SEED, order.owner.as_ref(), &order.order_id.to_le_bytes()],
+        bump = order.bump,
+        has_one = owner,
+        has_one = user_token_out,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+    #[account(mut, seeds = [ORDER_VAULT_SEED, order.key().as_ref()], bump)]
+    pub order_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.vault(order.input_token_account) @ SwapError::TokenNotFound)]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(mut, address = pool.vault(order.output_token_account) @ SwapError::TokenNotFound)]
+    pub pool_token_out: Account<'info, TokenAccount>,
+    // The admin fee goes to the pool authority
+    #[account(
+        mut,
+        token::mint = pool_token_in.mint,
+        token::authority = pool.authority
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Order owner, receives the rent of the order and its vault.
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+    // Anyone may execute a triggered order and earn its tip
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    /// CHECK: Price account configured on the pool, matched by address and parsed in the handler.
+    pub price_feed: Option<UncheckedAccount<'info>>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Option<Account<'info, TickArray>>,
+}
+
+// Swaps the escrowed input through the pool once the pool price crossed the trigger
+pub fn execute_order(ctx: Context<ExecuteOrder>) -> Result<()> {
+    let order = &ctx.accounts.order;
+    let clock = Clock::get()?;
+    require!(
+        clock.unix_timestamp <= order.expiry,
+        SwapError::OrderExpired
+    );
+
+    // The trigger is checked on the price before the order's own trade
+    let price_x64 = ctx.accounts.pool.spot_price_x64(
+        order.input_token_account,
+        order.output_token_account,
+        clock.unix_timestamp,
+    )?;
+    require!(order.is_triggered(price_x64), SwapError::OrderNotTriggered);
+
+    // A tripped breaker leaves the order open for a later attempt
+    let quote = apply_swap(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &ctx.accounts.price_feed,
+        (
+            ctx.accounts.pool_token_in.key(),
+            ctx.accounts.pool_token_out.key(),
+        ),
+        order.input_token_account,
+        order.output_token_account,
+        order.input_amount,
+        order.min_output_amount,
+        (None, None),
+        &clock,
+    )?;
+
+    let order_id = order.order_id.to_le_bytes();
+    let order_seeds: &[&[&[u8]]] = &[&[ORDER_SEED, order.owner.as_ref(), &order_id, &[order.bump]]];
+    let pool_key = ctx.accounts.pool.key();
+    let pool_signer_seeds: &[&[&[u8]]] =
+        &[&[POOL_SEED, pool_key.as_ref(), &[ctx.bumps.pool_signer]]];
+    settle_escrowed_swap(
+        &ctx.accounts.token_program,
+        ctx.accounts.order_vault.to_account_info(),
+        ctx.accounts.order.to_account_info(),
+        order_seeds,
+        &ctx.accounts.admin_token_account,
+        &ctx.accounts.pool_token_in,
+        &ctx.accounts.pool_token_out,
+        (
+            ctx.accounts.pool_signer.to_account_info(),
+            pool_signer_seeds,
+        ),
+        &ctx.accounts.user_token_out,
+        order.input_amount,
+        &quote,
+    )?;
+    close_token_vault(
+        ctx.accounts.order_vault.to_account_info(),
+        ctx.accounts.owner.to_account_info(),
+        ctx.accounts.order.to_account_info(),
+        ctx.accounts.token_program.clone(),
+        order_seeds,
+    )?;
+
+    // The tip comes out of the order before its rent returns to the owner
+    let keeper_tip = ctx.accounts.order.keeper_tip;
+    ctx.accounts.order.sub_lamports(keeper_tip)?;
+    ctx.accounts.keeper.add_lamports(keeper_tip)?;
+
+    emit!(SwapEvent {
+        pool: ctx.accounts.pool.key(),
+        user: ctx.accounts.owner.key(),
+        input_amount: ctx.accounts.order.input_amount,
+        output_amount: quote.output_amount,
+        fee_amount: quote.fee_amount + quote.lp_fee_amount,
+        fee_bps: quote.fee_bps,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(
+        mut,
+        seeds = [ORDER_SEED, owner.key().as_ref(), &order.order_id.to_le_bytes()],
+        bump = order.bump,
+        has_one = owner @ SwapError::Unauthorized,
+        close = owner
+    )]
+    pub order: Account<'info, Order>,
+    #[account(mut, seeds = [ORDER_VAULT_SEED, order.key().as_ref()], bump)]
+    pub order_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Refunds the escrowed input and the keeper tip
+pub fn cancel_order(ctx: Context<CancelOrder>) -> Result<()> {
+    let order = &ctx.accounts.order;
+    let order_id = order.order_id.to_le_bytes();
+    let order_seeds: &[&[&[u8]]] = &[&[ORDER_SEED, order.owner.as_ref(), &order_id, &[order.bump]]];
+
+    refund_escrow(
+        &ctx.accounts.token_program,
+        &ctx.accounts.order_vault,
+        ctx.accounts.order.to_account_info(),
+        order_seeds,
+        &ctx.accounts.user_token_in,
+        ctx.accounts.owner.to_account_info(),
+    )
+}
+
+// Settles a swap applied with apply_swap whose input sits in an escrow owned by a
+// program account. The admin fee and the rest of the input leave the escrow, and
+// the pool pays out the output through an authority over its output vault.
+#[allow(clippy::too_many_arguments)]
+fn settle_escrowed_swap<'info>(
+    token_program: &Program<'info, Token>,
+    escrow: AccountInfo<'info>,
+    escrow_authority: AccountInfo<'info>,
+    escrow_signer_seeds: &[&[&[u8]]],
+    admin_token_account: &Account<'info, TokenAccount>,
+    pool_token_in: &Account<'info, TokenAccount>,
+    pool_token_out: &Account<'info, TokenAccount>,
+    (pool_authority, pool_signer_seeds): (AccountInfo<'info>, &[&[&[u8]]]),
+    user_token_out: &Account<'info, TokenAccount>,
+    input_amount: u64,
+    quote: &SwapQuote,
+) -> Result<()> {
+    let escrow_transfers = [
+        (admin_token_account.to_account_info(), quote.fee_amount),
+        (
+            pool_token_in.to_account_info(),
+            input_amount
+                .checked_sub(quote.fee_amount)
+                .ok_or(SwapError::MathError)?,
+        ),
+    ];
+    for (to, amount) in escrow_transfers {
+        token::transfer(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                token::Transfer {
+                    from: escrow.clone(),
+                    to,
+                    authority: escrow_authority.clone(),
+                },
+                escrow_signer_seeds,
+            ),
+            amount,
+        )?;
+    }
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: pool_token_out.to_account_info(),
+                to: user_token_out.to_account_info(),
+                authority: pool_authority,
+            },
+            pool_signer_seeds,
+        ),
+        quote.output_amount,
+    )
+}
+
+// Returns what is left in a program owned escrow and closes it
+fn refund_escrow<'info>(
+    token_program: &Program<'info, Token>,
+    escrow: &Account<'info, TokenAccount>,
+    escrow_authority: AccountInfo<'info>,
+    escrow_signer_seeds: &[&[&[u8]]],
+    user_token: &Account<'info, TokenAccount>,
+    rent_destination: AccountInfo<'info>,
+) -> Result<()> {
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: escrow.to_account_info(),
+                to: user_token.to_account_info(),
+                authority: escrow_authority.clone(),
+            },
+            escrow_signer_seeds,
+        ),
+        escrow.amount,
+    )?;
+    close_token_vault(
+        escrow.to_account_info(),
+        rent_destination,
+        escrow_authority,
+        token_program.clone(),
+        escrow_signer_seeds,
+    )
+}
+
+#[derive(Accounts)]
+#[instruction(schedule_id: u64)]
+pub struct CreateDca<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = DcaSchedule::LEN,
+        seeds = [DCA_SEED, owner.key().as_ref(), &schedule_id.to_le_bytes()],
+        bump
+    )]
+    pub schedule: Account<'info, DcaSchedule>,
+    // Holds the input not swapped yet, owned by the schedule
+    #[account(
+        init,
+        payer = owner,
+        seeds = [DCA_VAULT_SEED, schedule.key().as_ref()],
+        bump,
+        token::mint = input_mint,
+        token::authority = schedule
+    )]
+    pub dca_vault: Account<'info, TokenAccount>,
+    #[account(address = user_token_in.mint)]
+    pub input_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// Deposits the lump sum of a DCA schedule. The first interval opens right away.
+pub fn create_dca(
+    ctx: Context<CreateDca>,
+    schedule_id: u64,
+    route: DcaRoute,
+    amount_per_interval: u64,
+    interval_seconds: i64,
+    min_output_per_interval: u64,
+    amount: u64,
+) -> Result<()> {
+    require!(
+        amount > 0 && amount_per_interval > 0 && min_output_per_interval > 0,
+        SwapError::InvalidAmount
+    );
+    require!(interval_seconds > 0, SwapError::InvalidAmount);
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.user_token_in.to_account_info(),
+                to: ctx.accounts.dca_vault.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    let schedule = &mut ctx.accounts.schedule;
+    schedule.owner = ctx.accounts.owner.key();
+    schedule.input_mint = ctx.accounts.input_mint.key();
+    schedule.output_mint = ctx.accounts.user_token_out.mint;
+    schedule.user_token_out = ctx.accounts.user_token_out.key();
+    schedule.route = route;
+    schedule.amount_per_interval = amount_per_interval;
+    schedule.min_output_per_interval = min_output_per_interval;
+    schedule.interval_seconds = interval_seconds;
+    schedule.next_execution_at = Clock::get()?.unix_timestamp;
+    schedule.schedule_id = schedule_id;
+    schedule.bump = ctx.bumps.schedule;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ExecuteDca<'info> {
+    #[account(mut, address = schedule.pool_route().0 @ SwapError::DcaRouteMismatch)]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Program signer of the pool, delegated over its vaults.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [DCA_SEED, schedule.owner.as_ref(), &schedule.schedule_id.to_le_bytes()],
+        bump = schedule.bump,
+        has_one = user_token_out
+    )]
+    pub schedule: Account<'info, DcaSchedule>,
+    #[account(mut, seeds = [DCA_VAULT_SEED, schedule.key().as_ref()], bump)]
+    pub dca_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        address = pool.vault(schedule.pool_route().1) @ SwapError::DcaRouteMismatch,
+        token::mint = schedule.input_mint
+    )]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        address = pool.vault(schedule.pool_route().2) @ SwapError::DcaRouteMismatch,
+        token::mint = schedule.output_mint
+    )]
+    pub pool_token_out: Account<'info, TokenAccount>,
+    // The admin fee goes to the pool authority
+    #[account(
+        mut,
+        token::mint = schedule.input_mint,
+        token::authority = pool.authority
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+    // Anyone may execute an interval once it opened
+    pub keeper: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    /// CHECK: Price account configured on the pool, matched by address and parsed in the handler.
+    pub price_feed: Option<UncheckedAccount<'info>>,
+    #[account(mut, seeds = [TICK_ARRAY_SEED, pool.key().as_ref()], bump = tick_array.bump)]
+    pub tick_array: Option<Account<'info, TickArray>>,
+}
+
+// Swaps one interval of a pool routed schedule through its pool
+pub fn execute_dca(ctx: Context<ExecuteDca>) -> Result<()> {
+    let DcaRoute::Pool {
+        input_token_account,
+        output_token_account,
+        ..
+    } = ctx.accounts.schedule.route
+    else {
+        return err!(SwapError::DcaRouteMismatch);
+    };
+
+    let clock = Clock::get()?;
+    let schedule = &mut ctx.accounts.schedule;
+    let input_amount =
+        schedule.start_interval(ctx.accounts.dca_vault.amount, clock.unix_timestamp)?;
+    let min_output_amount = schedule.min_output(input_amount)?;
+    let remaining_amount = ctx.accounts.dca_vault.amount - input_amount;
+
+    // A tripped breaker leaves the interval to a later attempt
+    let quote = apply_swap(
+        &mut ctx.accounts.pool,
+        &mut ctx.accounts.tick_array,
+        &ctx.accounts.price_feed,
+        (
+            ctx.accounts.pool_token_in.key(),
+            ctx.accounts.pool_token_out.key(),
+        ),
+        input_token_account,
+        output_token_account,
+        input_amount,
+        min_output_amount,
+        (None, None),
+        &clock,
+    )?;
+
+    let schedule = &ctx.accounts.schedule;
+    let schedule_id = schedule.schedule_id.to_le_bytes();
+    let schedule_seeds: &[&[&[u8]]] = &[&[
+        DCA_SEED,
+        schedule.owner.as_ref(),
+        &schedule_id,
+        &[schedule.bump],
+    ]];
+    let pool_key = ctx.accounts.pool.key();
+    let pool_signer_seeds: &[&[&[u8]]] =
+        &[&[POOL_SEED, pool_key.as_ref(), &[ctx.bumps.pool_signer]]];
+    settle_escrowed_swap(
+        &ctx.accounts.token_program,
+        ctx.accounts.dca_vault.to_account_info(),
+        ctx.accounts.schedule.to_account_info(),
+        schedule_seeds,
+        &ctx.accounts.admin_token_account,
+        &ctx.accounts.pool_token_in,
+        &ctx.accounts.pool_token_out,
+        (
+            ctx.accounts.pool_signer.to_account_info(),
+            pool_signer_seeds,
+        ),
+        &ctx.accounts.user_token_out,
+        input_amount,
+        &quote,
+    )?;
+
+    emit!(DcaEvent {
+        schedule: schedule.key(),
+        owner: schedule.owner,
+        input_amount,
+        output_amount: quote.output_amount,
+        remaining_amount,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ExecuteDcaViaJupiter<'info> {
+    #[account(
+        mut,
+        seeds = [DCA_SEED, schedule.owner.as_ref(), &schedule.schedule_id.to_le_bytes()],
+        bump = schedule.bump,
+        has_one = user_token_out
+    )]
+    pub schedule: Account<'info, DcaSchedule>,
+    #[account(mut, seeds = [DCA_VAULT_SEED, schedule.key().as_ref()], bump)]
+    pub dca_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
+    pub program_authority: SystemAccount<'info>,
+    /// CHECK: Temporary vault, created and closed within the instruction.
+    #[account(
+        mut,
+        seeds = [SWAP_VAULT_SEED, schedule.key().as_ref(), schedule.output_mint.as_ref()],
+        bump
+    )]
+    pub swap_vault: UncheckedAccount<'info>,
+    #[account(address = schedule.output_mint)]
+    pub output_token_mint: Account<'info, Mint>,
+    // Anyone may execute an interval once it opened. Pays the vault rent and gets it back.
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    pub jupiter_program: Program<'info, Jupiter>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// Swaps one interval of a Jupiter routed schedule through a route supplied by the
+// keeper. The schedule signs the route, which may spend at most the interval input.
+pub fn execute_dca_via_jupiter(ctx: Context<ExecuteDcaViaJupiter>, data: Vec<u8>) -> Result<()> {
+    require!(
+        ctx.accounts.schedule.route == DcaRoute::Jupiter,
+        SwapError::DcaRouteMismatch
+    );
+
+    let clock = Clock::get()?;
+    let balance_before = ctx.accounts.dca_vault.amount;
+    let schedule = &mut ctx.accounts.schedule;
+    let input_amount = schedule.start_interval(balance_before, clock.unix_timestamp)?;
+    let min_output_amount = schedule.min_output(input_amount)?;
+
+    let schedule_key = ctx.accounts.schedule.key();
+    let owner = ctx.accounts.schedule.owner;
+    let output_mint = ctx.accounts.schedule.output_mint;
+    let schedule_id = ctx.accounts.schedule.schedule_id.to_le_bytes();
+    let schedule_bump = [ctx.accounts.schedule.bump];
+    let authority_bump = [ctx.bumps.program_authority];
+    let vault_bump = [ctx.bumps.swap_vault];
+    let schedule_seeds: &[&[u8]] = &[DCA_SEED, owner.as_ref(), &schedule_id, &schedule_bump];
+    let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, &authority_bump];
+    let vault_seeds: &[&[u8]] = &[
+        SWAP_VAULT_SEED,
+        schedule_key.as_ref(),
+        output_mint.as_ref(),
+        &vault_bump,
+    ];
+
+    let out_amount = swap_into_vault(
+        ctx.accounts.keeper.to_account_info(),
+        ctx.accounts.swap_vault.to_account_info(),
+        ctx.accounts.output_token_mint.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.jupiter_program.clone(),
+        ctx.accounts.token_program.clone(),
+        ctx.accounts.system_program.clone(),
+        ctx.remaining_accounts,
+        data,
+        &[vault_seeds],
+        Some((schedule_key, schedule_seeds)),
+        (input_amount, min_output_amount),
+    )?;
+
+    // The input spent is measured like the output, across the CPI
+    ctx.accounts.dca_vault.reload()?;
+    let spent_amount = balance_before
+        .checked_sub(ctx.accounts.dca_vault.amount)
+        .ok_or(SwapError::MathError)?;
+    require!(spent_amount <= input_amount, SwapError::InvalidAmount);
+    require!(out_amount >= min_output_amount, SwapError::SlippageError);
+
+    msg!("Transfer output to user");
+    token::transfer(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.swap_vault.to_account_info(),
+                to: ctx.accounts.user_token_out.to_account_info(),
+                authority: ctx.accounts.program_authority.to_account_info(),
+            },
+            &[authority_seeds],
+        ),
+        out_amount,
+    )?;
+    close_token_vault(
+        ctx.accounts.swap_vault.to_account_info(),
+        ctx.accounts.keeper.to_account_info(),
+        ctx.accounts.program_authority.to_account_info(),
+        ctx.accounts.token_program.clone(),
+        &[authority_seeds],
+    )?;
+
+    emit!(DcaEvent {
+        schedule: schedule_key,
+        owner,
+        input_amount: spent_amount,
+        output_amount: out_amount,
+        remaining_amount: ctx.accounts.dca_vault.amount,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct WithdrawDca<'info> {
+    #[account(
+        mut,
+        seeds = [DCA_SEED, owner.key().as_ref(), &schedule.schedule_id.to_le_bytes()],
+        bump = schedule.bump,
+        has_one = owner @ SwapError::Unauthorized,
+        close = owner
+    )]
+    pub schedule: Account<'info, DcaSchedule>,
+    #[account(mut, seeds = [DCA_VAULT_SEED, schedule.key().as_ref()], bump)]
+    pub dca_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Returns the input not swapped yet and ends the schedule
+pub fn withdraw_dca(ctx: Context<WithdrawDca>) -> Result<()> {
+    let schedule = &ctx.accounts.schedule;
+    let schedule_id = schedule.schedule_id.to_le_bytes();
+    let schedule_seeds: &[&[&[u8]]] = &[&[
+        DCA_SEED,
+        schedule.owner.as_ref(),
+        &schedule_id,
+        &[schedule.bump],
+    ]];
+
+    refund_escrow(
+        &ctx.accounts.token_program,
+        &ctx.accounts.dca_vault,
+        ctx.accounts.schedule.to_account_info(),
+        schedule_seeds,
+        &ctx.accounts.user_token_in,
+        ctx.accounts.owner.to_account_info(),
+    )
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: u64, sell_a: bool)]
+pub struct PlaceLongTermOrder<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = owner,
+        space = LongTermOrder::LEN,
+        seeds = [
+            LONG_TERM_ORDER_SEED,
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &order_id.to_le_bytes()
+        ],
+        bump
+    )]
+    pub long_term_order: Account<'info, LongTermOrder>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    // Vault of the sold token
+    #[account(mut, address = pool.vault(pool.twamm.pair(sell_a).0) @ SwapError::TokenNotFound)]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// Sells the amount into the pool at a constant rate, until the first interval
+// boundary at least the given number of slots away. What does not divide into the
+// rate stays with the user.
+pub fn place_long_term_order(
+    ctx: Context<PlaceLongTermOrder>,
+    order_id: u64,
+    sell_a: bool,
+    amount: u64,
+    slots: u64,
+) -> Result<()> {
+    require!(slots > 0, SwapError::InvalidAmount);
+    let slot = Clock::get()?.slot;
+    let pool = &mut ctx.accounts.pool;
+    pool.settle_twamm(slot)?;
+    require!(
+        pool.twamm.token_a != Pubkey::default(),
+        SwapError::UnsupportedCurve
+    );
+
+    let end_slot = slot
+        .checked_add(slots)
+        .ok_or(SwapError::MathError)?
+        .div_ceil(TWAMM_INTERVAL_SLOTS)
+        .checked_mul(TWAMM_INTERVAL_SLOTS)
+        .ok_or(SwapError::MathError)?;
+    let sell_rate = amount / (end_slot - slot);
+    require!(sell_rate > 0, SwapError::InvalidAmount);
+    let sold_amount = sell_rate * (end_slot - slot);
+
+    // Orders ending in the same slot share an expiry
+    let twamm = &mut pool.twamm;
+    let expiry_index = match twamm
+        .expiries
+        .iter()
+        .position(|expiry| expiry.slot == end_slot)
+    {
+        Some(index) => index,
+        None => {
+            require!(
+                twamm.expiries.len() < Twamm::MAX_EXPIRIES,
+                SwapError::TwammExpiriesFull
+            );
+            twamm.expiries.push(TwammExpiry {
+                slot: end_slot,
+                ..Default::default()
+            });
+            twamm.expiries.len() - 1
+        }
+    };
+    let expiry = &mut twamm.expiries[expiry_index];
+    expiry.orders = expiry.orders.checked_add(1).ok_or(SwapError::MathError)?;
+    let (expiry_rate, pool_rate, earnings_per_rate_x64) = if sell_a {
+        (
+            &mut expiry.sell_rate_a,
+            &mut twamm.sell_rate_a,
+            twamm.earnings_per_rate_a_x64,
+        )
+    } else {
+        (
+            &mut expiry.sell_rate_b,
+            &mut twamm.sell_rate_b,
+            twamm.earnings_per_rate_b_x64,
+        )
+    };
+    *expiry_rate = expiry_rate
+        .checked_add(sell_rate)
+        .ok_or(SwapError::MathError)?;
+    *pool_rate = pool_rate
+        .checked_add(sell_rate)
+        .ok_or(SwapError::MathError)?;
+
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.user_token_in.to_account_info(),
+                to: ctx.accounts.pool_token_in.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        ),
+        sold_amount,
+    )?;
+
+    let long_term_order = &mut ctx.accounts.long_term_order;
+    long_term_order.pool = ctx.accounts.pool.key();
+    long_term_order.owner = ctx.accounts.owner.key();
+    long_term_order.sell_a = sell_a;
+    long_term_order.sell_rate = sell_rate;
+    long_term_order.end_slot = end_slot;
+    long_term_order.earnings_per_rate_last_x64 = earnings_per_rate_x64;
+    long_term_order.order_id = order_id;
+    long_term_order.bump = ctx.bumps.long_term_order;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClaimLongTermOrder<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Program signer of the pool, delegated over its vaults.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [
+            LONG_TERM_ORDER_SEED,
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &long_term_order.order_id.to_le_bytes()
+        ],
+        bump = long_term_order.bump,
+        has_one = pool,
+        has_one = owner @ SwapError::Unauthorized
+    )]
+    pub long_term_order: Account<'info, LongTermOrder>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    // Vault of the bought token
+    #[account(
+        mut,
+        address = pool.vault(pool.twamm.pair(long_term_order.sell_a).1) @ SwapError::TokenNotFound
+    )]
+    pub pool_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Pays out what a long-term order bought so far, and closes it once it has ended
+pub fn claim_long_term_order(ctx: Context<ClaimLongTermOrder>) -> Result<()> {
+    let slot = Clock::get()?.slot;
+    let pool = &mut ctx.accounts.pool;
+    pool.settle_twamm(slot)?;
+
+    let long_term_order = &mut ctx.accounts.long_term_order;
+    let twamm = &mut pool.twamm;
+    let expiry_index = twamm
+        .expiries
+        .iter()
+        .position(|expiry| expiry.slot == long_term_order.end_slot)
+        .ok_or(SwapError::MathError)?;
+
+    let ended = slot >= long_term_order.end_slot;
+    let (amount, earnings_per_rate_x64) =
+        long_term_order.proceeds(twamm, &twamm.expiries[expiry_index], ended)?;
+    long_term_order.earnings_per_rate_last_x64 = earnings_per_rate_x64;
+
+    if ended {
+        let expiry = &mut twamm.expiries[expiry_index];
+        expiry.orders = expiry.orders.checked_sub(1).ok_or(SwapError::MathError)?;
+        if expiry.orders == 0 {
+            twamm.expiries.remove(expiry_index);
+        }
+    }
+
+    if amount > 0 {
+        let pool_key = ctx.accounts.pool.key();
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.pool_token_out.to_account_info(),
+                    to: ctx.accounts.user_token_out.to_account_info(),
+                    authority: ctx.accounts.pool_signer.to_account_info(),
+                },
+                &[&[POOL_SEED, pool_key.as_ref(), &[ctx.bumps.pool_signer]]],
+            ),
+            amount,
+        )?;
+    }
+    if ended {
+        ctx.accounts
+            .long_term_order
+            .close(ctx.accounts.owner.to_account_info())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct CancelLongTermOrder<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    /// CHECK: Program signer of the pool, delegated over its vaults.
+    #[account(seeds = [POOL_SEED, pool.key().as_ref()], bump)]
+    pub pool_signer: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [
+            LONG_TERM_ORDER_SEED,
+            pool.key().as_ref(),
+            owner.key().as_ref(),
+            &long_term_order.order_id.to_le_bytes()
+        ],
+        bump = long_term_order.bump,
+        has_one = pool,
+        has_one = owner @ SwapError::Unauthorized,
+        close = owner
+    )]
+    pub long_term_order: Account<'info, LongTermOrder>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    // Vault of the sold token
+    #[account(
+        mut,
+        address = pool.vault(pool.twamm.pair(long_term_order.sell_a).0) @ SwapError::TokenNotFound
+    )]
+    pub pool_token_in: Account<'info, TokenAccount>,
+    // Vault of the bought token
+    #[account(
+        mut,
+        address = pool.vault(pool.twamm.pair(long_term_order.sell_a).1) @ SwapError::TokenNotFound
+    )]
+    pub pool_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Stops a long-term order before its end slot. Pays out what it bought so far,
+// refunds what it has not sold yet and closes it.
+pub fn cancel_long_term_order(ctx: Context<CancelLongTermOrder>) -> Result<()> {
+    let slot = Clock::get()?.slot;
+    let pool = &mut ctx.accounts.pool;
+    pool.settle_twamm(slot)?;
+
+    // An order that has ended is closed by claiming it
+    let long_term_order = &ctx.accounts.long_term_order;
+    require!(slot < long_term_order.end_slot, SwapError::OrderExpired);
+
+    let twamm = &mut pool.twamm;
+    let expiry_index = twamm
+        .expiries
+        .iter()
+        .position(|expiry| expiry.slot == long_term_order.end_slot)
+        .ok_or(SwapError::MathError)?;
+    let (amount_out, _) = long_term_order.proceeds(twamm, &twamm.expiries[expiry_index], false)?;
+    let amount_in = long_term_order
+        .sell_rate
+        .checked_mul(long_term_order.end_slot - slot)
+        .ok_or(SwapError::MathError)?;
+
+    // The order stops selling now instead of at its end slot
+    let sell_rate = long_term_order.sell_rate;
+    let expiry = &mut twamm.expiries[expiry_index];
+    let (expiry_rate, pool_rate) = if long_term_order.sell_a {
+        (&mut expiry.sell_rate_a, &mut twamm.sell_rate_a)
+    } else {
+        (&mut expiry.sell_rate_b, &mut twamm.sell_rate_b)
+    };
+    *expiry_rate = expiry_rate
+        .checked_sub(sell_rate)
+        .ok_or(SwapError::MathError)?;
+    *pool_rate = pool_rate
+        .checked_sub(sell_rate)
+        .ok_or(SwapError::MathError)?;
+    expiry.orders = expiry.orders.checked_sub(1).ok_or(SwapError::MathError)?;
+    if expiry.orders == 0 {
+        twamm.expiries.remove(expiry_index);
+    }
+
+    let pool_key = ctx.accounts.pool.key();
+    let pool_signer_seeds: &[&[&[u8]]] =
+        &[&[POOL_SEED, pool_key.as_ref(), &[ctx.bumps.pool_signer]]];
+    pay_out_of_vaults(
+        &ctx.accounts.token_program,
+        (
+            ctx.accounts.pool_signer.to_account_info(),
+            pool_signer_seeds,
+        ),
+        [
+            (
+                &ctx.accounts.pool_token_in,
+                &ctx.accounts.user_token_in,
+                amount_in,
+            ),
+            (
+                &ctx.accounts.pool_token_out,
+                &ctx.accounts.user_token_out,
+                amount_out,
+            ),
+        ],
+    )
+}
+
+#[derive(Accounts)]
+#[instruction(quote: RfqQuote)]
+pub struct FillRfq<'info> {
+    #[account(mut)]
+    pub taker: Signer<'info>,
+    // Created on the first fill, so a replayed quote fails here
+    #[account(
+        init,
+        payer = taker,
+        space = RfqNonce::LEN,
+        seeds = [RFQ_NONCE_SEED, quote.maker.as_ref(), &quote.nonce.to_le_bytes()],
+        bump
+    )]
+    pub rfq_nonce: Account<'info, RfqNonce>,
+    // Makers approve the RFQ authority as delegate of the account they sell from
+    #[account(
+        mut,
+        token::mint = quote.maker_mint,
+        token::authority = quote.maker
+    )]
+    pub maker_token_out: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        token::mint = quote.taker_mint,
+        token::authority = quote.maker
+    )]
+    pub maker_token_in: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = quote.maker_mint)]
+    pub taker_token_in: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = quote.taker_mint)]
+    pub taker_token_out: Account<'info, TokenAccount>,
+    #[account(seeds = [RFQ_AUTHORITY_SEED], bump)]
+    pub rfq_authority: SystemAccount<'info>,
+    /// CHECK: check instructions account
+    #[account(address = sysvar::instructions::ID @FlashFillError::AddressMismatch)]
+    pub instructions: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+// The ed25519 program instruction starts with a signature count and a padding byte,
+// followed per signature by seven u16 offsets: signature, signature instruction,
+// public key, public key instruction, message, message size and message instruction.
+const ED25519_OFFSETS_START: usize = 2;
+const ED25519_OFFSETS_LEN: usize = 14;
+
+// Checks the ed25519 program instruction verifies a single signature by the maker
+// over exactly the message, with all of it inside the instruction itself
+fn verify_quote_signature(ix: &Instruction, maker: &Pubkey, message: &[u8]) -> Result<()> {
+    require_keys_eq!(
+        ix.program_id,
+        ed25519_program::ID,
+        SwapError::InvalidQuoteSignature
+    );
+    let data = &ix.data;
+    require!(
+        data.len() >= ED25519_OFFSETS_START + ED25519_OFFSETS_LEN && data[0] == 1,
+        SwapError::InvalidQuoteSignature
+    );
+
+    let offset = |index: usize| {
+        let at = ED25519_OFFSETS_START + index * 2;
+        u16::from_le_bytes([data[at], data[at + 1]])
+    };
+    let slice = |start: u16, len: usize| data.get(start as usize..start as usize + len);
+    // u16::MAX points the ed25519 program at its own instruction data
+    require!(
+        [1, 3, 6].iter().all(|&index| offset(index) == u16::MAX),
+        SwapError::InvalidQuoteSignature
+    );
+    require!(
+        slice(offset(2), 32) == Some(maker.as_ref()),
+        SwapError::InvalidQuoteSignature
+    );
+    require!(
+        offset(5) as usize == message.len() && slice(offset(4), message.len()) == Some(message),
+        SwapError::InvalidQuoteSignature
+    );
+
+    Ok(())
+}
+
+// Swaps the quoted amounts between maker and taker. The maker's signature over the
+// borsh encoded quote is verified by an ed25519 program instruction placed right
+// before this one.
+pub fn fill_rfq(ctx: Context<FillRfq>, quote: RfqQuote) -> Result<()> {
+    require!(
+        quote.maker_amount > 0 && quote.taker_amount > 0,
+        SwapError::InvalidAmount
+    );
+    require_keys_eq!(
+        quote.taker,
+        ctx.accounts.taker.key(),
+        SwapError::Unauthorized
+    );
+    require!(
+        Clock::get()?.unix_timestamp <= quote.expiry,
+        SwapError::QuoteExpired
+    );
+
+    let ixs = ctx.accounts.instructions.to_account_info();
+    let current_index = load_current_index_checked(&ixs)?;
+    require!(current_index > 0, SwapError::InvalidQuoteSignature);
+    let signature_ix = load_instruction_at_checked(current_index as usize - 1, &ixs)
+        .map_err(|_| SwapError::InvalidQuoteSignature)?;
+    verify_quote_signature(&signature_ix, &quote.maker, &quote.try_to_vec()?)?;
+
+    let authority_bump: [u8; 1] = [ctx.bumps.rfq_authority];
+    let signer_seeds: &[&[&[u8]]] = &[&[RFQ_AUTHORITY_SEED, &authority_bump]];
+    token::transfer(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.maker_token_out.to_account_info(),
+                to: ctx.accounts.taker_token_in.to_account_info(),
+                authority: ctx.accounts.rfq_authority.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        quote.maker_amount,
+    )?;
+    token::transfer(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.taker_token_out.to_account_info(),
+                to: ctx.accounts.maker_token_in.to_account_info(),
+                authority: ctx.accounts.taker.to_account_info(),
+            },
+        ),
+        quote.taker_amount,
+    )?;
+
+    let rfq_nonce = &mut ctx.accounts.rfq_nonce;
+    rfq_nonce.maker = quote.maker;
+    rfq_nonce.nonce = quote.nonce;
+    rfq_nonce.bump = ctx.bumps.rfq_nonce;
+
+    emit!(RfqFillEvent {
+        maker: quote.maker,
+        taker: quote.taker,
+        maker_mint: quote.maker_mint,
+        taker_mint: quote.taker_mint,
+        maker_amount: quote.maker_amount,
+        taker_amount: quote.taker_amount,
+        nonce: quote.nonce,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeFarm<'info> {
+    #[account(has_one = authority @ SwapError::Unauthorized)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        init,
+        payer = authority,
+        space = Farm::LEN,
+        seeds = [FARM_SEED, pool.key().as_ref()],
+        bump
+    )]
+    pub farm: Account<'info, Farm>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_farm(ctx: Context<InitializeFarm>) -> Result<()> {
+    let farm = &mut ctx.accounts.farm;
+    farm.pool = ctx.accounts.pool.key();
+    farm.authority = ctx.accounts.authority.key();
+    farm.last_update_time = Clock::get()?.unix_timestamp;
+    farm.bump = ctx.bumps.farm;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct AddFarmReward<'info> {
+    #[account(mut, has_one = authority @ SwapError::Unauthorized)]
+    pub farm: Account<'info, Farm>,
+    pub reward_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        seeds = [FARM_REWARD_VAULT_SEED, farm.key().as_ref(), reward_mint.key().as_ref()],
+        bump,
+        token::mint = reward_mint,
+        token::authority = farm
+    )]
+    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+pub fn add_farm_reward(
+    ctx: Context<AddFarmReward>,
+    emission_per_second: u64,
+    end_time: i64,
+) -> Result<()> {
+    let farm = &mut ctx.accounts.farm;
+    farm.update_rewards(Clock::get()?.unix_timestamp)?;
+    require!(
+        farm.rewards.len() < Farm::MAX_REWARDS,
+        SwapError::FarmRewardsFull
+    );
+
+    farm.rewards.push(FarmReward {
+        mint: ctx.accounts.reward_mint.key(),
+        vault: ctx.accounts.reward_vault.key(),
+        emission_per_second,
+        end_time,
+        reward_per_share_x64: 0,
+        unclaimed: 0,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct FundFarmReward<'info> {
+    #[account(mut, has_one = authority @ SwapError::Unauthorized)]
+    pub farm: Account<'info, Farm>,
+    #[account(mut)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority_token: Account<'info, TokenAccount>,
+    pub authority: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Tops up a reward vault and sets its emissions from now on. The vault has to hold
+// the emissions until the end time.
+pub fn fund_farm_reward(
+    ctx: Context<FundFarmReward>,
+    amount: u64,
+    emission_per_second: u64,
+    end_time: i64,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let farm = &mut ctx.accounts.farm;
+    farm.update_rewards(now)?;
+    require!(
+        end_time > now && end_time >= farm.last_update_time,
+        SwapError::InvalidAmount
+    );
+    let reward = farm
+        .rewards
+        .iter_mut()
+        .find(|reward| reward.vault == ctx.accounts.reward_vault.key())
+        .ok_or(SwapError::RewardNotFound)?;
+    reward.emission_per_second = emission_per_second;
+    reward.end_time = end_time;
+    let unclaimed = reward.unclaimed;
+
+    if amount > 0 {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.authority_token.to_account_info(),
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.authority.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+    }
+
+    // The vault has to cover what stakers earned so far and the emissions to come
+    ctx.accounts.reward_vault.reload()?;
+    let required = (emission_per_second as u128)
+        .checked_mul((end_time - now) as u128)
+        .and_then(|emissions| emissions.checked_add(unclaimed as u128))
+        .ok_or(SwapError::MathError)?;
+    require!(
+        ctx.accounts.reward_vault.amount as u128 >= required,
+        SwapError::RewardVaultUnderfunded
+    );
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateStake<'info> {
+    #[account(mut)]
+    pub farm: Account<'info, Farm>,
+    #[account(
+        mut,
+        has_one = owner @ SwapError::Unauthorized,
+        constraint = liquidity_shares.pool == farm.pool @ SwapError::Unauthorized
+    )]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    pub owner: Signer<'info>,
+}
+
+// Brings the farm and the stake up to date before the shares change
+fn accrue_stake_rewards(farm: &mut Farm, liquidity_shares: &mut LiquidityShares) -> Result<()> {
+    farm.update_rewards(Clock::get()?.unix_timestamp)?;
+    liquidity_shares.update_rewards(farm)
+}
+
+// Registers the farm weight of the shares after they changed
+fn sync_stake_weight(farm: &mut Farm, liquidity_shares: &mut LiquidityShares) -> Result<()> {
+    let weight = liquidity_shares.farm_weight()?;
+    farm.total_weight = farm
+        .total_weight
+        .checked_sub(liquidity_shares.weight)
+        .and_then(|total_weight| total_weight.checked_add(weight))
+        .ok_or(SwapError::MathError)?;
+    liquidity_shares.weight = weight;
+    Ok(())
+}
+
+pub fn stake_lp(ctx: Context<UpdateStake>, amount: u64) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+    let farm = &mut ctx.accounts.farm;
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    accrue_stake_rewards(farm, liquidity_shares)?;
+    require!(
+        liquidity_shares.shares >= amount,
+        SwapError::InsufficientShares
+    );
+
+    liquidity_shares.shares -= amount;
+    liquidity_shares.staked = liquidity_shares
+        .staked
+        .checked_add(amount)
+        .ok_or(SwapError::MathError)?;
+    sync_stake_weight(farm, liquidity_shares)
+}
+
+// Rewards earned so far stay claimable after unstaking
+pub fn unstake_lp(ctx: Context<UpdateStake>, amount: u64) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+    let farm = &mut ctx.accounts.farm;
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    accrue_stake_rewards(farm, liquidity_shares)?;
+    require!(
+        liquidity_shares.staked >= amount,
+        SwapError::InsufficientShares
+    );
+
+    liquidity_shares.staked -= amount;
+    liquidity_shares.shares = liquidity_shares
+        .shares
+        .checked_add(amount)
+        .ok_or(SwapError::MathError)?;
+    sync_stake_weight(farm, liquidity_shares)
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(mut)]
+    pub farm: Account<'info, Farm>,
+    #[account(
+        mut,
+        has_one = owner @ SwapError::Unauthorized,
+        constraint = liquidity_shares.pool == farm.pool @ SwapError::Unauthorized
+    )]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    #[account(mut)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_reward_token: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Pays out what the stake earned in one reward, as far as the vault covers it
+pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+    let farm = &mut ctx.accounts.farm;
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    accrue_stake_rewards(farm, liquidity_shares)?;
+
+    let reward_index = farm
+        .rewards
+        .iter()
+        .position(|reward| reward.vault == ctx.accounts.reward_vault.key())
+        .ok_or(SwapError::RewardNotFound)?;
+    let stake_reward = &mut liquidity_shares.rewards[reward_index];
+    let amount = stake_reward.pending.min(ctx.accounts.reward_vault.amount);
+    stake_reward.pending -= amount;
+    if amount == 0 {
+        return Ok(());
+    }
+    let reward = &mut farm.rewards[reward_index];
+    reward.unclaimed = reward
+        .unclaimed
+        .checked_sub(amount)
+        .ok_or(SwapError::MathError)?;
+
+    let (pool_key, farm_bump) = (farm.pool, [farm.bump]);
+    let farm_seeds: &[&[u8]] = &[FARM_SEED, pool_key.as_ref(), &farm_bump];
+    token::transfer(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.reward_vault.to_account_info(),
+                to: ctx.accounts.user_reward_token.to_account_info(),
+                authority: ctx.accounts.farm.to_account_info(),
+            },
+            &[farm_seeds],
+        ),
+        amount,
+    )?;
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct LockLiquidity<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, Pool>,
+    #[account(
+        mut,
+        has_one = pool,
+        has_one = owner @ SwapError::Unauthorized
+    )]
+    pub liquidity_shares: Account<'info, LiquidityShares>,
+    // The pool's farm, required once the shares have weight in it
+    #[account(mut, constraint = farm.pool == pool.key() @ SwapError::Unauthorized)]
+    pub farm: Option<Account<'info, Farm>>,
+    pub owner: Signer<'info>,
+}
+
+// Accrues the farm rewards of the shares before their lock changes, and returns
+// the farm to register their new weight with
+fn accrue_lock_rewards<'a, 'info>(
+    farm: &'a mut Option<Account<'info, Farm>>,
+    liquidity_shares: &mut LiquidityShares,
+) -> Result<Option<&'a mut Account<'info, Farm>>> {
+    match farm {
+        Some(farm) => {
+            accrue_stake_rewards(farm, liquidity_shares)?;
+            Ok(Some(farm))
+        }
+        None => {
+            require!(liquidity_shares.weight == 0, SwapError::FarmRequired);
+            Ok(None)
+        }
+    }
+}
+
+// Locks free shares until the unlock time. Shares locked for longer get a larger
+// boost on farm rewards. A lock has to be released before shares are locked again,
+// so its start and the penalty it sets stay fixed.
+pub fn lock_liquidity(ctx: Context<LockLiquidity>, amount: u64, duration: i64) -> Result<()> {
+    require!(amount > 0, SwapError::InvalidAmount);
+    require!(
+        duration > 0 && duration <= MAX_LOCK_SECONDS,
+        SwapError::InvalidLockDuration
+    );
+    let now = Clock::get()?.unix_timestamp;
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    require!(liquidity_shares.locked == 0, SwapError::LockActive);
+    require!(
+        liquidity_shares.shares >= amount,
+        SwapError::InsufficientShares
+    );
+    let farm = accrue_lock_rewards(&mut ctx.accounts.farm, liquidity_shares)?;
+
+    liquidity_shares.shares -= amount;
+    liquidity_shares.locked = amount;
+    liquidity_shares.lock_start = now;
+    liquidity_shares.unlock_time = now + duration;
+    liquidity_shares.lock_boost_bps =
+        (MAX_LOCK_BOOST_BPS * duration as u64 / MAX_LOCK_SECONDS as u64) as u16;
+    if let Some(farm) = farm {
+        sync_stake_weight(farm, liquidity_shares)?;
+    }
+
+    let pool = &mut ctx.accounts.pool;
+    pool.locked_shares = pool
+        .locked_shares
+        .checked_add(amount)
+        .ok_or(SwapError::MathError)?;
+    Ok(())
+}
+
+// Releases the locked shares. Before the unlock time part of them is burned,
+// which the remaining liquidity providers gain.
+pub fn unlock_liquidity(ctx: Context<LockLiquidity>) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    let liquidity_shares = &mut ctx.accounts.liquidity_shares;
+    let locked = liquidity_shares.locked;
+    require!(locked > 0, SwapError::InsufficientShares);
+    let penalty = liquidity_shares.early_unlock_penalty(now)?;
+    let released = locked.checked_sub(penalty).ok_or(SwapError::MathError)?;
+    let farm = accrue_lock_rewards(&mut ctx.accounts.farm, liquidity_shares)?;
+
+    liquidity_shares.locked = 0;
+    liquidity_shares.lock_boost_bps = 0;
+    liquidity_shares.shares = liquidity_shares
+        .shares
+        .checked_add(released)
+        .ok_or(SwapError::MathError)?;
+    if let Some(farm) = farm {
+        sync_stake_weight(farm, liquidity_shares)?;
+    }
+
+    let pool = &mut ctx.accounts.pool;
+    pool.locked_shares = pool
+        .locked_shares
+        .checked_sub(locked)
+        .ok_or(SwapError::MathError)?;
+    pool.total_shares = pool
+        .total_shares
+        .checked_sub(penalty)
+        .ok_or(SwapError::MathError)?;
+
+    emit!(LiquidityUnlockEvent {
+        pool: pool.key(),
+        owner: liquidity_shares.owner,
+        shares: released,
+        penalty,
+    });
+    Ok(())
+}
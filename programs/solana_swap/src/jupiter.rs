@@ -0,0 +1,255 @@
+use crate::constants::BPS_DENOMINATOR;
+use crate::errors::SwapError;
+use anchor_lang::declare_id;
+
+declare_id!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+// Anchor discriminators (first 8 bytes of sha256("global:<name>")) of the Jupiter
+// route instructions the program is allowed to CPI into.
+pub const ROUTE: [u8; 8] = [229, 23, 203, 151, 122, 227, 173, 42];
+pub const ROUTE_WITH_TOKEN_LEDGER: [u8; 8] = [150, 86, 71, 116, 167, 93, 14, 104];
+pub const SHARED_ACCOUNTS_ROUTE: [u8; 8] = [193, 32, 155, 51, 65, 214, 156, 129];
+pub const SHARED_ACCOUNTS_ROUTE_WITH_TOKEN_LEDGER: [u8; 8] =
+    [230, 121, 143, 80, 119, 159, 106, 170];
+pub const EXACT_OUT_ROUTE: [u8; 8] = [208, 51, 239, 151, 123, 43, 237, 92];
+pub const SHARED_ACCOUNTS_EXACT_OUT_ROUTE: [u8; 8] = [176, 209, 105, 168, 154, 125, 69, 62];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteKind {
+    Route,
+    RouteWithTokenLedger,
+    SharedAccountsRoute,
+    SharedAccountsRouteWithTokenLedger,
+    ExactOutRoute,
+    SharedAccountsExactOutRoute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RouteArgs {
+    pub kind: RouteKind,
+    pub in_amount: Option<u64>, // None for token ledger routes, quoted input for exact out routes
+    pub quoted_out_amount: u64, // Exact output for exact out routes
+    pub slippage_bps: u16,
+    pub platform_fee_bps: u8,
+}
+
+impl RouteArgs {
+    // Most input the route can spend, None when a token ledger sets it at execution
+    pub fn max_in_amount(&self) -> Option<u64> {
+        let in_amount = self.in_amount?;
+        if !self.kind.is_exact_out() {
+            return Some(in_amount);
+        }
+        // Exact out routes may spend the quoted input plus the slippage
+        let max_in_amount = in_amount as u128
+            * (BPS_DENOMINATOR + self.slippage_bps as u64) as u128
+            / BPS_DENOMINATOR as u128;
+        Some(u64::try_from(max_in_amount).unwrap_or(u64::MAX))
+    }
+
+    // Checks the route against the input the caller commits and the output it expects
+    pub fn check_amounts(&self, max_in_amount: u64, min_out_amount: u64) -> Result<(), SwapError> {
+        if self
+            .max_in_amount()
+            .is_some_and(|in_amount| in_amount > max_in_amount)
+        {
+            return Err(SwapError::RouteAmountMismatch);
+        }
+        if self.quoted_out_amount < min_out_amount {
+            return Err(SwapError::SlippageError);
+        }
+        Ok(())
+    }
+}
+
+impl RouteKind {
+    pub fn from_discriminator(discriminator: &[u8]) -> Option<Self> {
+        let discriminator: [u8; 8] = discriminator.try_into().ok()?;
+        match discriminator {
+            ROUTE => Some(Self::Route),
+            ROUTE_WITH_TOKEN_LEDGER => Some(Self::RouteWithTokenLedger),
+            SHARED_ACCOUNTS_ROUTE => Some(Self::SharedAccountsRoute),
+            SHARED_ACCOUNTS_ROUTE_WITH_TOKEN_LEDGER => {
+                Some(Self::SharedAccountsRouteWithTokenLedger)
+            }
+            EXACT_OUT_ROUTE => Some(Self::ExactOutRoute),
+            SHARED_ACCOUNTS_EXACT_OUT_ROUTE => Some(Self::SharedAccountsExactOutRoute),
+            _ => None,
+        }
+    }
+
+    pub fn uses_token_ledger(&self) -> bool {
+        matches!(
+            self,
+            Self::RouteWithTokenLedger | Self::SharedAccountsRouteWithTokenLedger
+        )
+    }
+
+    pub fn is_exact_out(&self) -> bool {
+        matches!(
+            self,
+            Self::ExactOutRoute | Self::SharedAccountsExactOutRoute
+        )
+    }
+}
+
+pub fn parse_route(data: &[u8]) -> Result<RouteArgs, SwapError> {
+    let kind = data
+        .get(0..8)
+        .and_then(RouteKind::from_discriminator)
+        .ok_or(SwapError::JupiterRouteNotAllowed)?;
+
+    // The route plan is a variable length vector, so the fixed size arguments are
+    // read from the end of the data:
+    // [in_amount: u64] quoted_out_amount: u64, slippage_bps: u16, platform_fee_bps: u8
+    let tail_len = if kind.uses_token_ledger() { 11 } else { 19 };
+    let args = &data[8..];
+    if args.len() < tail_len {
+        return Err(SwapError::InvalidRouteData);
+    }
+    let tail = &args[args.len() - tail_len..];
+
+    let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    let (first_amount, rest) = if kind.uses_token_ledger() {
+        (None, tail)
+    } else {
+        (Some(read_u64(&tail[0..8])), &tail[8..])
+    };
+    let second_amount = read_u64(&rest[0..8]);
+
+    // Exact out routes take the output first: out_amount, quoted_in_amount
+    let (in_amount, quoted_out_amount) = match first_amount {
+        Some(out_amount) if kind.is_exact_out() => (Some(second_amount), out_amount),
+        _ => (first_amount, second_amount),
+    };
+
+    let route_args = RouteArgs {
+        kind,
+        in_amount,
+        quoted_out_amount,
+        slippage_bps: u16::from_le_bytes([rest[8], rest[9]]),
+        platform_fee_bps: rest[10],
+    };
+
+    if route_args.slippage_bps as u64 > BPS_DENOMINATOR {
+        return Err(SwapError::InvalidRouteData);
+    }
+
+    Ok(route_args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Route instruction data with an empty route plan. Shared accounts routes start
+    // with a one byte id, token ledger routes carry a single amount.
+    fn route_data(discriminator: [u8; 8], amounts: &[u64], slippage_bps: u16) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        if matches!(
+            RouteKind::from_discriminator(&discriminator),
+            Some(
+                RouteKind::SharedAccountsRoute
+                    | RouteKind::SharedAccountsRouteWithTokenLedger
+                    | RouteKind::SharedAccountsExactOutRoute
+            )
+        ) {
+            data.push(7);
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for amount in amounts {
+            data.extend_from_slice(&amount.to_le_bytes());
+        }
+        data.extend_from_slice(&slippage_bps.to_le_bytes());
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn parses_exact_in_routes() {
+        for (discriminator, kind) in [
+            (ROUTE, RouteKind::Route),
+            (SHARED_ACCOUNTS_ROUTE, RouteKind::SharedAccountsRoute),
+        ] {
+            let args = parse_route(&route_data(discriminator, &[1_000, 900], 50)).unwrap();
+            assert_eq!(args.kind, kind);
+            assert_eq!(args.in_amount, Some(1_000));
+            assert_eq!(args.quoted_out_amount, 900);
+            assert_eq!(args.slippage_bps, 50);
+            assert_eq!(args.max_in_amount(), Some(1_000));
+        }
+    }
+
+    #[test]
+    fn parses_token_ledger_routes() {
+        for (discriminator, kind) in [
+            (ROUTE_WITH_TOKEN_LEDGER, RouteKind::RouteWithTokenLedger),
+            (
+                SHARED_ACCOUNTS_ROUTE_WITH_TOKEN_LEDGER,
+                RouteKind::SharedAccountsRouteWithTokenLedger,
+            ),
+        ] {
+            let args = parse_route(&route_data(discriminator, &[900], 50)).unwrap();
+            assert_eq!(args.kind, kind);
+            assert_eq!(args.in_amount, None);
+            assert_eq!(args.quoted_out_amount, 900);
+            assert_eq!(args.max_in_amount(), None);
+        }
+    }
+
+    #[test]
+    fn parses_exact_out_routes() {
+        for (discriminator, kind) in [
+            (EXACT_OUT_ROUTE, RouteKind::ExactOutRoute),
+            (
+                SHARED_ACCOUNTS_EXACT_OUT_ROUTE,
+                RouteKind::SharedAccountsExactOutRoute,
+            ),
+        ] {
+            // out_amount comes before quoted_in_amount
+            let args = parse_route(&route_data(discriminator, &[900, 1_000], 50)).unwrap();
+            assert_eq!(args.kind, kind);
+            assert_eq!(args.in_amount, Some(1_000));
+            assert_eq!(args.quoted_out_amount, 900);
+            assert_eq!(args.max_in_amount(), Some(1_005));
+        }
+    }
+
+    #[test]
+    fn rejects_other_instructions() {
+        let data = route_data([0; 8], &[1_000, 900], 50);
+        assert!(matches!(
+            parse_route(&data),
+            Err(SwapError::JupiterRouteNotAllowed)
+        ));
+        let data = route_data(ROUTE, &[1_000, 900], 10_001);
+        assert!(matches!(
+            parse_route(&data),
+            Err(SwapError::InvalidRouteData)
+        ));
+    }
+
+    #[test]
+    fn checks_route_amounts() {
+        let exact_in = parse_route(&route_data(ROUTE, &[1_000, 900], 50)).unwrap();
+        assert!(exact_in.check_amounts(1_000, 900).is_ok());
+        assert!(matches!(
+            exact_in.check_amounts(999, 900),
+            Err(SwapError::RouteAmountMismatch)
+        ));
+        assert!(matches!(
+            exact_in.check_amounts(1_000, 901),
+            Err(SwapError::SlippageError)
+        ));
+
+        let exact_out = parse_route(&route_data(EXACT_OUT_ROUTE, &[900, 1_000], 50)).unwrap();
+        assert!(exact_out.check_amounts(1_005, 900).is_ok());
+        assert!(matches!(
+            exact_out.check_amounts(1_004, 900),
+            Err(SwapError::RouteAmountMismatch)
+        ));
+
+        let ledger = parse_route(&route_data(ROUTE_WITH_TOKEN_LEDGER, &[900], 50)).unwrap();
+        assert!(ledger.check_amounts(0, 900).is_ok());
+    }
+}
@@ -1,25 +1,16 @@
 use crate::instructions::*;
+use crate::state::{CurveType, DcaRoute, OracleGuard, OrderKind, RfqQuote, WeightSchedule};
 use anchor_lang::prelude::*;
 
 pub mod constants;
 pub mod curve;
 pub mod errors;
+pub mod events;
 pub mod instructions;
+pub mod jupiter;
+pub mod oracle;
 pub mod state;
 
-use crate::errors::{FlashFillError, SwapError};
-use anchor_lang::prelude::*;
-use anchor_lang::solana_program::sysvar;
-use anchor_lang::solana_program::sysvar::instructions::{
-    load_current_index_checked, load_instruction_at_checked,
-};
-use anchor_lang::Discriminator;
-use anchor_spl::token::TokenAccount;
-
-use anchor_lang::system_program;
-
-pub const AUTHORITY_SEED: &[u8] = b"authority";
-
 declare_id!("7aEi72qNaX16AJBDhgsmsPvyXuMKCXea9qRA2jVL6Hjg");
 
 #[program]
@@ -41,6 +32,11 @@ pub mod solana_swap {
         instructions::initialize_pool_token(ctx, token_account)
     }
 
+    // Opens the account holding a liquidity provider's pool shares
+    pub fn open_liquidity_shares(ctx: Context<OpenLiquidityShares>) -> Result<()> {
+        instructions::open_liquidity_shares(ctx)
+    }
+
     pub fn add_liquidity(
         ctx: Context<AddLiquidity>,
         token_account: Pubkey,
@@ -57,12 +53,34 @@ pub mod solana_swap {
         instructions::remove_liquidity(ctx, token_account, amount)
     }
 
+    // Deposits every pool token in proportion to the reserves for pool shares
+    pub fn mint_liquidity_shares<'info>(
+        ctx: Context<'_, '_, '_, 'info, MintLiquidityShares<'info>>,
+        shares: u64,
+        max_amounts: Vec<u64>,
+    ) -> Result<()> {
+        instructions::mint_liquidity_shares(ctx, shares, max_amounts)
+    }
+
+    // Withdraws the part of every pool token free pool shares are worth
+    pub fn burn_liquidity_shares<'info>(
+        ctx: Context<'_, '_, '_, 'info, BurnLiquidityShares<'info>>,
+        shares: u64,
+        min_amounts: Vec<u64>,
+    ) -> Result<()> {
+        instructions::burn_liquidity_shares(ctx, shares, min_amounts)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn swap(
         ctx: Context<Swap>,
         input_token_account: Pubkey,
         output_token_account: Pubkey,
         input_amount: u64,
         min_output_amount: u64,
+        deadline: Option<i64>,
+        max_price_impact_bps: Option<u16>,
+        limit_price_x64: Option<u128>,
     ) -> Result<()> {
         instructions::swap(
             ctx,
@@ -70,131 +88,401 @@ pub mod solana_swap {
             output_token_account,
             input_amount,
             min_output_amount,
+            deadline,
+            max_price_impact_bps,
+            limit_price_x64,
         )
     }
 
-    // Jupiter swap to sol
-    pub fn swap_to_sol(ctx: Context<SwapToSOL>, data: Vec<u8>) -> Result<()> {
-        let authority_bump = ctx.bumps.program_authority;
-        let wsol_bump = ctx.bumps.program_wsol_account;
-
-        instructions::swap_to_sol(ctx, data, authority_bump, wsol_bump)
-    }
-
-    // Jupiter borrow repay
-    pub fn borrow(ctx: Context<Borrow>) -> Result<()> {
-        let ixs = ctx.accounts.instructions.to_account_info();
-
-        // make sure this isnt a cpi call
-        let current_index = load_current_index_checked(&ixs)? as usize;
-        let current_ix = load_instruction_at_checked(current_index, &ixs)?;
-        if current_ix.program_id != *ctx.program_id {
-            return Err(FlashFillError::ProgramMismatch.into());
-        }
-
-        // loop through instructions, looking for an equivalent repay to this borrow
-        let mut index = current_index + 1; // jupiter swap
-        loop {
-            // get the next instruction, die if theres no more
-            if let Ok(ix) = load_instruction_at_checked(index, &ixs) {
-                if ix.program_id == crate::id() {
-                    let ix_discriminator: [u8; 8] = ix.data[0..8]
-                        .try_into()
-                        .map_err(|_| FlashFillError::UnknownInstruction)?;
-
-                    // check if we have a toplevel repay toward the program authority
-                    if ix_discriminator == self::instruction::Repay::discriminator() {
-                        require_keys_eq!(
-                            ix.accounts[1].pubkey,
-                            ctx.accounts.program_authority.key(),
-                            FlashFillError::IncorrectProgramAuthority
-                        );
-
-                        break;
-                    } else if ix_discriminator == self::instruction::Borrow::discriminator() {
-                        return Err(FlashFillError::CannotBorrowBeforeRepay.into());
-                    } else {
-                        return Err(FlashFillError::UnknownInstruction.into());
-                    }
-                }
-            } else {
-                // no more instructions, so we're missing a repay
-                return Err(FlashFillError::MissingRepay.into());
-            }
-
-            index += 1
-        }
-
-        let authority_bump: [u8; 1] = [ctx.bumps.program_authority];
-        let rent = Rent::get()?;
-        let space = TokenAccount::LEN;
-        let token_lamports = rent.minimum_balance(space);
-
-        // transfer enough SOL to the borrower to open wSOL account.
-        let signer_seeds: &[&[&[u8]]] = &[&[AUTHORITY_SEED, authority_bump.as_ref()]];
-        system_program::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.system_program.to_account_info(),
-                system_program::Transfer {
-                    from: ctx.accounts.program_authority.to_account_info(),
-                    to: ctx.accounts.borrower.to_account_info(),
-                },
-                signer_seeds,
-            ),
-            token_lamports,
-        )?;
-
-        Ok(())
-    }
-
-    pub fn repay(ctx: Context<Repay>) -> Result<()> {
-        let ixs = ctx.accounts.instructions.to_account_info();
-
-        // make sure this isnt a cpi call
-        let current_index = load_current_index_checked(&ixs)? as usize;
-        let current_ix = load_instruction_at_checked(current_index, &ixs)?;
-        if current_ix.program_id != *ctx.program_id {
-            return Err(FlashFillError::ProgramMismatch.into());
-        }
-
-        let rent = Rent::get()?;
-        let space = TokenAccount::LEN;
-        let token_lamports = rent.minimum_balance(space);
-
-        // transfer borrowed SOL back to the program authority
-        system_program::transfer(
-            CpiContext::new(
-                ctx.accounts.system_program.to_account_info(),
-                system_program::Transfer {
-                    from: ctx.accounts.borrower.to_account_info(),
-                    to: ctx.accounts.program_authority.to_account_info(),
-                },
-            ),
-            token_lamports,
-        )?;
-
-        Ok(())
+    pub fn flash_swap(
+        ctx: Context<FlashSwap>,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+        output_amount: u64,
+        callback_data: Vec<u8>,
+    ) -> Result<()> {
+        instructions::flash_swap(
+            ctx,
+            input_token_account,
+            output_token_account,
+            output_amount,
+            callback_data,
+        )
     }
-}
 
-#[derive(Accounts)]
-pub struct Borrow<'info> {
-    pub borrower: Signer<'info>,
-    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
-    pub program_authority: SystemAccount<'info>,
-    /// CHECK: check instructions account
-    #[account(address = sysvar::instructions::ID @FlashFillError::AddressMismatch)]
-    pub instructions: UncheckedAccount<'info>,
-    pub system_program: Program<'info, System>,
-}
+    // Jupiter swap to any token, unwrapping wSOL output when no token account is given
+    pub fn swap_via_jupiter(
+        ctx: Context<SwapViaJupiter>,
+        output_mint: Pubkey,
+        in_amount: u64,
+        min_out: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        instructions::swap_via_jupiter(ctx, output_mint, in_amount, min_out, data)
+    }
+
+    // Swap to SOL for users without SOL. The relayer pays the transaction and rent
+    // and is reimbursed from the output.
+    pub fn gasless_swap_to_sol(
+        ctx: Context<GaslessSwapToSol>,
+        relayer_fee: u64,
+        max_relayer_fee: u64,
+        in_amount: u64,
+        min_lamports_out: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        instructions::gasless_swap_to_sol(
+            ctx,
+            relayer_fee,
+            max_relayer_fee,
+            in_amount,
+            min_lamports_out,
+            data,
+        )
+    }
+
+    // Pool authority sets the circuit breaker limits, zero disables a limit
+    pub fn configure_circuit_breaker(
+        ctx: Context<ManagePool>,
+        max_price_move_bps: u16,
+        window_slots: u64,
+        max_volume_per_slot: u64,
+    ) -> Result<()> {
+        instructions::configure_circuit_breaker(
+            ctx,
+            max_price_move_bps,
+            window_slots,
+            max_volume_per_slot,
+        )
+    }
+
+    // Pool authority bounds how far a swap may execute from the start of slot price
+    pub fn configure_price_band(
+        ctx: Context<ManagePool>,
+        band_bps: u16,
+        surcharge_bps: u16,
+    ) -> Result<()> {
+        instructions::configure_price_band(ctx, band_bps, surcharge_bps)
+    }
+
+    // Pool authority switches to a volatility driven fee, a zero max keeps the flat fee
+    pub fn configure_dynamic_fee(
+        ctx: Context<ManagePool>,
+        base_fee_bps: u16,
+        max_fee_bps: u16,
+        variable_fee_control: u16,
+        decay_slots: u64,
+    ) -> Result<()> {
+        instructions::configure_dynamic_fee(
+            ctx,
+            base_fee_bps,
+            max_fee_bps,
+            variable_fee_control,
+            decay_slots,
+        )
+    }
+
+    // Pool authority guards swaps with a price feed, a default feed key disables it
+    pub fn configure_oracle(ctx: Context<ManagePool>, oracle: OracleGuard) -> Result<()> {
+        instructions::configure_oracle(ctx, oracle)
+    }
+
+    // Pool authority picks the swap curve, PMM pools quote around the oracle price
+    pub fn configure_curve(ctx: Context<ManagePool>, curve: CurveType) -> Result<()> {
+        instructions::configure_curve(ctx, curve)
+    }
+
+    // Pool authority runs a liquidity bootstrapping sale on a weighted pool whose
+    // weights move from the start to the end weights over the sale
+    pub fn configure_lbp(ctx: Context<ManagePool>, weights: WeightSchedule) -> Result<()> {
+        instructions::configure_lbp(ctx, weights)
+    }
+
+    // Pool authority turns an empty pool into a concentrated liquidity pool
+    pub fn initialize_concentrated(
+        ctx: Context<InitializeConcentrated>,
+        token_a: Pubkey,
+        token_b: Pubkey,
+        tick_spacing: u16,
+        sqrt_price_x64: u128,
+    ) -> Result<()> {
+        instructions::initialize_concentrated(ctx, token_a, token_b, tick_spacing, sqrt_price_x64)
+    }
+
+    // Concentrated liquidity positions between two ticks
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        tick_lower: i32,
+        tick_upper: i32,
+    ) -> Result<()> {
+        instructions::open_position(ctx, tick_lower, tick_upper)
+    }
+
+    // Hands control of a position to whoever holds its 1-of-1 token
+    pub fn mint_position_nft(ctx: Context<MintPositionNft>) -> Result<()> {
+        instructions::mint_position_nft(ctx)
+    }
+
+    pub fn increase_liquidity(
+        ctx: Context<IncreaseLiquidity>,
+        liquidity: u128,
+        max_amount_a: u64,
+        max_amount_b: u64,
+    ) -> Result<()> {
+        instructions::increase_liquidity(ctx, liquidity, max_amount_a, max_amount_b)
+    }
+
+    pub fn decrease_liquidity(
+        ctx: Context<DecreaseLiquidity>,
+        liquidity: u128,
+        min_amount_a: u64,
+        min_amount_b: u64,
+    ) -> Result<()> {
+        instructions::decrease_liquidity(ctx, liquidity, min_amount_a, min_amount_b)
+    }
+
+    pub fn collect_fees(ctx: Context<DecreaseLiquidity>) -> Result<()> {
+        instructions::collect_fees(ctx)
+    }
+
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        instructions::close_position(ctx)
+    }
+
+    // Limit orders as single-sided liquidity one tick spacing wide, claimable once
+    // the price has moved through them
+    pub fn place_range_order(
+        ctx: Context<PlaceRangeOrder>,
+        tick_lower: i32,
+        tick_upper: i32,
+        sell_a: bool,
+        amount: u64,
+    ) -> Result<()> {
+        instructions::place_range_order(ctx, tick_lower, tick_upper, sell_a, amount)
+    }
+
+    pub fn claim_range_order(ctx: Context<ClaimRangeOrder>) -> Result<()> {
+        instructions::claim_range_order(ctx)
+    }
+
+    // Pool keypair lets the pool's program signer pay out of a vault
+    pub fn delegate_pool_vault(ctx: Context<DelegatePoolVault>) -> Result<()> {
+        instructions::delegate_pool_vault(ctx)
+    }
+
+    // Limit and stop-loss orders escrowed on chain and executed by any keeper once
+    // the pool price crosses the trigger
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        order_id: u64,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+        input_amount: u64,
+        min_output_amount: u64,
+        kind: OrderKind,
+        expiry: i64,
+        keeper_tip: u64,
+    ) -> Result<()> {
+        instructions::place_order(
+            ctx,
+            order_id,
+            input_token_account,
+            output_token_account,
+            input_amount,
+            min_output_amount,
+            kind,
+            expiry,
+            keeper_tip,
+        )
+    }
+
+    pub fn execute_order(ctx: Context<ExecuteOrder>) -> Result<()> {
+        instructions::execute_order(ctx)
+    }
+
+    pub fn cancel_order(ctx: Context<CancelOrder>) -> Result<()> {
+        instructions::cancel_order(ctx)
+    }
+
+    // Dollar cost averaging: a lump sum swapped by keepers one interval at a time,
+    // through a pool or a Jupiter route
+    pub fn create_dca(
+        ctx: Context<CreateDca>,
+        schedule_id: u64,
+        route: DcaRoute,
+        amount_per_interval: u64,
+        interval_seconds: i64,
+        min_output_per_interval: u64,
+        amount: u64,
+    ) -> Result<()> {
+        instructions::create_dca(
+            ctx,
+            schedule_id,
+            route,
+            amount_per_interval,
+            interval_seconds,
+            min_output_per_interval,
+            amount,
+        )
+    }
+
+    pub fn execute_dca(ctx: Context<ExecuteDca>) -> Result<()> {
+        instructions::execute_dca(ctx)
+    }
+
+    pub fn execute_dca_via_jupiter(
+        ctx: Context<ExecuteDcaViaJupiter>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        instructions::execute_dca_via_jupiter(ctx, data)
+    }
+
+    pub fn withdraw_dca(ctx: Context<WithdrawDca>) -> Result<()> {
+        instructions::withdraw_dca(ctx)
+    }
+
+    // Long-term orders sold into a constant product pool at a constant rate, with
+    // opposing flows matched at the spot price
+    pub fn place_long_term_order(
+        ctx: Context<PlaceLongTermOrder>,
+        order_id: u64,
+        sell_a: bool,
+        amount: u64,
+        slots: u64,
+    ) -> Result<()> {
+        instructions::place_long_term_order(ctx, order_id, sell_a, amount, slots)
+    }
+
+    pub fn claim_long_term_order(ctx: Context<ClaimLongTermOrder>) -> Result<()> {
+        instructions::claim_long_term_order(ctx)
+    }
+
+    pub fn cancel_long_term_order(ctx: Context<CancelLongTermOrder>) -> Result<()> {
+        instructions::cancel_long_term_order(ctx)
+    }
+
+    // Fills a market maker quote verified by an ed25519 instruction right before it
+    pub fn fill_rfq(ctx: Context<FillRfq>, quote: RfqQuote) -> Result<()> {
+        instructions::fill_rfq(ctx, quote)
+    }
+
+    // Liquidity mining: staked pool shares earn per-second emissions of up to four
+    // reward tokens, funded by the farm authority
+    pub fn initialize_farm(ctx: Context<InitializeFarm>) -> Result<()> {
+        instructions::initialize_farm(ctx)
+    }
+
+    pub fn add_farm_reward(
+        ctx: Context<AddFarmReward>,
+        emission_per_second: u64,
+        end_time: i64,
+    ) -> Result<()> {
+        instructions::add_farm_reward(ctx, emission_per_second, end_time)
+    }
+
+    pub fn fund_farm_reward(
+        ctx: Context<FundFarmReward>,
+        amount: u64,
+        emission_per_second: u64,
+        end_time: i64,
+    ) -> Result<()> {
+        instructions::fund_farm_reward(ctx, amount, emission_per_second, end_time)
+    }
 
-#[derive(Accounts)]
-pub struct Repay<'info> {
-    pub borrower: Signer<'info>,
-    #[account(mut, seeds = [AUTHORITY_SEED], bump)]
-    pub program_authority: SystemAccount<'info>,
-    /// CHECK: check instructions account
-    #[account(address = sysvar::instructions::ID @FlashFillError::AddressMismatch)]
-    pub instructions: UncheckedAccount<'info>,
-    pub system_program: Program<'info, System>,
+    pub fn stake_lp(ctx: Context<UpdateStake>, amount: u64) -> Result<()> {
+        instructions::stake_lp(ctx, amount)
+    }
+
+    pub fn unstake_lp(ctx: Context<UpdateStake>, amount: u64) -> Result<()> {
+        instructions::unstake_lp(ctx, amount)
+    }
+
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        instructions::claim_rewards(ctx)
+    }
+
+    // Locks free pool shares until an unlock time for a boost on farm rewards.
+    // Unlocking early burns part of them in favor of the other liquidity providers.
+    pub fn lock_liquidity(ctx: Context<LockLiquidity>, amount: u64, duration: i64) -> Result<()> {
+        instructions::lock_liquidity(ctx, amount, duration)
+    }
+
+    pub fn unlock_liquidity(ctx: Context<LockLiquidity>) -> Result<()> {
+        instructions::unlock_liquidity(ctx)
+    }
+
+    // Pool authority rejects all swaps until it resets the circuit breaker
+    pub fn halt_pool(ctx: Context<ManagePool>) -> Result<()> {
+        instructions::halt_pool(ctx)
+    }
+
+    // Pool authority re-enables swaps after halting the pool
+    pub fn reset_circuit_breaker(ctx: Context<ManagePool>) -> Result<()> {
+        instructions::reset_circuit_breaker(ctx)
+    }
+
+    // Pool authority picks the pair long-term orders trade, default keys disable them
+    pub fn configure_twamm(
+        ctx: Context<ManagePool>,
+        token_a: Pubkey,
+        token_b: Pubkey,
+    ) -> Result<()> {
+        instructions::configure_twamm(ctx, token_a, token_b)
+    }
+
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        flash_loan_fee_bps: u16,
+    ) -> Result<()> {
+        instructions::initialize_config(ctx, flash_loan_fee_bps)
+    }
+
+    pub fn set_flash_loan_fee(ctx: Context<UpdateConfig>, flash_loan_fee_bps: u16) -> Result<()> {
+        instructions::set_flash_loan_fee(ctx, flash_loan_fee_bps)
+    }
+
+    pub fn set_platform_fee(ctx: Context<UpdateConfig>, platform_fee_bps: u16) -> Result<()> {
+        instructions::set_platform_fee(ctx, platform_fee_bps)
+    }
+
+    pub fn set_treasury_reserve_floor(
+        ctx: Context<UpdateConfig>,
+        treasury_reserve_floor: u64,
+    ) -> Result<()> {
+        instructions::set_treasury_reserve_floor(ctx, treasury_reserve_floor)
+    }
+
+    // Treasury held by the program authority PDA
+    pub fn initialize_treasury_token(ctx: Context<InitializeTreasuryToken>) -> Result<()> {
+        instructions::initialize_treasury_token(ctx)
+    }
+
+    pub fn fund_treasury(ctx: Context<ManageTreasury>, amount: u64) -> Result<()> {
+        instructions::fund_treasury(ctx, amount)
+    }
+
+    pub fn withdraw_treasury(ctx: Context<ManageTreasury>, amount: u64) -> Result<()> {
+        instructions::withdraw_treasury(ctx, amount)
+    }
+
+    // Flash loans from the program treasury or pool vaults. The borrow and the repay
+    // each carry the transaction index of their counterpart.
+    pub fn flash_borrow(
+        ctx: Context<FlashBorrow>,
+        mint: Pubkey,
+        amount: u64,
+        repay_index: u16,
+    ) -> Result<()> {
+        instructions::flash_borrow(ctx, mint, amount, repay_index)
+    }
+
+    pub fn flash_repay(
+        ctx: Context<FlashRepay>,
+        mint: Pubkey,
+        amount: u64,
+        borrow_index: u16,
+    ) -> Result<()> {
+        instructions::flash_repay(ctx, mint, amount, borrow_index)
+    }
 }
@@ -0,0 +1,107 @@
+use crate::constants::BPS_DENOMINATOR;
+use crate::errors::SwapError;
+
+// Pyth v2 price account layout. Only the fields the swap guard needs are read.
+pub const MAGIC: u32 = 0xa1b2c3d4;
+pub const ACCOUNT_TYPE_PRICE: u32 = 3;
+pub const STATUS_TRADING: u32 = 1;
+
+const MAGIC_OFFSET: usize = 0;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPONENT_OFFSET: usize = 20;
+const TIMESTAMP_OFFSET: usize = 96;
+const AGGREGATE_PRICE_OFFSET: usize = 208;
+const AGGREGATE_CONF_OFFSET: usize = 216;
+const AGGREGATE_STATUS_OFFSET: usize = 224;
+const AGGREGATE_PUBLISH_SLOT_OFFSET: usize = 232;
+pub const PRICE_ACCOUNT_MIN_LEN: usize = 240;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PriceFeed {
+    pub price: i64,
+    pub conf: u64,
+    pub exponent: i32,
+    pub publish_slot: u64,
+    pub timestamp: i64,
+}
+
+pub fn parse_price_feed(data: &[u8]) -> Result<PriceFeed, SwapError> {
+    if data.len() < PRICE_ACCOUNT_MIN_LEN {
+        return Err(SwapError::InvalidOracle);
+    }
+
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+    if read_u32(MAGIC_OFFSET) != MAGIC || read_u32(ACCOUNT_TYPE_OFFSET) != ACCOUNT_TYPE_PRICE {
+        return Err(SwapError::InvalidOracle);
+    }
+    // Halted or unknown feeds carry no usable price
+    if read_u32(AGGREGATE_STATUS_OFFSET) != STATUS_TRADING {
+        return Err(SwapError::InvalidOracle);
+    }
+
+    let feed = PriceFeed {
+        price: read_u64(AGGREGATE_PRICE_OFFSET) as i64,
+        conf: read_u64(AGGREGATE_CONF_OFFSET),
+        exponent: read_u32(EXPONENT_OFFSET) as i32,
+        publish_slot: read_u64(AGGREGATE_PUBLISH_SLOT_OFFSET),
+        timestamp: read_u64(TIMESTAMP_OFFSET) as i64,
+    };
+    if feed.price <= 0 {
+        return Err(SwapError::InvalidOracle);
+    }
+
+    Ok(feed)
+}
+
+// Oracle price as quote per base in raw token units, returned as a fraction with
+// the decimals of both tokens folded into the oracle exponent
+pub fn oracle_price_ratio(
+    feed: &PriceFeed,
+    base_decimals: u8,
+    quote_decimals: u8,
+) -> Result<(u128, u128), SwapError> {
+    let exponent = feed.exponent + quote_decimals as i32 - base_decimals as i32;
+    let scale = 10u128
+        .checked_pow(exponent.unsigned_abs())
+        .ok_or(SwapError::MathError)?;
+    if exponent >= 0 {
+        let numerator = (feed.price as u128)
+            .checked_mul(scale)
+            .ok_or(SwapError::MathError)?;
+        Ok((numerator, 1))
+    } else {
+        Ok((feed.price as u128, scale))
+    }
+}
+
+// Divergence of the pool price (quote reserve per base reserve) from the oracle price
+pub fn calculate_oracle_divergence_bps(
+    feed: &PriceFeed,
+    base_reserve: u64,
+    quote_reserve: u64,
+    base_decimals: u8,
+    quote_decimals: u8,
+) -> Result<u64, SwapError> {
+    let (oracle_numerator, oracle_denominator) =
+        oracle_price_ratio(feed, base_decimals, quote_decimals)?;
+
+    // Compare quote / base against numerator / denominator by cross multiplying
+    let oracle = (base_reserve as u128)
+        .checked_mul(oracle_numerator)
+        .ok_or(SwapError::MathError)?;
+    let pool = (quote_reserve as u128)
+        .checked_mul(oracle_denominator)
+        .ok_or(SwapError::MathError)?;
+    if oracle == 0 {
+        return Err(SwapError::MathError);
+    }
+
+    let divergence_bps = oracle
+        .abs_diff(pool)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(SwapError::MathError)?
+        / oracle;
+    Ok(u64::try_from(divergence_bps).unwrap_or(u64::MAX))
+}
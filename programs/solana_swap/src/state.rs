@@ -1,26 +1,1175 @@
 use anchor_lang::prelude::*;
 
+use crate::constants::{BPS_DENOMINATOR, EARLY_UNLOCK_PENALTY_BPS};
+use crate::curve::{calculate_twamm_trade, mul_div, Q64};
+use crate::errors::SwapError;
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
 pub struct TokenAmount {
-    pub token_account: Pubkey, // The address of the token account
-    pub amount: u64,           // The amount of the token
+    pub token_account: Pubkey,  // The address of the token account
+    pub vault: Pubkey,          // The pool token account holding the reserve
+    pub amount: u64,            // The amount of the token
+    pub reference_amount: u64,  // The amount at the last circuit breaker checkpoint
+    pub slot_start_amount: u64, // The amount at the start of the price band slot
+    pub target_amount: u64,     // Liquidity provided, the PMM equilibrium balance
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CurveType {
+    #[default]
+    ConstantProduct,
+    Pmm {
+        k_bps: u16, // Slippage coefficient, 0 trades flat at the oracle price
+    },
+    Concentrated {
+        tick_spacing: u16, // Position ticks are multiples of the spacing
+    },
+    Weighted, // Two token weighted curve following the pool's weight schedule
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct CircuitBreaker {
+    pub max_price_move_bps: u16, // Largest move from the reference price, 0 disables
+    pub window_slots: u64,       // Slots before the reference price is refreshed
+    pub max_volume_per_slot: u64, // Largest input volume per slot, 0 disables
+    pub reference_slot: u64,     // Slot of the last reference checkpoint
+    pub volume_slot: u64,        // Slot the volume below was recorded in
+    pub slot_volume: u64,        // Input volume swapped during volume_slot
+    pub halted: bool,            // Swaps are rejected until the authority resets it
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PriceBand {
+    pub band_bps: u16, // Largest deviation from the start of slot price, 0 disables
+    pub surcharge_bps: u16, // Output kept for LPs outside the band, 0 rejects instead
+    pub slot: u64,     // Slot the start amounts were recorded in
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct DynamicFee {
+    pub base_fee_bps: u16,           // Fee charged when the market is calm
+    pub max_fee_bps: u16,            // Upper bound of the fee, 0 keeps the flat fee
+    pub variable_fee_control: u16,   // Fee bps added per 10000 bps of accumulated volatility
+    pub decay_slots: u64,            // Slots for the accumulator to decay to zero
+    pub volatility_accumulator: u64, // Sum of recent price moves in bps
+    pub last_update_slot: u64,       // Slot the accumulator was last decayed in
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct OracleGuard {
+    pub price_feed: Pubkey, // Pyth style price account, default disables the guard
+    pub base_token_account: Pubkey, // Pool token entry the feed prices
+    pub quote_token_account: Pubkey, // Pool token entry the feed quotes in
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    pub tolerance_bps: u16, // Largest post-trade divergence from the feed
+    pub max_staleness_slots: u64, // 0 skips the slot check
+    pub max_staleness_seconds: i64, // 0 skips the timestamp check
+}
+
+impl OracleGuard {
+    // Whether the input is the base token, or None when the feed does not price the pair
+    pub fn base_is_input(
+        &self,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+    ) -> Option<bool> {
+        if input_token_account == self.base_token_account
+            && output_token_account == self.quote_token_account
+        {
+            Some(true)
+        } else if input_token_account == self.quote_token_account
+            && output_token_account == self.base_token_account
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ConcentratedLiquidity {
+    pub token_a: Pubkey, // Pool token entry the price is given for
+    pub token_b: Pubkey, // Pool token entry the price is quoted in
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub sqrt_price_x64: u128,          // sqrt(token B per token A) in Q64.64
+    pub tick_current: i32,             // Largest tick at or below the current price
+    pub liquidity: u128,               // Liquidity of the positions in range
+    pub fee_growth_global_a_x64: u128, // Fees earned per unit of liquidity in Q64.64
+    pub fee_growth_global_b_x64: u128,
+}
+
+impl ConcentratedLiquidity {
+    // Whether the input is token A, or None when the pair is not the pool's
+    pub fn a_is_input(
+        &self,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+    ) -> Option<bool> {
+        if input_token_account == self.token_a && output_token_account == self.token_b {
+            Some(true)
+        } else if input_token_account == self.token_b && output_token_account == self.token_a {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TwammExpiry {
+    pub slot: u64,
+    pub sell_rate_a: u64, // Sell rate of the orders ending at the slot
+    pub sell_rate_b: u64,
+    pub earnings_per_rate_a_x64: u128, // Accumulators at the slot, once it is settled
+    pub earnings_per_rate_b_x64: u128,
+    pub orders: u16, // Orders ending at the slot and not claimed yet
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct Twamm {
+    pub token_a: Pubkey, // Pool token entries of the pair, default disables long-term orders
+    pub token_b: Pubkey,
+    pub sell_rate_a: u64, // Token A sold into the pool per slot
+    pub sell_rate_b: u64,
+    pub earnings_per_rate_a_x64: u128, // Token B bought per unit of token A sell rate, in Q64.64
+    pub earnings_per_rate_b_x64: u128,
+    pub last_settled_slot: u64, // Virtual trades are executed up to this slot
+    pub expiries: Vec<TwammExpiry>,
+}
+
+impl Twamm {
+    pub const MAX_EXPIRIES: usize = 8; // Distinct end slots of the open orders
+
+    // Whether the order sells token A, or None when the pair is not the TWAMM's
+    pub fn sells_a(
+        &self,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+    ) -> Option<bool> {
+        if input_token_account == self.token_a && output_token_account == self.token_b {
+            Some(true)
+        } else if input_token_account == self.token_b && output_token_account == self.token_a {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    // Pool token entries an order sells and buys
+    pub fn pair(&self, sell_a: bool) -> (Pubkey, Pubkey) {
+        if sell_a {
+            (self.token_a, self.token_b)
+        } else {
+            (self.token_b, self.token_a)
+        }
+    }
+}
+
+// Weights of a weighted pool pair, moving linearly from the start to the end weights
+// between the start and end times. A liquidity bootstrapping pool starts heavy on
+// the token it sells, so its price descends unless it is bought.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct WeightSchedule {
+    pub token_a: Pubkey, // Pool token entry the weights are given for
+    pub token_b: Pubkey, // Weighted with the rest up to 100%
+    pub start_weight_a_bps: u16,
+    pub end_weight_a_bps: u16,
+    pub start_time: i64,
+    pub end_time: i64, // Only the pool authority provides liquidity until then
+}
+
+impl WeightSchedule {
+    // Weight of token A at the timestamp
+    pub fn weight_a_bps(&self, now: i64) -> u16 {
+        if now <= self.start_time {
+            return self.start_weight_a_bps;
+        }
+        if now >= self.end_time {
+            return self.end_weight_a_bps;
+        }
+        let (start, end) = (
+            self.start_weight_a_bps as i128,
+            self.end_weight_a_bps as i128,
+        );
+        let elapsed = (now - self.start_time) as i128;
+        let duration = (self.end_time - self.start_time) as i128;
+        (start + (end - start) * elapsed / duration) as u16
+    }
+
+    // Weights of the input and output token, or None when the pair is not the pool's
+    pub fn weights_bps(
+        &self,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+        now: i64,
+    ) -> Option<(u16, u16)> {
+        let weight_a = self.weight_a_bps(now);
+        let weight_b = BPS_DENOMINATOR as u16 - weight_a;
+        if input_token_account == self.token_a && output_token_account == self.token_b {
+            Some((weight_a, weight_b))
+        } else if input_token_account == self.token_b && output_token_account == self.token_a {
+            Some((weight_b, weight_a))
+        } else {
+            None
+        }
+    }
 }
 
 #[account]
 pub struct Pool {
     pub authority: Pubkey,
     pub tokens: Vec<TokenAmount>,
+    pub circuit_breaker: CircuitBreaker,
+    pub price_band: PriceBand,
+    pub dynamic_fee: DynamicFee,
+    pub oracle: OracleGuard,
+    pub curve: CurveType,
+    pub concentrated: ConcentratedLiquidity,
+    pub twamm: Twamm,
+    pub total_shares: u64, // Shares held by liquidity providers, see LiquidityShares
+    pub locked_shares: u64, // Part of the shares locked until an unlock time
+    pub weights: WeightSchedule,
 }
 
 impl Pool {
     pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
     pub const AUTHORITY_LEN: usize = 32; // Length of the authority
-    pub const TOKEN_AMOUNT_SIZE: usize = 40; // Size of the TokenAmount struct (32 for Pubkey + 8 for u64)
+    pub const VEC_PREFIX_LEN: usize = 4; // Length prefix of the tokens vector
+    pub const TOKEN_AMOUNT_SIZE: usize = 96; // Size of the TokenAmount struct (2 * 32 for Pubkey + 4 * 8 for u64)
+    pub const CIRCUIT_BREAKER_SIZE: usize = 43; // Size of the CircuitBreaker struct (2 + 5 * 8 + 1)
+    pub const PRICE_BAND_SIZE: usize = 12; // Size of the PriceBand struct (2 * 2 + 8)
+    pub const DYNAMIC_FEE_SIZE: usize = 30; // Size of the DynamicFee struct (3 * 2 + 3 * 8)
+    pub const ORACLE_GUARD_SIZE: usize = 116; // Size of the OracleGuard struct (3 * 32 + 2 + 2 + 2 * 8)
+    pub const CURVE_TYPE_SIZE: usize = 3; // Size of the CurveType enum (1 for the variant + 2 for k or the tick spacing)
+    pub const CONCENTRATED_SIZE: usize = 196; // Size of the ConcentratedLiquidity struct (4 * 32 + 4 * 16 + 4)
+    pub const TWAMM_SIZE: usize = 588; // Size of the Twamm struct (2 * 32 + 2 * 8 + 2 * 16 + 8 + 4 + 8 * 58 for the expiries)
+    pub const SHARES_LEN: usize = 8; // Size of the total and locked shares (u64)
+    pub const WEIGHT_SCHEDULE_SIZE: usize = 84; // Size of the WeightSchedule struct (2 * 32 + 2 * 2 + 2 * 8)
 
     pub const MAX_TOKENS: usize = 10; // Example maximum number of tokens
 
     // Calculate the length of the Pool account
     pub const LEN: usize = Self::DISCRIMINATOR_LEN
         + Self::AUTHORITY_LEN
-        + (Self::TOKEN_AMOUNT_SIZE * Self::MAX_TOKENS);
+        + Self::VEC_PREFIX_LEN
+        + (Self::TOKEN_AMOUNT_SIZE * Self::MAX_TOKENS)
+        + Self::CIRCUIT_BREAKER_SIZE
+        + Self::PRICE_BAND_SIZE
+        + Self::DYNAMIC_FEE_SIZE
+        + Self::ORACLE_GUARD_SIZE
+        + Self::CURVE_TYPE_SIZE
+        + Self::CONCENTRATED_SIZE
+        + Self::TWAMM_SIZE
+        + (Self::SHARES_LEN * 2)
+        + Self::WEIGHT_SCHEDULE_SIZE;
+
+    // Liquidity of a weighted pool is the pool authority's alone until the end of
+    // its weight schedule
+    pub fn can_provide_liquidity(&self, user: Pubkey, now: i64) -> bool {
+        self.curve != CurveType::Weighted || now >= self.weights.end_time || user == self.authority
+    }
+
+    // Amounts of every pool token a number of shares is worth, in pool token order.
+    // Shares are valued against all reserves at once, so a deposit and withdrawal
+    // always move every token in proportion.
+    pub fn amounts_for_shares(&self, shares: u64, round_up: bool) -> Result<Vec<u64>> {
+        self.tokens
+            .iter()
+            .map(|token| {
+                let amount = mul_div(
+                    token.amount as u128,
+                    shares as u128,
+                    self.total_shares as u128,
+                    round_up,
+                )?;
+                Ok(u64::try_from(amount).map_err(|_| SwapError::MathError)?)
+            })
+            .collect()
+    }
+
+    // Pool token account holding the reserve of a pool token, the default key for
+    // tokens not in the pool
+    pub fn vault(&self, token_account: Pubkey) -> Pubkey {
+        self.tokens
+            .iter()
+            .find(|token| token.token_account == token_account)
+            .map(|token| token.vault)
+            .unwrap_or_default()
+    }
+
+    // Starts a new reference window once the previous one has elapsed, and a new
+    // volume count on every new slot
+    pub fn refresh_circuit_breaker(&mut self, slot: u64) {
+        let breaker = &mut self.circuit_breaker;
+        if slot >= breaker.reference_slot.saturating_add(breaker.window_slots) {
+            breaker.reference_slot = slot;
+            for token in self.tokens.iter_mut() {
+                token.reference_amount = token.amount;
+            }
+        }
+        if slot != breaker.volume_slot {
+            breaker.volume_slot = slot;
+            breaker.slot_volume = 0;
+        }
+    }
+
+    // Records the reserves the first time the pool is touched in a slot
+    pub fn refresh_price_band(&mut self, slot: u64) {
+        if slot != self.price_band.slot {
+            self.price_band.slot = slot;
+            for token in self.tokens.iter_mut() {
+                token.slot_start_amount = token.amount;
+            }
+        }
+    }
+
+    // Decays the volatility accumulator linearly over decay_slots and returns the
+    // fee for the current slot, or None while dynamic fees are disabled
+    pub fn dynamic_fee_bps(&mut self, slot: u64) -> Option<u16> {
+        let fee = &mut self.dynamic_fee;
+        if fee.max_fee_bps == 0 {
+            return None;
+        }
+
+        let elapsed = slot.saturating_sub(fee.last_update_slot);
+        fee.volatility_accumulator = if elapsed >= fee.decay_slots {
+            0
+        } else {
+            let remaining = (fee.volatility_accumulator as u128)
+                * (fee.decay_slots - elapsed) as u128
+                / fee.decay_slots as u128;
+            remaining as u64
+        };
+        fee.last_update_slot = slot;
+
+        let variable_fee = (fee.volatility_accumulator as u128) * fee.variable_fee_control as u128
+            / BPS_DENOMINATOR as u128;
+        let fee_bps = (fee.base_fee_bps as u128 + variable_fee).min(fee.max_fee_bps as u128);
+        Some(fee_bps as u16)
+    }
+
+    pub fn record_volatility(&mut self, price_move_bps: u64) {
+        let fee = &mut self.dynamic_fee;
+        fee.volatility_accumulator = fee.volatility_accumulator.saturating_add(price_move_bps);
+    }
+
+    pub fn reset_circuit_breaker(&mut self, slot: u64) {
+        let breaker = &mut self.circuit_breaker;
+        breaker.halted = false;
+        breaker.reference_slot = slot;
+        breaker.volume_slot = slot;
+        breaker.slot_volume = 0;
+        for token in self.tokens.iter_mut() {
+            token.reference_amount = token.amount;
+        }
+    }
+
+    // Executes the virtual trades of the long-term orders up to the slot, stopping at
+    // every end slot on the way to record the accumulators its orders are paid from
+    pub fn settle_twamm(&mut self, slot: u64) -> Result<()> {
+        if self.twamm.token_a == Pubkey::default() {
+            return Ok(());
+        }
+
+        while self.twamm.last_settled_slot < slot {
+            let last_settled_slot = self.twamm.last_settled_slot;
+            let next_expiry = self
+                .twamm
+                .expiries
+                .iter()
+                .map(|expiry| expiry.slot)
+                .filter(|&expiry_slot| expiry_slot > last_settled_slot)
+                .min();
+            let settle_until = next_expiry.map_or(slot, |expiry_slot| expiry_slot.min(slot));
+            self.execute_twamm(settle_until - last_settled_slot)?;
+
+            let twamm = &mut self.twamm;
+            twamm.last_settled_slot = settle_until;
+            if let Some(expiry) = twamm
+                .expiries
+                .iter_mut()
+                .find(|expiry| expiry.slot == settle_until)
+            {
+                // The orders ending here stop selling
+                expiry.earnings_per_rate_a_x64 = twamm.earnings_per_rate_a_x64;
+                expiry.earnings_per_rate_b_x64 = twamm.earnings_per_rate_b_x64;
+                twamm.sell_rate_a = twamm
+                    .sell_rate_a
+                    .checked_sub(expiry.sell_rate_a)
+                    .ok_or(SwapError::MathError)?;
+                twamm.sell_rate_b = twamm
+                    .sell_rate_b
+                    .checked_sub(expiry.sell_rate_b)
+                    .ok_or(SwapError::MathError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sells the long-term order flow of a number of slots into the pool
+    fn execute_twamm(&mut self, slots: u64) -> Result<()> {
+        let twamm = &self.twamm;
+        let sold_a = twamm
+            .sell_rate_a
+            .checked_mul(slots)
+            .ok_or(SwapError::MathError)?;
+        let sold_b = twamm
+            .sell_rate_b
+            .checked_mul(slots)
+            .ok_or(SwapError::MathError)?;
+        if sold_a == 0 && sold_b == 0 {
+            return Ok(());
+        }
+
+        let (token_a, token_b) = (twamm.token_a, twamm.token_b);
+        let reserve = |key: Pubkey| {
+            self.tokens
+                .iter()
+                .position(|token| token.token_account == key)
+                .ok_or(SwapError::TokenNotFound)
+        };
+        let (index_a, index_b) = (reserve(token_a)?, reserve(token_b)?);
+        let (bought_b, bought_a) = calculate_twamm_trade(
+            self.tokens[index_a].amount,
+            self.tokens[index_b].amount,
+            sold_a,
+            sold_b,
+        )?;
+
+        // The sold tokens join the reserves, and the bought ones stay in the vaults
+        // for the orders to claim
+        let apply = |amount: u64, sold: u64, bought: u64| {
+            amount
+                .checked_add(sold)
+                .and_then(|amount| amount.checked_sub(bought))
+                .ok_or(SwapError::MathError)
+        };
+        self.tokens[index_a].amount = apply(self.tokens[index_a].amount, sold_a, bought_a)?;
+        self.tokens[index_b].amount = apply(self.tokens[index_b].amount, sold_b, bought_b)?;
+
+        let twamm = &mut self.twamm;
+        if twamm.sell_rate_a > 0 {
+            let earnings = mul_div(bought_b as u128, Q64, twamm.sell_rate_a as u128, false)?;
+            twamm.earnings_per_rate_a_x64 = twamm.earnings_per_rate_a_x64.wrapping_add(earnings);
+        }
+        if twamm.sell_rate_b > 0 {
+            let earnings = mul_div(bought_a as u128, Q64, twamm.sell_rate_b as u128, false)?;
+            twamm.earnings_per_rate_b_x64 = twamm.earnings_per_rate_b_x64.wrapping_add(earnings);
+        }
+
+        Ok(())
+    }
+
+    // Spot price of the input token in output tokens at the timestamp, in Q64.64
+    pub fn spot_price_x64(
+        &self,
+        input_token_account: Pubkey,
+        output_token_account: Pubkey,
+        now: i64,
+    ) -> Result<u128> {
+        let amount = |key: Pubkey| {
+            self.tokens
+                .iter()
+                .find(|token| token.token_account == key)
+                .map(|token| token.amount as u128)
+                .ok_or(SwapError::TokenNotFound)
+        };
+        match self.curve {
+            CurveType::ConstantProduct => {
+                let input_reserve = amount(input_token_account)?;
+                let output_reserve = amount(output_token_account)?;
+                if input_reserve == 0 {
+                    return err!(SwapError::InsufficientLiquidity);
+                }
+                Ok(mul_div(output_reserve, Q64, input_reserve, false)?)
+            }
+            CurveType::Weighted => {
+                let (input_weight_bps, output_weight_bps) = self
+                    .weights
+                    .weights_bps(input_token_account, output_token_account, now)
+                    .ok_or(SwapError::TokenNotFound)?;
+                let input_reserve = amount(input_token_account)?;
+                let output_reserve = amount(output_token_account)?;
+                if input_reserve == 0 {
+                    return err!(SwapError::InsufficientLiquidity);
+                }
+                Ok(mul_div(
+                    output_reserve * input_weight_bps as u128,
+                    Q64,
+                    input_reserve * output_weight_bps as u128,
+                    false,
+                )?)
+            }
+            CurveType::Concentrated { .. } => {
+                let state = &self.concentrated;
+                let a_is_input = state
+                    .a_is_input(input_token_account, output_token_account)
+                    .ok_or(SwapError::TokenNotFound)?;
+                let price_x64 = mul_div(state.sqrt_price_x64, state.sqrt_price_x64, Q64, false)?;
+                if a_is_input {
+                    Ok(price_x64)
+                } else {
+                    Ok(mul_div(Q64, Q64, price_x64, false)?)
+                }
+            }
+            // PMM pools trade at the oracle price, not at a price of their own
+            CurveType::Pmm { .. } => err!(SwapError::UnsupportedCurve),
+        }
+    }
+}
+
+#[account]
+pub struct ProgramConfig {
+    pub authority: Pubkey,
+    pub flash_loan_fee_bps: u16,
+    pub platform_fee_bps: u16,       // Deducted from Jupiter swap output
+    pub treasury_reserve_floor: u64, // Lamports the treasury always keeps
+    pub outstanding_borrowed_lamports: u64, // SOL flash loans not yet repaid
+    pub bump: u8,
+}
+
+impl ProgramConfig {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const AUTHORITY_LEN: usize = 32; // Length of the authority
+    pub const FEE_BPS_LEN: usize = 2; // Size of a fee in basis points (u16)
+    pub const LAMPORTS_LEN: usize = 8; // Size of a lamport amount (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the ProgramConfig account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + Self::AUTHORITY_LEN
+        + (Self::FEE_BPS_LEN * 2)
+        + (Self::LAMPORTS_LEN * 2)
+        + Self::BUMP_LEN;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct Tick {
+    pub index: i32,
+    pub liquidity_gross: u128, // Liquidity of the positions bounded by the tick
+    pub liquidity_net: i128,   // Liquidity added when the price crosses it upwards
+    pub fee_growth_outside_a_x64: u128, // Fee growth on the other side of the tick
+    pub fee_growth_outside_b_x64: u128,
+}
+
+impl Tick {
+    // Fee growth outside flips to the other side as the price crosses the tick
+    pub fn cross(&mut self, fee_growth_global_a_x64: u128, fee_growth_global_b_x64: u128) {
+        self.fee_growth_outside_a_x64 =
+            fee_growth_global_a_x64.wrapping_sub(self.fee_growth_outside_a_x64);
+        self.fee_growth_outside_b_x64 =
+            fee_growth_global_b_x64.wrapping_sub(self.fee_growth_outside_b_x64);
+    }
+}
+
+#[account]
+pub struct TickArray {
+    pub pool: Pubkey,
+    pub ticks: Vec<Tick>, // Initialized ticks sorted by index
+    pub bump: u8,
+}
+
+impl TickArray {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const POOL_LEN: usize = 32; // Length of the pool key
+    pub const VEC_PREFIX_LEN: usize = 4; // Length prefix of the ticks vector
+    pub const TICK_SIZE: usize = 68; // Size of the Tick struct (4 + 4 * 16)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    pub const MAX_TICKS: usize = 64; // Distinct position bounds per pool
+
+    // Calculate the length of the TickArray account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + Self::POOL_LEN
+        + Self::VEC_PREFIX_LEN
+        + (Self::TICK_SIZE * Self::MAX_TICKS)
+        + Self::BUMP_LEN;
+
+    // Adds a position's liquidity to one of its bounds, initializing the tick the
+    // first time it is used. Fees before that count as grown below the price.
+    pub fn update(
+        &mut self,
+        index: i32,
+        upper: bool,
+        liquidity_delta: i128,
+        state: &ConcentratedLiquidity,
+    ) -> Result<()> {
+        let position = match self.ticks.binary_search_by_key(&index, |tick| tick.index) {
+            Ok(position) => position,
+            Err(position) => {
+                if self.ticks.len() >= Self::MAX_TICKS {
+                    return err!(SwapError::TickArrayFull);
+                }
+                let (fee_growth_outside_a_x64, fee_growth_outside_b_x64) =
+                    if index <= state.tick_current {
+                        (state.fee_growth_global_a_x64, state.fee_growth_global_b_x64)
+                    } else {
+                        (0, 0)
+                    };
+                self.ticks.insert(
+                    position,
+                    Tick {
+                        index,
+                        fee_growth_outside_a_x64,
+                        fee_growth_outside_b_x64,
+                        ..Default::default()
+                    },
+                );
+                position
+            }
+        };
+
+        let tick = &mut self.ticks[position];
+        tick.liquidity_gross = tick
+            .liquidity_gross
+            .checked_add_signed(liquidity_delta)
+            .ok_or(SwapError::MathError)?;
+        let net_delta = if upper {
+            liquidity_delta.checked_neg().ok_or(SwapError::MathError)?
+        } else {
+            liquidity_delta
+        };
+        tick.liquidity_net = tick
+            .liquidity_net
+            .checked_add(net_delta)
+            .ok_or(SwapError::MathError)?;
+
+        Ok(())
+    }
+
+    // Fee growth per unit of liquidity between two initialized ticks
+    pub fn fee_growth_inside(
+        &self,
+        tick_lower: i32,
+        tick_upper: i32,
+        state: &ConcentratedLiquidity,
+    ) -> Result<(u128, u128)> {
+        let find = |index: i32| {
+            self.ticks
+                .iter()
+                .find(|tick| tick.index == index)
+                .ok_or(SwapError::InvalidTick)
+        };
+        let (lower, upper) = (find(tick_lower)?, find(tick_upper)?);
+        let (global_a, global_b) = (state.fee_growth_global_a_x64, state.fee_growth_global_b_x64);
+
+        let (below_a, below_b) = if state.tick_current >= tick_lower {
+            (
+                lower.fee_growth_outside_a_x64,
+                lower.fee_growth_outside_b_x64,
+            )
+        } else {
+            (
+                global_a.wrapping_sub(lower.fee_growth_outside_a_x64),
+                global_b.wrapping_sub(lower.fee_growth_outside_b_x64),
+            )
+        };
+        let (above_a, above_b) = if state.tick_current < tick_upper {
+            (
+                upper.fee_growth_outside_a_x64,
+                upper.fee_growth_outside_b_x64,
+            )
+        } else {
+            (
+                global_a.wrapping_sub(upper.fee_growth_outside_a_x64),
+                global_b.wrapping_sub(upper.fee_growth_outside_b_x64),
+            )
+        };
+
+        Ok((
+            global_a.wrapping_sub(below_a).wrapping_sub(above_a),
+            global_b.wrapping_sub(below_b).wrapping_sub(above_b),
+        ))
+    }
+
+    // Drops ticks no position is bounded by anymore
+    pub fn clear_unused(&mut self) {
+        self.ticks.retain(|tick| tick.liquidity_gross > 0);
+    }
+}
+
+#[account]
+pub struct Position {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub nft_mint: Pubkey, // Holder of this 1-of-1 mint controls the position, default when none
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    pub fee_growth_inside_a_last_x64: u128, // Fee growth inside the range at the last update
+    pub fee_growth_inside_b_last_x64: u128,
+    pub tokens_owed_a: u64, // Fees earned and not collected yet
+    pub tokens_owed_b: u64,
+    pub bump: u8,
+}
+
+impl Position {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const TICK_LEN: usize = 4; // Size of a tick index (i32)
+    pub const X64_LEN: usize = 16; // Size of a Q64.64 value or liquidity (u128)
+    pub const AMOUNT_LEN: usize = 8; // Size of a token amount (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the Position account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 3)
+        + (Self::TICK_LEN * 2)
+        + (Self::X64_LEN * 3)
+        + (Self::AMOUNT_LEN * 2)
+        + Self::BUMP_LEN;
+
+    // Credits the fees earned since the last update, then applies the liquidity change
+    pub fn update(
+        &mut self,
+        liquidity_delta: i128,
+        fee_growth_inside_a_x64: u128,
+        fee_growth_inside_b_x64: u128,
+    ) -> Result<()> {
+        let earned = |inside: u128, last: u128| {
+            let amount = mul_div(self.liquidity, inside.wrapping_sub(last), Q64, false)?;
+            u64::try_from(amount).map_err(|_| SwapError::MathError)
+        };
+        let earned_a = earned(fee_growth_inside_a_x64, self.fee_growth_inside_a_last_x64)?;
+        let earned_b = earned(fee_growth_inside_b_x64, self.fee_growth_inside_b_last_x64)?;
+
+        self.tokens_owed_a = self
+            .tokens_owed_a
+            .checked_add(earned_a)
+            .ok_or(SwapError::MathError)?;
+        self.tokens_owed_b = self
+            .tokens_owed_b
+            .checked_add(earned_b)
+            .ok_or(SwapError::MathError)?;
+        self.liquidity = self
+            .liquidity
+            .checked_add_signed(liquidity_delta)
+            .ok_or(SwapError::MathError)?;
+        self.fee_growth_inside_a_last_x64 = fee_growth_inside_a_x64;
+        self.fee_growth_inside_b_last_x64 = fee_growth_inside_b_x64;
+
+        Ok(())
+    }
+}
+
+#[account]
+pub struct RangeOrder {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey, // Single tick spacing position holding the order
+    pub sell_a: bool,     // Sells token A as the price rises through the band, token B as it falls
+    pub amount_in: u64,   // Amount of the sold token deposited
+    pub bump: u8,
+}
+
+impl RangeOrder {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const SELL_A_LEN: usize = 1; // Size of the direction flag
+    pub const AMOUNT_LEN: usize = 8; // Size of a token amount (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the RangeOrder account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 3)
+        + Self::SELL_A_LEN
+        + Self::AMOUNT_LEN
+        + Self::BUMP_LEN;
+
+    // The whole band is converted once the price has moved past it
+    pub fn is_filled(&self, position: &Position, tick_current: i32) -> bool {
+        if self.sell_a {
+            tick_current >= position.tick_upper
+        } else {
+            tick_current < position.tick_lower
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OrderKind {
+    Limit {
+        trigger_price_x64: u128, // Executes once the pool price is at or above it
+    },
+    StopLoss {
+        trigger_price_x64: u128, // Executes once the pool price is at or below it
+    },
+}
+
+#[account]
+pub struct Order {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub input_token_account: Pubkey,  // Pool token entry sold
+    pub output_token_account: Pubkey, // Pool token entry bought
+    pub user_token_out: Pubkey,       // Receives the output
+    pub input_amount: u64,            // Escrowed in the order vault
+    pub min_output_amount: u64,
+    pub kind: OrderKind, // Prices are input in output tokens, in Q64.64
+    pub expiry: i64,     // Unix timestamp after which the order no longer executes
+    pub keeper_tip: u64, // Lamports paid to whoever executes the order
+    pub order_id: u64,   // Distinguishes the orders of one owner
+    pub bump: u8,
+}
+
+impl Order {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const AMOUNT_LEN: usize = 8; // Size of a token or lamport amount (u64)
+    pub const KIND_LEN: usize = 17; // Size of the OrderKind enum (1 for the variant + 16 for the price)
+    pub const TIMESTAMP_LEN: usize = 8; // Size of a unix timestamp (i64)
+    pub const ORDER_ID_LEN: usize = 8; // Size of the order id (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the Order account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 5)
+        + (Self::AMOUNT_LEN * 3)
+        + Self::KIND_LEN
+        + Self::TIMESTAMP_LEN
+        + Self::ORDER_ID_LEN
+        + Self::BUMP_LEN;
+
+    pub fn is_triggered(&self, price_x64: u128) -> bool {
+        match self.kind {
+            OrderKind::Limit { trigger_price_x64 } => price_x64 >= trigger_price_x64,
+            OrderKind::StopLoss { trigger_price_x64 } => price_x64 <= trigger_price_x64,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DcaRoute {
+    Pool {
+        pool: Pubkey,
+        input_token_account: Pubkey,  // Pool token entry sold
+        output_token_account: Pubkey, // Pool token entry bought
+    },
+    Jupiter, // Keepers supply the route on every execution
+}
+
+#[account]
+pub struct DcaSchedule {
+    pub owner: Pubkey,
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    pub user_token_out: Pubkey, // Accumulates the output
+    pub route: DcaRoute,
+    pub amount_per_interval: u64,
+    pub min_output_per_interval: u64, // Output required of a full interval
+    pub interval_seconds: i64,
+    pub next_execution_at: i64, // Unix timestamp the next interval opens at
+    pub schedule_id: u64,       // Distinguishes the schedules of one owner
+    pub bump: u8,
+}
+
+impl DcaSchedule {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const ROUTE_LEN: usize = 97; // Size of the DcaRoute enum (1 for the variant + 3 * 32 for the pool route)
+    pub const AMOUNT_LEN: usize = 8; // Size of a token amount (u64)
+    pub const TIMESTAMP_LEN: usize = 8; // Size of a duration or unix timestamp (i64)
+    pub const SCHEDULE_ID_LEN: usize = 8; // Size of the schedule id (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the DcaSchedule account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 4)
+        + Self::ROUTE_LEN
+        + (Self::AMOUNT_LEN * 2)
+        + (Self::TIMESTAMP_LEN * 2)
+        + Self::SCHEDULE_ID_LEN
+        + Self::BUMP_LEN;
+
+    // Pool, sold and bought pool token entries of a pool routed schedule, default
+    // keys for a Jupiter route
+    pub fn pool_route(&self) -> (Pubkey, Pubkey, Pubkey) {
+        match self.route {
+            DcaRoute::Pool {
+                pool,
+                input_token_account,
+                output_token_account,
+            } => (pool, input_token_account, output_token_account),
+            DcaRoute::Jupiter => Default::default(),
+        }
+    }
+
+    // Opens the next interval and returns the input to swap in the current one,
+    // the remaining balance once it is less than a full interval
+    pub fn start_interval(&mut self, balance: u64, now: i64) -> Result<u64> {
+        if now < self.next_execution_at {
+            return err!(SwapError::DcaIntervalNotElapsed);
+        }
+        let amount = self.amount_per_interval.min(balance);
+        if amount == 0 {
+            return err!(SwapError::InvalidAmount);
+        }
+        self.next_execution_at = now
+            .checked_add(self.interval_seconds)
+            .ok_or(SwapError::MathError)?;
+        Ok(amount)
+    }
+
+    // Minimum output for the input of an interval, pro rata for a final partial one
+    pub fn min_output(&self, amount: u64) -> Result<u64> {
+        let min_output = mul_div(
+            self.min_output_per_interval as u128,
+            amount as u128,
+            self.amount_per_interval as u128,
+            true,
+        )?;
+        Ok(u64::try_from(min_output).map_err(|_| SwapError::MathError)?)
+    }
+}
+
+#[account]
+pub struct LongTermOrder {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub sell_a: bool,   // Sells token A of the pool's TWAMM pair, token B otherwise
+    pub sell_rate: u64, // Tokens sold per slot
+    pub end_slot: u64,
+    pub earnings_per_rate_last_x64: u128, // Accumulator at the last claim
+    pub order_id: u64,                    // Distinguishes the orders of one owner in the pool
+    pub bump: u8,
+}
+
+impl LongTermOrder {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const SELL_A_LEN: usize = 1; // Size of the direction flag
+    pub const U64_LEN: usize = 8; // Size of the sell rate, end slot and order id (u64)
+    pub const X64_LEN: usize = 16; // Size of a Q64.64 value (u128)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the LongTermOrder account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 2)
+        + Self::SELL_A_LEN
+        + (Self::U64_LEN * 3)
+        + Self::X64_LEN
+        + Self::BUMP_LEN;
+
+    // Tokens bought since the last claim and the accumulator they were paid up to.
+    // An order that has ended is paid up to its end slot.
+    pub fn proceeds(
+        &self,
+        twamm: &Twamm,
+        expiry: &TwammExpiry,
+        ended: bool,
+    ) -> Result<(u64, u128)> {
+        let earnings_per_rate_x64 = match (ended, self.sell_a) {
+            (true, true) => expiry.earnings_per_rate_a_x64,
+            (true, false) => expiry.earnings_per_rate_b_x64,
+            (false, true) => twamm.earnings_per_rate_a_x64,
+            (false, false) => twamm.earnings_per_rate_b_x64,
+        };
+        let amount = mul_div(
+            self.sell_rate as u128,
+            earnings_per_rate_x64.wrapping_sub(self.earnings_per_rate_last_x64),
+            Q64,
+            false,
+        )?;
+        let amount = u64::try_from(amount).map_err(|_| SwapError::MathError)?;
+        Ok((amount, earnings_per_rate_x64))
+    }
+}
+
+// Quote signed off chain by a market maker. Its borsh encoding is the message the
+// maker signs with ed25519.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RfqQuote {
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub maker_mint: Pubkey, // Token the maker sells
+    pub taker_mint: Pubkey, // Token the maker buys
+    pub maker_amount: u64,
+    pub taker_amount: u64,
+    pub expiry: i64,
+    pub nonce: u64, // Each nonce of a maker fills at most once
+}
+
+// Marks a maker nonce as used
+#[account]
+pub struct RfqNonce {
+    pub maker: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
+}
+
+impl RfqNonce {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const NONCE_LEN: usize = 8; // Size of the nonce (u64)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the RfqNonce account
+    pub const LEN: usize =
+        Self::DISCRIMINATOR_LEN + Self::KEY_LEN + Self::NONCE_LEN + Self::BUMP_LEN;
+}
+
+// Pool shares of one liquidity provider, minted by mint_liquidity_shares and burned
+// by burn_liquidity_shares. Staked and locked shares earn the rewards of the pool's farm,
+// locked ones with a boost growing with the lock duration.
+#[account]
+pub struct LiquidityShares {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub shares: u64, // Free to withdraw, stake or lock
+    pub staked: u64, // Staked in the farm
+    pub locked: u64, // Locked until the unlock time
+    pub lock_start: i64,
+    pub unlock_time: i64,
+    pub lock_boost_bps: u16, // Extra farm weight of the locked shares
+    pub weight: u64,         // Farm weight at the last update
+    pub rewards: [StakeReward; Farm::MAX_REWARDS],
+    pub bump: u8,
+}
+
+impl LiquidityShares {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const SHARES_LEN: usize = 8; // Size of the free, staked and locked shares and the weight (u64)
+    pub const TIME_LEN: usize = 8; // Size of the lock start and unlock time (i64)
+    pub const BOOST_LEN: usize = 2; // Size of the lock boost (u16)
+    pub const STAKE_REWARD_SIZE: usize = 24; // Size of the StakeReward struct (16 + 8)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    // Calculate the length of the LiquidityShares account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 2)
+        + (Self::SHARES_LEN * 4)
+        + (Self::TIME_LEN * 2)
+        + Self::BOOST_LEN
+        + (Self::STAKE_REWARD_SIZE * Farm::MAX_REWARDS)
+        + Self::BUMP_LEN;
+
+    // Weight of the shares in the farm: staked shares count once, locked shares
+    // with their boost on top
+    pub fn farm_weight(&self) -> Result<u64> {
+        let locked_weight = mul_div(
+            self.locked as u128,
+            BPS_DENOMINATOR as u128 + self.lock_boost_bps as u128,
+            BPS_DENOMINATOR as u128,
+            false,
+        )?;
+        u64::try_from(locked_weight)
+            .ok()
+            .and_then(|locked_weight| locked_weight.checked_add(self.staked))
+            .ok_or(error!(SwapError::MathError))
+    }
+
+    // Shares lost when leaving the lock now, shrinking linearly from the full
+    // penalty at the lock start to nothing at the unlock time
+    pub fn early_unlock_penalty(&self, now: i64) -> Result<u64> {
+        if now >= self.unlock_time {
+            return Ok(0);
+        }
+        let penalty = mul_div(
+            self.locked as u128 * EARLY_UNLOCK_PENALTY_BPS as u128,
+            (self.unlock_time - now) as u128,
+            BPS_DENOMINATOR as u128 * (self.unlock_time - self.lock_start) as u128,
+            true,
+        )?;
+        Ok(u64::try_from(penalty).map_err(|_| SwapError::MathError)?)
+    }
+
+    // Credits the rewards the farm weight earned since the last update
+    pub fn update_rewards(&mut self, farm: &Farm) -> Result<()> {
+        for (stake_reward, reward) in self.rewards.iter_mut().zip(farm.rewards.iter()) {
+            let earned = mul_div(
+                self.weight as u128,
+                reward
+                    .reward_per_share_x64
+                    .wrapping_sub(stake_reward.reward_per_share_last_x64),
+                Q64,
+                false,
+            )?;
+            stake_reward.pending = u64::try_from(earned)
+                .ok()
+                .and_then(|earned| stake_reward.pending.checked_add(earned))
+                .ok_or(SwapError::MathError)?;
+            stake_reward.reward_per_share_last_x64 = reward.reward_per_share_x64;
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct StakeReward {
+    pub reward_per_share_last_x64: u128, // Accumulator at the last update
+    pub pending: u64,                    // Earned and not claimed yet
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct FarmReward {
+    pub mint: Pubkey,
+    pub vault: Pubkey, // Held by the farm, topped up by its authority
+    pub emission_per_second: u64,
+    pub end_time: i64,              // Emissions stop at this timestamp
+    pub reward_per_share_x64: u128, // Rewards emitted per unit of weight in Q64.64
+    pub unclaimed: u64,             // Emitted and not claimed yet
+}
+
+// Liquidity mining for a pool, emitting up to MAX_REWARDS tokens to its staked and
+// locked shares by weight
+#[account]
+pub struct Farm {
+    pub pool: Pubkey,
+    pub authority: Pubkey, // Adds and funds the rewards
+    pub total_weight: u64, // Staked shares plus boosted locked shares
+    pub last_update_time: i64,
+    pub rewards: Vec<FarmReward>,
+    pub bump: u8,
+}
+
+impl Farm {
+    pub const DISCRIMINATOR_LEN: usize = 8; // Discriminator length
+    pub const KEY_LEN: usize = 32; // Length of a Pubkey
+    pub const U64_LEN: usize = 8; // Size of the total weight and last update time (u64, i64)
+    pub const VEC_PREFIX_LEN: usize = 4; // Length prefix of the rewards vector
+    pub const FARM_REWARD_SIZE: usize = 104; // Size of the FarmReward struct (2 * 32 + 3 * 8 + 16)
+    pub const BUMP_LEN: usize = 1; // Size of the PDA bump
+
+    pub const MAX_REWARDS: usize = 4;
+
+    // Calculate the length of the Farm account
+    pub const LEN: usize = Self::DISCRIMINATOR_LEN
+        + (Self::KEY_LEN * 2)
+        + (Self::U64_LEN * 2)
+        + Self::VEC_PREFIX_LEN
+        + (Self::FARM_REWARD_SIZE * Self::MAX_REWARDS)
+        + Self::BUMP_LEN;
+
+    // Accrues the emissions since the last update per unit of weight. Nothing is
+    // accrued while no shares are staked or locked.
+    pub fn update_rewards(&mut self, now: i64) -> Result<()> {
+        if now <= self.last_update_time {
+            return Ok(());
+        }
+
+        if self.total_weight > 0 {
+            for reward in self.rewards.iter_mut() {
+                let elapsed = now.min(reward.end_time) - self.last_update_time;
+                if elapsed <= 0 {
+                    continue;
+                }
+                let emitted = (reward.emission_per_second as u128)
+                    .checked_mul(elapsed as u128)
+                    .ok_or(SwapError::MathError)?;
+                let per_share = mul_div(emitted, Q64, self.total_weight as u128, false)?;
+                reward.reward_per_share_x64 = reward.reward_per_share_x64.wrapping_add(per_share);
+                reward.unclaimed = u64::try_from(emitted)
+                    .ok()
+                    .and_then(|emitted| reward.unclaimed.checked_add(emitted))
+                    .ok_or(SwapError::MathError)?;
+            }
+        }
+        self.last_update_time = now;
+        Ok(())
+    }
 }
@@ -10,15 +10,19 @@ import {
   createAssociatedTokenAccountIdempotentInstruction,
   NATIVE_MINT,
   createCloseAccountInstruction,
+  createTransferInstruction,
+  approve,
 } from "@solana/spl-token";
 import { expect } from "chai";
 import {
   Connection,
+  Ed25519Program,
   Keypair,
   PublicKey,
   LAMPORTS_PER_SOL,
   SystemProgram,
   Transaction,
+  TransactionInstruction,
   SYSVAR_INSTRUCTIONS_PUBKEY,
   sendAndConfirmTransaction,
 } from "@solana/web3.js";
@@ -208,14 +212,15 @@ describe("solana_swap_deposit", () => {
   });
 
   it("Deposit tokens into the pool", async () => {
-    // Initialize the pool
+    // Initialize the pool with the depositor as its authority, who alone
+    // lists new tokens
     await program.methods
       .initializePool()
       .accounts({
         pool: pool.publicKey,
-        payer: provider.wallet.publicKey,
+        payer: payer.publicKey,
       })
-      .signers([pool]) // Sign with the pool account's keypair
+      .signers([pool, payer]) // Sign with the pool account's keypair
       .rpc();
 
     // Deposit amounts
@@ -476,17 +481,34 @@ describe("solana_swap_liquidity_operations", () => {
       .rpc();
   });
 
+  it("Only the pool authority lists tokens", async () => {
+    try {
+      await program.methods
+        .initializePoolToken(userTokenAccountA)
+        .accounts({
+          pool: pool.publicKey,
+          authority: payer.publicKey,
+          poolToken: poolTokenAccountA,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+      expect.fail("a token was listed by someone other than the authority");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("Unauthorized");
+    }
+  });
+
   it("Add & Remove liquidity from the pool", async () => {
     // Step 1: Initialize pool token for Token A
     const txInitPoolTokenA = await program.methods
       .initializePoolToken(userTokenAccountA)
       .accounts({
         pool: pool.publicKey,
-        user: payer.publicKey,
+        authority: provider.wallet.publicKey,
         poolToken: poolTokenAccountA,
         tokenProgram: TOKEN_PROGRAM_ID, // Token program ID
       })
-      .signers([payer])
       .rpc();
 
     const initialBlockHashA = await connection.getLatestBlockhash();
@@ -501,11 +523,10 @@ describe("solana_swap_liquidity_operations", () => {
       .initializePoolToken(userTokenAccountB)
       .accounts({
         pool: pool.publicKey,
-        user: payer.publicKey,
+        authority: provider.wallet.publicKey,
         poolToken: poolTokenAccountB,
         tokenProgram: TOKEN_PROGRAM_ID, // Token program ID
       })
-      .signers([payer])
       .rpc();
 
     const initialBlockHashB = await connection.getLatestBlockhash();
@@ -520,11 +541,10 @@ describe("solana_swap_liquidity_operations", () => {
       .initializePoolToken(userTokenAccountC)
       .accounts({
         pool: pool.publicKey,
-        user: payer.publicKey,
+        authority: provider.wallet.publicKey,
         poolToken: poolTokenAccountC,
         tokenProgram: TOKEN_PROGRAM_ID, // Token program ID
       })
-      .signers([payer])
       .rpc();
 
     const initialBlockHashC = await connection.getLatestBlockhash();
@@ -914,11 +934,10 @@ describe("solana_swap_swap_tokens", () => {
       .initializePoolToken(userTokenAccountA)
       .accounts({
         pool: pool.publicKey,
-        user: payer.publicKey,
+        authority: provider.wallet.publicKey,
         poolToken: poolTokenAccountA,
         tokenProgram: TOKEN_PROGRAM_ID, // Token program ID
       })
-      .signers([payer])
       .rpc();
 
     const initialBlockHashA = await connection.getLatestBlockhash();
@@ -933,11 +952,10 @@ describe("solana_swap_swap_tokens", () => {
       .initializePoolToken(userTokenAccountB)
       .accounts({
         pool: pool.publicKey,
-        user: payer.publicKey,
+        authority: provider.wallet.publicKey,
         poolToken: poolTokenAccountB,
         tokenProgram: TOKEN_PROGRAM_ID, // Token program ID
       })
-      .signers([payer])
       .rpc();
 
     const initialBlockHashB = await connection.getLatestBlockhash();
@@ -1027,14 +1045,17 @@ describe("solana_swap_swap_tokens", () => {
 
   it("Swap tokens in the pool", async () => {
     const swapAmountIn = 50; // Amount of Token A to swap
-    const minAmountOut = 454; // Minimum amount of Token B to receive
+    const minAmountOut = 45; // Exactly 500 * 50 / (500 + 50) Token B at these reserves
     // Perform the swap
     const tx = await program.methods
       .swap(
         userTokenAccountA, // Input token account (Token A)
         userTokenAccountB, // Output token account (Token B)
         new anchor.BN(swapAmountIn), // Amount of Token A to swap
-        new anchor.BN(minAmountOut) // Minimum amount of Token B to receive
+        new anchor.BN(minAmountOut), // Minimum amount of Token B to receive
+        null, // No deadline
+        null, // No price impact limit
+        null // No limit price
       )
       .accounts({
         pool: pool.publicKey,
@@ -1102,97 +1123,3998 @@ describe("solana_swap_swap_tokens", () => {
       "Admin Token Account balance after swap is incorrect"
     );
   });
-});
 
-const WALLET_RENT_EXEMPT_MINIMUM = 890_880;
-const LAMPORTS_PER_SIGNATURE = 5000;
-const TOKEN_ACCOUNT_LAMPORTS = 2_039_280;
+  it("Rejects a swap that lands after its deadline", async () => {
+    const deadline = Math.floor(Date.now() / 1000) - 60;
 
-describe("flash-fill", () => {
-  // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
-  const provider = anchor.getProvider();
-  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
-  const borrower = new Keypair();
-  const connection = provider.connection;
-  const programAuthority = PublicKey.findProgramAddressSync(
-    [Buffer.from("authority")],
-    program.programId
-  )[0];
+    try {
+      await program.methods
+        .swap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(10),
+          new anchor.BN(1),
+          new anchor.BN(deadline),
+          null,
+          null
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          userTokenIn: userTokenAccountA,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminTokenAccount,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
+      expect.fail("Expected DeadlineExceeded error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("DeadlineExceeded");
+    }
+  });
 
-  it("is working", async () => {
-    const transferToProgramAuthorityInstruction = SystemProgram.transfer({
-      fromPubkey: provider.publicKey,
-      toPubkey: programAuthority,
-      lamports: TOKEN_ACCOUNT_LAMPORTS + WALLET_RENT_EXEMPT_MINIMUM, // Enough to cover 2 transactions.
-    });
+  it("Rejects a swap moving the price more than the impact limit", async () => {
+    const swap = (amountIn: number) =>
+      program.methods
+        .swap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(amountIn),
+          new anchor.BN(1),
+          null,
+          500, // At most 5% below the spot price
+          null
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          userTokenIn: userTokenAccountA,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminTokenAccount,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
 
-    const transferToBorrowerInstruction = SystemProgram.transfer({
-      fromPubkey: provider.publicKey,
-      toPubkey: borrower.publicKey,
-      lamports: LAMPORTS_PER_SIGNATURE * 4 + WALLET_RENT_EXEMPT_MINIMUM, // Enough to cover 2 transactions.
-    });
+    // 100 Token A into 550 / 455 reserves executes about 15% below the spot price
+    try {
+      await swap(100);
+      expect.fail("Expected PriceImpactTooHigh error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("PriceImpactTooHigh");
+    }
 
-    await provider.sendAndConfirm(
-      new Transaction().add(
-        transferToProgramAuthorityInstruction,
-        transferToBorrowerInstruction
-      )
-    );
+    // 10 Token A stays within the limit
+    await swap(10);
+  });
 
-    const borrowIx = await program.methods
-      .borrow()
-      .accountsStrict({
-        borrower: borrower.publicKey,
-        programAuthority,
-        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
-        systemProgram: SystemProgram.programId,
-      })
-      .instruction();
+  it("Rejects a swap pushing the price past the limit price", async () => {
+    // Limit the price of Token A to 1% below its current spot price
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    const [tokenA, tokenB] = poolAccount.tokens;
+    const limitPriceX64 = tokenB.amount
+      .shln(64)
+      .muln(99)
+      .div(tokenA.amount.muln(100));
 
-    const wSOLAccountAddress = await getAssociatedTokenAddress(
-      NATIVE_MINT,
-      borrower.publicKey
-    );
+    try {
+      await program.methods
+        .swap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(10),
+          new anchor.BN(1),
+          null,
+          null,
+          limitPriceX64
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          userTokenIn: userTokenAccountA,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminTokenAccount,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
+      expect.fail("Expected PriceLimitExceeded error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("PriceLimitExceeded");
+    }
+  });
 
-    const createWSOLAccountIx =
-      createAssociatedTokenAccountIdempotentInstruction(
-        borrower.publicKey,
-        wSOLAccountAddress,
-        borrower.publicKey,
-        NATIVE_MINT
+  it("Rejects swaps over the circuit breaker limits and halts the pool", async () => {
+    const swap = (amountIn: number) =>
+      program.methods
+        .swap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(amountIn),
+          new anchor.BN(1),
+          null,
+          null,
+          null
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          userTokenIn: userTokenAccountA,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminTokenAccount,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
+
+    // No price limit, at most 5 Token A swapped in per slot
+    await program.methods
+      .configureCircuitBreaker(0, new anchor.BN(100), new anchor.BN(5))
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    try {
+      await swap(10);
+      expect.fail(
+        "Expected CircuitBreakerTripped error, but no error was thrown"
       );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("CircuitBreakerTripped");
+    }
 
-    const closeWSOLAccountIx = createCloseAccountInstruction(
-      wSOLAccountAddress,
-      borrower.publicKey,
-      borrower.publicKey
-    );
+    // The authority halts the pool explicitly
+    await program.methods
+      .haltPool()
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    let poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccount.circuitBreaker.halted).to.equal(true);
 
-    const repayIx = await program.methods
-      .repay()
-      .accountsStrict({
-        borrower: borrower.publicKey,
-        programAuthority,
-        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
-        systemProgram: SystemProgram.programId,
+    try {
+      await swap(1);
+      expect.fail("Expected PoolHalted error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("PoolHalted");
+    }
+
+    await program.methods
+      .resetCircuitBreaker()
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
       })
-      .instruction();
+      .rpc();
 
-    const tx = new Transaction().add(
-      borrowIx,
-      createWSOLAccountIx,
-      closeWSOLAccountIx,
-      repayIx
-    );
+    poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccount.circuitBreaker.halted).to.equal(false);
+  });
+
+  it("Rejects a swap priced outside the slot price band", async () => {
+    // 1 bps band without a surcharge rejects instead of charging
+    await program.methods
+      .configurePriceBand(1, 0)
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
 
-    let success = true;
     try {
-      await sendAndConfirmTransaction(connection, tx, [borrower]);
-    } catch (e) {
-      console.log(e);
-      success = false;
+      await program.methods
+        .swap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(1),
+          new anchor.BN(1),
+          null,
+          null,
+          null
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          userTokenIn: userTokenAccountA,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminTokenAccount,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
+      expect.fail("Expected PriceBandExceeded error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("PriceBandExceeded");
+    }
+  });
+
+  it("Charges the dynamic fee and reports it in the swap event", async () => {
+    // Lift the limits set by the previous tests
+    await program.methods
+      .configurePriceBand(0, 0)
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .configureCircuitBreaker(0, new anchor.BN(0), new anchor.BN(0))
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    // 1% base fee rising with volatility up to 5%, decaying over 1000 slots
+    await program.methods
+      .configureDynamicFee(100, 500, 10000, new anchor.BN(1000))
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    let feeBps: number;
+    const listener = program.addEventListener("swapEvent", (event) => {
+      feeBps = event.feeBps;
+    });
+
+    await program.methods
+      .swap(
+        userTokenAccountA,
+        userTokenAccountB,
+        new anchor.BN(10),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolTokenAccountA,
+        poolTokenOut: poolTokenAccountB,
+        adminTokenAccount: adminTokenAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.removeEventListener(listener);
+
+    // The accumulator was empty, so the base fee applies to this swap
+    expect(feeBps).to.equal(100);
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(
+      poolAccount.dynamicFee.volatilityAccumulator.toNumber()
+    ).to.be.greaterThan(0);
+  });
+});
+
+describe("solana_swap_flash_swap", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      1000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      1000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(500))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+    }
+  });
+
+  // The token program doubles as the receiver: its callback is a plain transfer
+  // paying the pool back from the user's Token A account.
+  const flashSwap = (
+    outputAmount: number,
+    repayAmount: number,
+    poolTokenIn = poolTokenAccountA,
+    poolTokenOut = poolTokenAccountB
+  ) => {
+    const repayIx = createTransferInstruction(
+      userTokenAccountA,
+      poolTokenAccountA,
+      payer.publicKey,
+      repayAmount
+    );
+
+    return program.methods
+      .flashSwap(
+        userTokenAccountA,
+        userTokenAccountB,
+        new anchor.BN(outputAmount),
+        repayIx.data
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        poolTokenIn,
+        poolTokenOut,
+        userTokenOut: userTokenAccountB,
+        user: payer.publicKey,
+        receiverProgram: TOKEN_PROGRAM_ID,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(repayIx.keys)
+      .signers([payer, pool])
+      .rpc();
+  };
+
+  it("Rejects a flash swap that underpays the pool", async () => {
+    try {
+      await flashSwap(100, 100);
+      expect.fail("Expected InvariantViolated error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("InvariantViolated");
+    }
+  });
+
+  it("Rejects a flash swap through accounts other than the pool's vaults", async () => {
+    try {
+      await flashSwap(100, 126, poolTokenAccountB, poolTokenAccountA);
+      expect.fail("Expected TokenNotFound error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("TokenNotFound");
+    }
+  });
+
+  it("Flash swaps when the callback restores the invariant", async () => {
+    // (500 + 126 * 0.997) * (500 - 100) >= 500 * 500
+    await flashSwap(100, 126);
+
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    const reserves = poolAccount.tokens.map((token) => token.amount.toNumber());
+    expect(reserves).to.deep.equal([626, 400]);
+
+    const userTokenAccountDataB = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    expect(userTokenAccountDataB.value.amount).to.equal("600");
+  });
+});
+
+describe("solana_swap_oracle", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  // Loaded into the test validator from tests/fixtures, see Anchor.toml
+  const priceFeed = new PublicKey(
+    "BFmJ4Bt9sySt97ofFjcRiAUy3h7avmnqaZHEbdLye7vx"
+  );
+
+  const pool = Keypair.generate();
+  const admin = Keypair.generate();
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminTokenAccount: PublicKey;
+  let adminTokenAccountB: PublicKey;
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    adminTokenAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        admin.publicKey
+      )
+    ).address;
+    adminTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      1000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      1000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(500))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+    }
+  });
+
+  // The feed prices Token A at 1.0 Token B
+  const configureOracle = (
+    toleranceBps: number,
+    maxStalenessSlots: number
+  ) =>
+    program.methods
+      .configureOracle({
+        priceFeed,
+        baseTokenAccount: userTokenAccountA,
+        quoteTokenAccount: userTokenAccountB,
+        baseDecimals: 9,
+        quoteDecimals: 9,
+        toleranceBps,
+        maxStalenessSlots: new anchor.BN(maxStalenessSlots),
+        maxStalenessSeconds: new anchor.BN(0),
+      })
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+  const swap = () =>
+    program.methods
+      .swap(
+        userTokenAccountA,
+        userTokenAccountB,
+        new anchor.BN(10),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolTokenAccountA,
+        poolTokenOut: poolTokenAccountB,
+        adminTokenAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        priceFeed,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+  it("Rejects a swap priced away from the oracle", async () => {
+    await configureOracle(100, 0);
+
+    try {
+      await swap();
+      expect.fail(
+        "Expected OraclePriceDivergence error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("OraclePriceDivergence");
+    }
+  });
+
+  it("Rejects a flash swap priced away from the oracle", async () => {
+    // The token program doubles as the receiver, paying the pool back in
+    // Token A. (500 + 21 * 0.997) * (500 - 20) >= 500 * 500
+    const repayIx = createTransferInstruction(
+      userTokenAccountA,
+      poolTokenAccountA,
+      payer.publicKey,
+      21
+    );
+
+    try {
+      await program.methods
+        .flashSwap(
+          userTokenAccountA,
+          userTokenAccountB,
+          new anchor.BN(20),
+          repayIx.data
+        )
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          userTokenOut: userTokenAccountB,
+          user: payer.publicKey,
+          receiverProgram: TOKEN_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          priceFeed,
+        })
+        .remainingAccounts(repayIx.keys)
+        .signers([payer, pool])
+        .rpc();
+      expect.fail(
+        "Expected OraclePriceDivergence error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("OraclePriceDivergence");
+    }
+  });
+
+  it("Rejects a swap against a stale oracle", async () => {
+    // The mock feed was published at slot 0
+    await configureOracle(10000, 1);
+
+    try {
+      await swap();
+      expect.fail("Expected OracleStale error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("OracleStale");
+    }
+  });
+
+  it("Swaps within the oracle tolerance", async () => {
+    await configureOracle(10000, 0);
+    await swap();
+
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccount.tokens[0].amount.toNumber()).to.equal(510);
+  });
+
+  it("Quotes around the oracle price on the PMM curve", async () => {
+    await program.methods
+      .configureCurve({ pmm: { kBps: 1000 } })
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    // Token A is above its target, so 10 Token B buys close to 10 Token A
+    await program.methods
+      .swap(
+        userTokenAccountB,
+        userTokenAccountA,
+        new anchor.BN(10),
+        new anchor.BN(9),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountB,
+        userTokenOut: userTokenAccountA,
+        poolTokenIn: poolTokenAccountB,
+        poolTokenOut: poolTokenAccountA,
+        adminTokenAccount: adminTokenAccountB,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        priceFeed,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(9);
+  });
+});
+
+describe("solana_swap_native_sol", () => {
+  const payer = Keypair.generate();
+  const admin = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  const solDeposit = 500_000;
+  let tokenMintB: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolWsolAccount: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminWsolAccount: PublicKey;
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      2 * LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    poolWsolAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        NATIVE_MINT,
+        pool.publicKey
+      )
+    ).address;
+    adminWsolAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        NATIVE_MINT,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      1000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [tokenAccount, poolToken] of [
+      [poolWsolAccount, poolWsolAccount],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(tokenAccount)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    }
+
+    await program.methods
+      .addLiquidity(userTokenAccountB, new anchor.BN(500))
+      .accounts({
+        pool: pool.publicKey,
+        userToken: userTokenAccountB,
+        poolToken: poolTokenAccountB,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+  });
+
+  it("Adds native SOL liquidity without a wSOL account", async () => {
+    await program.methods
+      .addLiquidity(poolWsolAccount, new anchor.BN(solDeposit))
+      .accounts({
+        pool: pool.publicKey,
+        userToken: null,
+        poolToken: poolWsolAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+
+    const poolWsolData = await connection.getTokenAccountBalance(
+      poolWsolAccount
+    );
+    expect(poolWsolData.value.amount).to.equal(solDeposit.toString());
+
+    // The temporary wSOL account is closed again
+    const wsolAccount = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("swap_vault"),
+        payer.publicKey.toBuffer(),
+        NATIVE_MINT.toBuffer(),
+      ],
+      program.programId
+    )[0];
+    expect(await connection.getAccountInfo(wsolAccount)).to.be.null;
+  });
+
+  it("Swaps native SOL in for tokens", async () => {
+    const swapAmountIn = 50_000;
+
+    await program.methods
+      .swap(
+        poolWsolAccount,
+        userTokenAccountB,
+        new anchor.BN(swapAmountIn),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: null,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolWsolAccount,
+        poolTokenOut: poolTokenAccountB,
+        adminTokenAccount: adminWsolAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    const poolWsolData = await connection.getTokenAccountBalance(
+      poolWsolAccount
+    );
+    const adminWsolData = await connection.getTokenAccountBalance(
+      adminWsolAccount
+    );
+    const userTokenAccountDataB = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    expect(
+      Number(poolWsolData.value.amount) + Number(adminWsolData.value.amount)
+    ).to.equal(solDeposit + swapAmountIn);
+    expect(Number(userTokenAccountDataB.value.amount)).to.be.greaterThan(500);
+  });
+});
+
+const WALLET_RENT_EXEMPT_MINIMUM = 890_880;
+const LAMPORTS_PER_SIGNATURE = 5000;
+const TOKEN_ACCOUNT_LAMPORTS = 2_039_280;
+
+// The program config is a singleton PDA, so it is created once for the whole run
+async function ensureConfig(
+  program: Program<SolanaSwap>,
+  provider: anchor.Provider,
+  flashLoanFeeBps: number
+): Promise<PublicKey> {
+  const config = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    program.programId
+  )[0];
+
+  if ((await provider.connection.getAccountInfo(config)) === null) {
+    await program.methods
+      .initializeConfig(flashLoanFeeBps)
+      .accounts({
+        authority: provider.publicKey,
+      })
+      .rpc();
+  } else {
+    await program.methods
+      .setFlashLoanFee(flashLoanFeeBps)
+      .accounts({
+        authority: provider.publicKey,
+      })
+      .rpc();
+  }
+
+  return config;
+}
+
+describe("solana_swap_concentrated", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  const admin = Keypair.generate();
+  const tickLower = -600;
+  const tickUpper = 600;
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminTokenAccount: PublicKey;
+  let tickArray: PublicKey;
+  let position: PublicKey;
+  const poolSigner = PublicKey.findProgramAddressSync(
+    [Buffer.from("pool"), pool.publicKey.toBuffer()],
+    program.programId
+  )[0];
+
+  const tickSeed = (tick: number) => {
+    const seed = Buffer.alloc(4);
+    seed.writeInt32LE(tick);
+    return seed;
+  };
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    adminTokenAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      10000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      10000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      // Range orders are claimed through the pool's program signer
+      await program.methods
+        .delegatePoolVault()
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          poolSigner,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([pool])
+        .rpc();
+    }
+
+    tickArray = PublicKey.findProgramAddressSync(
+      [Buffer.from("tick_array"), pool.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    position = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        pool.publicKey.toBuffer(),
+        payer.publicKey.toBuffer(),
+        tickSeed(tickLower),
+        tickSeed(tickUpper),
+      ],
+      program.programId
+    )[0];
+
+    // Token A starts at a price of 1.0 Token B, a sqrt price of 2^64
+    await program.methods
+      .initializeConcentrated(
+        userTokenAccountA,
+        userTokenAccountB,
+        60,
+        new anchor.BN(1).shln(64)
+      )
+      .accounts({
+        pool: pool.publicKey,
+        tickArray,
+        poolTokenA: poolTokenAccountA,
+        poolTokenB: poolTokenAccountB,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await program.methods
+      .openPosition(tickLower, tickUpper)
+      .accounts({
+        pool: pool.publicKey,
+        position,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+  });
+
+  const positionAccounts = () => ({
+    pool: pool.publicKey,
+    poolAuthority: pool.publicKey,
+    tickArray,
+    position,
+    positionToken: null,
+    userTokenA: userTokenAccountA,
+    userTokenB: userTokenAccountB,
+    poolTokenA: poolTokenAccountA,
+    poolTokenB: poolTokenAccountB,
+    owner: payer.publicKey,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  it("Provides liquidity in range with both tokens", async () => {
+    await program.methods
+      .increaseLiquidity(
+        new anchor.BN(100000),
+        new anchor.BN(3000),
+        new anchor.BN(3000)
+      )
+      .accounts(positionAccounts())
+      .signers([payer])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccount.concentrated.liquidity.toNumber()).to.equal(100000);
+    const ticks = await program.account.tickArray.fetch(tickArray);
+    expect(ticks.ticks.map((tick) => tick.index)).to.deep.equal([
+      tickLower,
+      tickUpper,
+    ]);
+
+    // 1 - 1.0001^-300 of the liquidity on either side, rounded up
+    const poolBalanceA = await connection.getTokenAccountBalance(
+      poolTokenAccountA
+    );
+    const poolBalanceB = await connection.getTokenAccountBalance(
+      poolTokenAccountB
+    );
+    expect(Number(poolBalanceA.value.amount)).to.equal(2956);
+    expect(Number(poolBalanceB.value.amount)).to.equal(2956);
+  });
+
+  it("Swaps along the range and leaves the fee with the position", async () => {
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+
+    await program.methods
+      .swap(
+        userTokenAccountA,
+        userTokenAccountB,
+        new anchor.BN(1000),
+        new anchor.BN(980),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolTokenAccountA,
+        poolTokenOut: poolTokenAccountB,
+        adminTokenAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tickArray,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    // 997 after the fee moves the price to L / (L + 997), paying L * 997 / (L + 997)
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(987);
+
+    const adminBalance = await connection.getTokenAccountBalance(
+      adminTokenAccount
+    );
+    expect(adminBalance.value.amount).to.equal("0");
+  });
+
+  it("Collects the fees earned by the position", async () => {
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    await program.methods
+      .collectFees()
+      .accounts(positionAccounts())
+      .signers([payer, pool])
+      .rpc();
+
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(2);
+  });
+
+  it("Rejects a withdrawal authorized by a key other than the pool", async () => {
+    try {
+      await program.methods
+        .collectFees()
+        .accounts({ ...positionAccounts(), poolAuthority: payer.publicKey })
+        .signers([payer])
+        .rpc();
+      expect.fail("Expected Unauthorized error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("Unauthorized");
+    }
+  });
+
+  it("Rejects constant product liquidity on a concentrated pool", async () => {
+    try {
+      await program.methods
+        .addLiquidity(userTokenAccountA, new anchor.BN(10))
+        .accounts({
+          pool: pool.publicKey,
+          userToken: userTokenAccountA,
+          poolToken: poolTokenAccountA,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+      expect.fail("Expected UnsupportedCurve error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("UnsupportedCurve");
+    }
+  });
+
+  it("Hands the position to the holder of its NFT", async () => {
+    const positionMint = PublicKey.findProgramAddressSync(
+      [Buffer.from("position_mint"), position.toBuffer()],
+      program.programId
+    )[0];
+    const positionToken = await getAssociatedTokenAddress(
+      positionMint,
+      payer.publicKey
+    );
+
+    await program.methods
+      .mintPositionNft()
+      .accounts({
+        position,
+        positionMint,
+        positionToken,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    const positionAccount = await program.account.position.fetch(position);
+    expect(positionAccount.nftMint.toBase58()).to.equal(
+      positionMint.toBase58()
+    );
+
+    // Without the NFT the position can no longer be modified
+    try {
+      await program.methods
+        .decreaseLiquidity(
+          new anchor.BN(1000),
+          new anchor.BN(0),
+          new anchor.BN(0)
+        )
+        .accounts(positionAccounts())
+        .signers([payer, pool])
+        .rpc();
+      expect.fail(
+        "Expected MissingTokenAccount error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("MissingTokenAccount");
+    }
+
+    await program.methods
+      .decreaseLiquidity(new anchor.BN(1000), new anchor.BN(0), new anchor.BN(0))
+      .accounts({ ...positionAccounts(), positionToken })
+      .signers([payer, pool])
+      .rpc();
+
+    const positionAfter = await program.account.position.fetch(position);
+    expect(positionAfter.liquidity.toNumber()).to.equal(99000);
+  });
+
+  it("Fills a range order as the price moves through it", async () => {
+    // Sells 100 Token B in the band just below the price, around tick -199
+    const [orderLower, orderUpper] = [-300, -240];
+    const orderPosition = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        pool.publicKey.toBuffer(),
+        payer.publicKey.toBuffer(),
+        tickSeed(orderLower),
+        tickSeed(orderUpper),
+      ],
+      program.programId
+    )[0];
+    const rangeOrder = PublicKey.findProgramAddressSync(
+      [Buffer.from("range_order"), orderPosition.toBuffer()],
+      program.programId
+    )[0];
+
+    await program.methods
+      .placeRangeOrder(orderLower, orderUpper, false, new anchor.BN(100))
+      .accounts({
+        pool: pool.publicKey,
+        tickArray,
+        position: orderPosition,
+        rangeOrder,
+        userTokenIn: userTokenAccountB,
+        poolTokenIn: poolTokenAccountB,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    const claim = () =>
+      program.methods
+        .claimRangeOrder()
+        .accounts({
+          pool: pool.publicKey,
+          poolSigner,
+          tickArray,
+          position: orderPosition,
+          rangeOrder,
+          userTokenA: userTokenAccountA,
+          userTokenB: userTokenAccountB,
+          poolTokenA: poolTokenAccountA,
+          poolTokenB: poolTokenAccountB,
+          owner: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+
+    try {
+      await claim();
+      expect.fail(
+        "Expected RangeOrderNotFilled error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("RangeOrderNotFilled");
+    }
+
+    // Selling Token A pushes the price down through the whole band
+    await program.methods
+      .swap(
+        userTokenAccountA,
+        userTokenAccountB,
+        new anchor.BN(700),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolTokenAccountA,
+        poolTokenOut: poolTokenAccountB,
+        adminTokenAccount,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        tickArray,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    await claim();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    // Token B was sold above 1.0 Token A
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.be.at.least(100);
+    expect(await program.account.rangeOrder.fetchNullable(rangeOrder)).to.be
+      .null;
+  });
+});
+
+describe("solana_swap_orders", () => {
+  const payer = Keypair.generate();
+  const keeper = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  // Admin fees go to the pool authority
+  const admin = provider.wallet;
+  const q64 = new anchor.BN(2).pow(new anchor.BN(64));
+  const keeperTip = 10000;
+  let tokenMintA: PublicKey;
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminTokenAccount: PublicKey;
+  let adminTokenAccountB: PublicKey;
+  const poolSigner = PublicKey.findProgramAddressSync(
+    [Buffer.from("pool"), pool.publicKey.toBuffer()],
+    program.programId
+  )[0];
+
+  const orderAccounts = (orderId: number) => {
+    const order = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("order"),
+        payer.publicKey.toBuffer(),
+        new anchor.BN(orderId).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    )[0];
+    const orderVault = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_vault"), order.toBuffer()],
+      program.programId
+    )[0];
+    return { order, orderVault };
+  };
+
+  const placeOrder = (
+    orderId: number,
+    kind: any,
+    inputTokenAccount = userTokenAccountA
+  ) =>
+    program.methods
+      .placeOrder(
+        new anchor.BN(orderId),
+        inputTokenAccount,
+        userTokenAccountB,
+        new anchor.BN(100),
+        new anchor.BN(1),
+        kind,
+        new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+        new anchor.BN(keeperTip)
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolTokenIn:
+          inputTokenAccount === userTokenAccountA
+            ? poolTokenAccountA
+            : poolTokenAccountB,
+        ...orderAccounts(orderId),
+        inputMint: tokenMintA,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+  before(async () => {
+    for (const wallet of [payer, keeper]) {
+      const airdropSignature = await connection.requestAirdrop(
+        wallet.publicKey,
+        LAMPORTS_PER_SOL
+      );
+      const latestBlockHash = await connection.getLatestBlockhash();
+      await connection.confirmTransaction({
+        blockhash: latestBlockHash.blockhash,
+        lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+        signature: airdropSignature,
+      });
+    }
+
+    tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    adminTokenAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        admin.publicKey
+      )
+    ).address;
+    adminTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      10000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      10000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(5000))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+
+      // Keepers pay out of the vault through the pool's program signer
+      await program.methods
+        .delegatePoolVault()
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          poolSigner,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([pool])
+        .rpc();
+    }
+  });
+
+  it("Executes a limit order once the price crosses the trigger", async () => {
+    // Sell 100 Token A once it is worth 1.1 Token B or more
+    const { order, orderVault } = orderAccounts(1);
+    await placeOrder(1, {
+      limit: { triggerPriceX64: q64.muln(11).divn(10) },
+    });
+
+    const execute = (adminToken = adminTokenAccount) =>
+      program.methods
+        .executeOrder()
+        .accounts({
+          pool: pool.publicKey,
+          poolSigner,
+          order,
+          orderVault,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn: poolTokenAccountA,
+          poolTokenOut: poolTokenAccountB,
+          adminTokenAccount: adminToken,
+          owner: payer.publicKey,
+          keeper: keeper.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc();
+
+    try {
+      await execute();
+      expect.fail("Expected OrderNotTriggered error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("OrderNotTriggered");
+    }
+
+    // The keeper cannot redirect the admin fee away from the pool authority
+    try {
+      await execute(userTokenAccountA);
+      expect.fail(
+        "Expected ConstraintTokenOwner error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("ConstraintTokenOwner");
+    }
+
+    // Buying Token A with Token B raises its price above the trigger
+    await program.methods
+      .swap(
+        userTokenAccountB,
+        userTokenAccountA,
+        new anchor.BN(1000),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountB,
+        userTokenOut: userTokenAccountA,
+        poolTokenIn: poolTokenAccountB,
+        poolTokenOut: poolTokenAccountA,
+        adminTokenAccount: adminTokenAccountB,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer, pool])
+      .rpc();
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    const keeperLamportsBefore = await connection.getBalance(keeper.publicKey);
+    await execute();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    const keeperLamportsAfter = await connection.getBalance(keeper.publicKey);
+
+    // Filled above the trigger price
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.be.at.least(110);
+    expect(keeperLamportsAfter - keeperLamportsBefore).to.equal(keeperTip);
+    expect(await program.account.order.fetchNullable(order)).to.be.null;
+  });
+
+  it("Refunds a cancelled stop-loss order", async () => {
+    const { order, orderVault } = orderAccounts(2);
+    await placeOrder(2, {
+      stopLoss: { triggerPriceX64: q64.divn(2) },
+    });
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    await program.methods
+      .cancelOrder()
+      .accounts({
+        order,
+        orderVault,
+        userTokenIn: userTokenAccountA,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(100);
+    expect(await program.account.order.fetchNullable(order)).to.be.null;
+  });
+
+  it("Rejects an order escrowing another token than it sells", async () => {
+    // Selling Token B from a Token A escrow
+    try {
+      await placeOrder(3, { limit: { triggerPriceX64: q64 } }, userTokenAccountB);
+      expect.fail("Expected TokenNotFound error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("TokenNotFound");
+    }
+  });
+});
+
+describe("solana_swap_dca", () => {
+  const payer = Keypair.generate();
+  const keeper = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  // Admin fees go to the pool authority
+  const admin = provider.wallet;
+  let tokenMintA: PublicKey;
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminTokenAccount: PublicKey;
+  const poolSigner = PublicKey.findProgramAddressSync(
+    [Buffer.from("pool"), pool.publicKey.toBuffer()],
+    program.programId
+  )[0];
+
+  const scheduleId = 1;
+  const schedule = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("dca"),
+      payer.publicKey.toBuffer(),
+      new anchor.BN(scheduleId).toArrayLike(Buffer, "le", 8),
+    ],
+    program.programId
+  )[0];
+  const dcaVault = PublicKey.findProgramAddressSync(
+    [Buffer.from("dca_vault"), schedule.toBuffer()],
+    program.programId
+  )[0];
+
+  before(async () => {
+    for (const wallet of [payer, keeper]) {
+      const airdropSignature = await connection.requestAirdrop(
+        wallet.publicKey,
+        LAMPORTS_PER_SOL
+      );
+      const latestBlockHash = await connection.getLatestBlockhash();
+      await connection.confirmTransaction({
+        blockhash: latestBlockHash.blockhash,
+        lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+        signature: airdropSignature,
+      });
+    }
+
+    tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    adminTokenAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      10000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      10000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(5000))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+
+      // Keepers pay out of the vault through the pool's program signer
+      await program.methods
+        .delegatePoolVault()
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          poolSigner,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([pool])
+        .rpc();
+    }
+  });
+
+  it("Swaps one interval at a time through the pool", async () => {
+    // 300 Token A sold 100 at a time, once an hour
+    await program.methods
+      .createDca(
+        new anchor.BN(scheduleId),
+        {
+          pool: {
+            pool: pool.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+          },
+        },
+        new anchor.BN(100),
+        new anchor.BN(3600),
+        new anchor.BN(90),
+        new anchor.BN(300)
+      )
+      .accounts({
+        schedule,
+        dcaVault,
+        inputMint: tokenMintA,
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    const execute = (
+      poolTokenIn = poolTokenAccountA,
+      poolTokenOut = poolTokenAccountB,
+      adminToken = adminTokenAccount
+    ) =>
+      program.methods
+        .executeDca()
+        .accounts({
+          pool: pool.publicKey,
+          poolSigner,
+          schedule,
+          dcaVault,
+          userTokenOut: userTokenAccountB,
+          poolTokenIn,
+          poolTokenOut,
+          adminTokenAccount: adminToken,
+          keeper: keeper.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc();
+
+    // Only the pool vaults of the route's tokens may be swapped through
+    try {
+      await execute(poolTokenAccountB, poolTokenAccountA);
+      expect.fail("Expected DcaRouteMismatch error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("DcaRouteMismatch");
+    }
+
+    // The keeper cannot redirect the admin fee away from the pool authority
+    try {
+      await execute(poolTokenAccountA, poolTokenAccountB, userTokenAccountA);
+      expect.fail(
+        "Expected ConstraintTokenOwner error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("ConstraintTokenOwner");
+    }
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    await execute();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    const vaultBalance = await connection.getTokenAccountBalance(dcaVault);
+
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.be.at.least(90);
+    expect(Number(vaultBalance.value.amount)).to.equal(200);
+
+    // The next interval opens an hour later
+    try {
+      await execute();
+      expect.fail(
+        "Expected DcaIntervalNotElapsed error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("DcaIntervalNotElapsed");
+    }
+  });
+
+  it("Withdraws the remaining balance", async () => {
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    await program.methods
+      .withdrawDca()
+      .accounts({
+        schedule,
+        dcaVault,
+        userTokenIn: userTokenAccountA,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(200);
+    expect(await program.account.dcaSchedule.fetchNullable(schedule)).to.be
+      .null;
+  });
+});
+
+describe("solana_swap_twamm", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  const poolSigner = PublicKey.findProgramAddressSync(
+    [Buffer.from("pool"), pool.publicKey.toBuffer()],
+    program.programId
+  )[0];
+
+  const longTermOrderAddress = (orderId: number) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("long_term_order"),
+        pool.publicKey.toBuffer(),
+        payer.publicKey.toBuffer(),
+        new anchor.BN(orderId).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    )[0];
+  const longTermOrder = longTermOrderAddress(1);
+
+  const placeLongTermOrder = (orderId: number, slots: number) =>
+    program.methods
+      .placeLongTermOrder(
+        new anchor.BN(orderId),
+        true,
+        new anchor.BN(100000),
+        new anchor.BN(slots)
+      )
+      .accounts({
+        pool: pool.publicKey,
+        longTermOrder: longTermOrderAddress(orderId),
+        userTokenIn: userTokenAccountA,
+        poolTokenIn: poolTokenAccountA,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+  const claimLongTermOrder = (orderId: number) =>
+    program.methods
+      .claimLongTermOrder()
+      .accounts({
+        pool: pool.publicKey,
+        poolSigner,
+        longTermOrder: longTermOrderAddress(orderId),
+        userTokenOut: userTokenAccountB,
+        poolTokenOut: poolTokenAccountB,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      1000000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      1000000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    for (const [userToken, poolToken] of [
+      [userTokenAccountA, poolTokenAccountA],
+      [userTokenAccountB, poolTokenAccountB],
+    ]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(500000))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+
+      // Long-term orders are paid out through the pool's program signer
+      await program.methods
+        .delegatePoolVault()
+        .accounts({
+          pool: pool.publicKey,
+          poolAuthority: pool.publicKey,
+          poolSigner,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([pool])
+        .rpc();
+    }
+
+    await program.methods
+      .configureTwamm(userTokenAccountA, userTokenAccountB)
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Sells a long-term order into the pool over time", async () => {
+    await placeLongTermOrder(1, 300);
+
+    const order = await program.account.longTermOrder.fetch(longTermOrder);
+    expect(order.endSlot.toNumber() % 150).to.equal(0);
+    expect(order.sellRate.toNumber()).to.be.greaterThan(0);
+
+    // Let a few slots of the order execute
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    await claimLongTermOrder(1);
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.be.greaterThan(0);
+
+    // The sold Token A joined the reserves on settlement
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccount.tokens[0].amount.toNumber()).to.be.greaterThan(500000);
+  });
+
+  it("Keeps the pair while long-term orders are open", async () => {
+    try {
+      await program.methods
+        .configureTwamm(PublicKey.default, PublicKey.default)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      expect.fail("Expected TwammOrdersOpen error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("TwammOrdersOpen");
+    }
+  });
+
+  it("Pays an expired long-term order up to its end slot", async () => {
+    // Ends at the next interval boundary
+    await placeLongTermOrder(2, 1);
+    const order = await program.account.longTermOrder.fetch(
+      longTermOrderAddress(2)
+    );
+    while ((await connection.getSlot()) < order.endSlot.toNumber()) {
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    }
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+    await claimLongTermOrder(2);
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountB
+    );
+
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.be.greaterThan(0);
+    expect(
+      await program.account.longTermOrder.fetchNullable(
+        longTermOrderAddress(2)
+      )
+    ).to.be.null;
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(
+      poolAccount.twamm.expiries.map((expiry) => expiry.slot.toNumber())
+    ).to.not.include(order.endSlot.toNumber());
+  });
+
+  it("Refunds the unsold input of a cancelled long-term order", async () => {
+    await placeLongTermOrder(3, 300);
+    const order = await program.account.longTermOrder.fetch(
+      longTermOrderAddress(3)
+    );
+    const soldAmount =
+      order.sellRate.toNumber() *
+      (order.endSlot.toNumber() - (await connection.getSlot()));
+
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    await program.methods
+      .cancelLongTermOrder()
+      .accounts({
+        pool: pool.publicKey,
+        poolSigner,
+        longTermOrder: longTermOrderAddress(3),
+        userTokenIn: userTokenAccountA,
+        userTokenOut: userTokenAccountB,
+        poolTokenIn: poolTokenAccountA,
+        poolTokenOut: poolTokenAccountB,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    // Only the slots up to the cancellation were sold
+    const refund =
+      Number(userBalanceAfter.value.amount) -
+      Number(userBalanceBefore.value.amount);
+    expect(refund).to.be.greaterThan(0);
+    expect(refund).to.be.at.most(soldAmount);
+
+    // The cancellation already paid out and closed the order
+    try {
+      await claimLongTermOrder(3);
+      expect.fail(
+        "Expected AccountNotInitialized error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("AccountNotInitialized");
+    }
+  });
+});
+
+describe("solana_swap_rfq", () => {
+  const maker = Keypair.generate();
+  const taker = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const rfqAuthority = PublicKey.findProgramAddressSync(
+    [Buffer.from("rfq_authority")],
+    program.programId
+  )[0];
+  let tokenMintA: PublicKey;
+  let tokenMintB: PublicKey;
+  let makerTokenAccountA: PublicKey;
+  let makerTokenAccountB: PublicKey;
+  let takerTokenAccountA: PublicKey;
+  let takerTokenAccountB: PublicKey;
+
+  const makeQuote = (nonce: number) => ({
+    maker: maker.publicKey,
+    taker: taker.publicKey,
+    makerMint: tokenMintA,
+    takerMint: tokenMintB,
+    makerAmount: new anchor.BN(1000),
+    takerAmount: new anchor.BN(2000),
+    expiry: new anchor.BN(Math.floor(Date.now() / 1000) + 3600),
+    nonce: new anchor.BN(nonce),
+  });
+
+  // Borsh encoding of the quote, which is the message the maker signs
+  const encodeQuote = (quote: ReturnType<typeof makeQuote>) =>
+    Buffer.concat([
+      quote.maker.toBuffer(),
+      quote.taker.toBuffer(),
+      quote.makerMint.toBuffer(),
+      quote.takerMint.toBuffer(),
+      quote.makerAmount.toArrayLike(Buffer, "le", 8),
+      quote.takerAmount.toArrayLike(Buffer, "le", 8),
+      quote.expiry.toTwos(64).toArrayLike(Buffer, "le", 8),
+      quote.nonce.toArrayLike(Buffer, "le", 8),
+    ]);
+
+  const fillRfq = (
+    quote: ReturnType<typeof makeQuote>,
+    signedQuote: ReturnType<typeof makeQuote> = quote
+  ) =>
+    program.methods
+      .fillRfq(quote)
+      .accounts({
+        taker: taker.publicKey,
+        rfqNonce: PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("rfq_nonce"),
+            maker.publicKey.toBuffer(),
+            quote.nonce.toArrayLike(Buffer, "le", 8),
+          ],
+          program.programId
+        )[0],
+        makerTokenOut: makerTokenAccountA,
+        makerTokenIn: makerTokenAccountB,
+        takerTokenIn: takerTokenAccountA,
+        takerTokenOut: takerTokenAccountB,
+        rfqAuthority,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .preInstructions([
+        Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: maker.secretKey,
+          message: encodeQuote(signedQuote),
+        }),
+      ])
+      .signers([taker])
+      .rpc();
+
+  before(async () => {
+    for (const wallet of [maker, taker]) {
+      const airdropSignature = await connection.requestAirdrop(
+        wallet.publicKey,
+        LAMPORTS_PER_SOL
+      );
+      const latestBlockHash = await connection.getLatestBlockhash();
+      await connection.confirmTransaction({
+        blockhash: latestBlockHash.blockhash,
+        lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+        signature: airdropSignature,
+      });
+    }
+
+    tokenMintA = await createMint(
+      connection,
+      maker,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    tokenMintB = await createMint(
+      connection,
+      maker,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    makerTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        maker,
+        tokenMintA,
+        maker.publicKey
+      )
+    ).address;
+    makerTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        maker,
+        tokenMintB,
+        maker.publicKey
+      )
+    ).address;
+    takerTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        taker,
+        tokenMintA,
+        taker.publicKey
+      )
+    ).address;
+    takerTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        taker,
+        tokenMintB,
+        taker.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      maker,
+      tokenMintA,
+      makerTokenAccountA,
+      mintAuthority,
+      10000
+    );
+    await mintTo(
+      connection,
+      maker,
+      tokenMintB,
+      takerTokenAccountB,
+      mintAuthority,
+      10000
+    );
+
+    // The maker lets the program move up to 5000 Token A for its quotes
+    await approve(
+      connection,
+      maker,
+      makerTokenAccountA,
+      rfqAuthority,
+      maker,
+      5000
+    );
+  });
+
+  it("Swaps the quoted amounts between maker and taker", async () => {
+    await fillRfq(makeQuote(1));
+
+    const takerBalanceA = await connection.getTokenAccountBalance(
+      takerTokenAccountA
+    );
+    const makerBalanceB = await connection.getTokenAccountBalance(
+      makerTokenAccountB
+    );
+    expect(takerBalanceA.value.amount).to.equal("1000");
+    expect(makerBalanceB.value.amount).to.equal("2000");
+  });
+
+  it("Rejects a replayed quote", async () => {
+    try {
+      await fillRfq(makeQuote(1));
+      expect.fail("Expected the replay to fail, but no error was thrown");
+    } catch (error) {
+      // The nonce account of the first fill already exists
+      expect(error.logs.join("\n")).to.include("already in use");
+    }
+  });
+
+  it("Rejects a quote the maker did not sign", async () => {
+    const quote = makeQuote(2);
+    try {
+      await fillRfq({ ...quote, takerAmount: new anchor.BN(1) }, quote);
+      expect.fail(
+        "Expected InvalidQuoteSignature error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("InvalidQuoteSignature");
+    }
+  });
+});
+
+describe("solana_swap_farm", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  let userTokenAccountA: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let rewardMint: PublicKey;
+  let rewardVault: PublicKey;
+  let userRewardAccount: PublicKey;
+
+  const liquidityShares = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("lp_shares"),
+      pool.publicKey.toBuffer(),
+      payer.publicKey.toBuffer(),
+    ],
+    program.programId
+  )[0];
+  const farm = PublicKey.findProgramAddressSync(
+    [Buffer.from("farm"), pool.publicKey.toBuffer()],
+    program.programId
+  )[0];
+
+  // User token account and pool vault of every pool token
+  const shareTokenAccounts = () =>
+    [userTokenAccountA, poolTokenAccountA].map((pubkey) => ({
+      pubkey,
+      isSigner: false,
+      isWritable: true,
+    }));
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    rewardMint = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    userRewardAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        rewardMint,
+        payer.publicKey
+      )
+    ).address;
+    const authorityRewardAccount = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        rewardMint,
+        provider.wallet.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      10000
+    );
+    await mintTo(
+      connection,
+      payer,
+      rewardMint,
+      authorityRewardAccount,
+      mintAuthority,
+      1000000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+    await program.methods
+      .initializePoolToken(userTokenAccountA)
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+        poolToken: poolTokenAccountA,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    await program.methods
+      .openLiquidityShares()
+      .accounts({
+        pool: pool.publicKey,
+        liquidityShares,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    rewardVault = PublicKey.findProgramAddressSync(
+      [Buffer.from("farm_reward_vault"), farm.toBuffer(), rewardMint.toBuffer()],
+      program.programId
+    )[0];
+    const endTime = new anchor.BN(Math.floor(Date.now() / 1000) + 3600);
+    await program.methods
+      .initializeFarm()
+      .accounts({
+        pool: pool.publicKey,
+        farm,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .addFarmReward(new anchor.BN(100), endTime)
+      .accounts({
+        farm,
+        rewardMint,
+        rewardVault,
+        authority: provider.wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    await program.methods
+      .fundFarmReward(new anchor.BN(1000000), new anchor.BN(100), endTime)
+      .accounts({
+        farm,
+        rewardVault,
+        authorityToken: authorityRewardAccount,
+        authority: provider.wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+  });
+
+  it("Rejects minting shares while reserves back no shares", async () => {
+    const liquidityAccounts = {
+      pool: pool.publicKey,
+      userToken: userTokenAccountA,
+      poolToken: poolTokenAccountA,
+      user: payer.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    await program.methods
+      .addLiquidity(userTokenAccountA, new anchor.BN(100))
+      .accounts(liquidityAccounts)
+      .signers([payer])
+      .rpc();
+
+    // The first shareholder would own the liquidity added without shares
+    try {
+      await program.methods
+        .mintLiquidityShares(new anchor.BN(100), [new anchor.BN(100)])
+        .accounts({
+          pool: pool.publicKey,
+          liquidityShares,
+          owner: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(shareTokenAccounts())
+        .signers([payer])
+        .rpc();
+      expect.fail("Expected UnbackedReserves error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("UnbackedReserves");
+    }
+
+    await program.methods
+      .removeLiquidity(userTokenAccountA, new anchor.BN(100))
+      .accounts(liquidityAccounts)
+      .signers([payer, pool])
+      .rpc();
+  });
+
+  it("Mints pool shares for deposits of every pool token", async () => {
+    await program.methods
+      .mintLiquidityShares(new anchor.BN(5000), [new anchor.BN(5000)])
+      .accounts({
+        pool: pool.publicKey,
+        liquidityShares,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(shareTokenAccounts())
+      .signers([payer])
+      .rpc();
+
+    const shares = await program.account.liquidityShares.fetch(
+      liquidityShares
+    );
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(shares.shares.toNumber()).to.equal(5000);
+    expect(poolAccount.totalShares.toNumber()).to.equal(5000);
+  });
+
+  it("Pays staked shares their reward emissions", async () => {
+    await program.methods
+      .stakeLp(new anchor.BN(5000))
+      .accounts({
+        farm,
+        liquidityShares,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    // Let a few seconds of emissions accrue
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    await program.methods
+      .claimRewards()
+      .accounts({
+        farm,
+        liquidityShares,
+        rewardVault,
+        userRewardToken: userRewardAccount,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer])
+      .rpc();
+    const rewardBalance = await connection.getTokenAccountBalance(
+      userRewardAccount
+    );
+    expect(Number(rewardBalance.value.amount)).to.be.greaterThan(0);
+
+    await program.methods
+      .unstakeLp(new anchor.BN(5000))
+      .accounts({
+        farm,
+        liquidityShares,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+    const shares = await program.account.liquidityShares.fetch(
+      liquidityShares
+    );
+    expect(shares.shares.toNumber()).to.equal(5000);
+    expect(shares.staked.toNumber()).to.equal(0);
+
+    // Rewards earned since the claim stay owed to the stake
+    const farmAccount = await program.account.farm.fetch(farm);
+    expect(farmAccount.rewards[0].unclaimed.toNumber()).to.be.at.least(
+      shares.rewards[0].pending.toNumber()
+    );
+  });
+
+  it("Rejects withdrawals that burn no shares", async () => {
+    try {
+      await program.methods
+        .removeLiquidity(userTokenAccountA, new anchor.BN(1000))
+        .accounts({
+          pool: pool.publicKey,
+          userToken: userTokenAccountA,
+          poolToken: poolTokenAccountA,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer, pool])
+        .rpc();
+      expect.fail("Expected SharesOutstanding error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("SharesOutstanding");
+    }
+  });
+
+  it("Boosts locked shares and burns a penalty on early unlock", async () => {
+    await program.methods
+      .lockLiquidity(new anchor.BN(1000), new anchor.BN(24 * 60 * 60))
+      .accounts({
+        pool: pool.publicKey,
+        liquidityShares,
+        farm,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    let shares = await program.account.liquidityShares.fetch(liquidityShares);
+    let poolAccount = await program.account.pool.fetch(pool.publicKey);
+    expect(shares.locked.toNumber()).to.equal(1000);
+    expect(shares.weight.toNumber()).to.be.greaterThan(1000);
+    expect(poolAccount.lockedShares.toNumber()).to.equal(1000);
+
+    await program.methods
+      .unlockLiquidity()
+      .accounts({
+        pool: pool.publicKey,
+        liquidityShares,
+        farm,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    // Leaving right away costs close to the full 20% penalty
+    shares = await program.account.liquidityShares.fetch(liquidityShares);
+    poolAccount = await program.account.pool.fetch(pool.publicKey);
+    const penalty = 5000 - shares.shares.toNumber();
+    expect(penalty).to.be.within(199, 200);
+    expect(shares.weight.toNumber()).to.equal(0);
+    expect(poolAccount.lockedShares.toNumber()).to.equal(0);
+    expect(poolAccount.totalShares.toNumber()).to.equal(5000 - penalty);
+  });
+
+  it("Rejects empty locks and adding to an open lock", async () => {
+    const lock = (amount: number, duration: number) =>
+      program.methods
+        .lockLiquidity(new anchor.BN(amount), new anchor.BN(duration))
+        .accounts({
+          pool: pool.publicKey,
+          liquidityShares,
+          farm,
+          owner: payer.publicKey,
+        })
+        .signers([payer])
+        .rpc();
+    const day = 24 * 60 * 60;
+
+    try {
+      await lock(0, day);
+      expect.fail("Expected InvalidAmount error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("InvalidAmount");
+    }
+
+    // A zero duration would also divide by zero in the unlock penalty
+    try {
+      await lock(100, 0);
+      expect.fail(
+        "Expected InvalidLockDuration error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("InvalidLockDuration");
+    }
+
+    // A second lock would restart the first one's penalty schedule
+    await lock(100, day);
+    try {
+      await lock(100, 2 * day);
+      expect.fail("Expected LockActive error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("LockActive");
+    }
+
+    await program.methods
+      .unlockLiquidity()
+      .accounts({
+        pool: pool.publicKey,
+        liquidityShares,
+        farm,
+        owner: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+  });
+
+  it("Burns free shares for their part of every pool token", async () => {
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    const expectedAmount = poolAccount.tokens[0].amount
+      .muln(1000)
+      .div(poolAccount.totalShares)
+      .toNumber();
+    const userBalanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    await program.methods
+      .burnLiquidityShares(new anchor.BN(1000), [
+        new anchor.BN(expectedAmount),
+      ])
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        liquidityShares,
+        owner: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts(shareTokenAccounts())
+      .signers([payer, pool])
+      .rpc();
+
+    const userBalanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    expect(
+      Number(userBalanceAfter.value.amount) -
+        Number(userBalanceBefore.value.amount)
+    ).to.equal(expectedAmount);
+    const poolAccountAfter = await program.account.pool.fetch(pool.publicKey);
+    expect(poolAccountAfter.totalShares.toNumber()).to.equal(
+      poolAccount.totalShares.toNumber() - 1000
+    );
+  });
+
+  it("Rejects emissions that ended or the vault does not cover", async () => {
+    const fund = (emissionPerSecond: number, endTime: number) =>
+      program.methods
+        .fundFarmReward(
+          new anchor.BN(0),
+          new anchor.BN(emissionPerSecond),
+          new anchor.BN(endTime)
+        )
+        .accounts({
+          farm,
+          rewardVault,
+          authorityToken: userRewardAccount,
+          authority: provider.wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const now = Math.floor(Date.now() / 1000);
+
+    try {
+      await fund(100, now - 60);
+      expect.fail("Expected InvalidAmount error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("InvalidAmount");
+    }
+
+    // The vault holds less than 1,000,000 reward tokens
+    try {
+      await fund(1000, now + 3600);
+      expect.fail(
+        "Expected RewardVaultUnderfunded error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("RewardVaultUnderfunded");
+    }
+  });
+});
+
+describe("solana_swap_lbp", () => {
+  const payer = Keypair.generate();
+  const mintAuthority = Keypair.generate();
+
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const provider = program.provider as anchor.AnchorProvider;
+  const connection = new Connection("http://localhost:8899", "confirmed");
+
+  const pool = Keypair.generate();
+  const admin = Keypair.generate();
+  let userTokenAccountA: PublicKey;
+  let userTokenAccountB: PublicKey;
+  let poolTokenAccountA: PublicKey;
+  let poolTokenAccountB: PublicKey;
+  let adminTokenAccountB: PublicKey;
+
+  before(async () => {
+    const airdropSignature = await connection.requestAirdrop(
+      payer.publicKey,
+      LAMPORTS_PER_SOL
+    );
+    const latestBlockHash = await connection.getLatestBlockhash();
+    await connection.confirmTransaction({
+      blockhash: latestBlockHash.blockhash,
+      lastValidBlockHeight: latestBlockHash.lastValidBlockHeight,
+      signature: airdropSignature,
+    });
+
+    const tokenMintA = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+    const tokenMintB = await createMint(
+      connection,
+      payer,
+      mintAuthority.publicKey,
+      null,
+      9
+    );
+
+    userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        payer.publicKey
+      )
+    ).address;
+    userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        payer.publicKey
+      )
+    ).address;
+    poolTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintA,
+        pool.publicKey
+      )
+    ).address;
+    poolTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        pool.publicKey
+      )
+    ).address;
+    adminTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        payer,
+        tokenMintB,
+        admin.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      payer,
+      tokenMintA,
+      userTokenAccountA,
+      mintAuthority,
+      1000000000
+    );
+    await mintTo(
+      connection,
+      payer,
+      tokenMintB,
+      userTokenAccountB,
+      mintAuthority,
+      1000000000
+    );
+
+    await program.methods
+      .initializePool()
+      .accounts({
+        pool: pool.publicKey,
+        payer: provider.wallet.publicKey,
+      })
+      .signers([pool])
+      .rpc();
+
+    // Token A is sold, seeded ten to one against Token B
+    for (const [userToken, poolToken, amount] of [
+      [userTokenAccountA, poolTokenAccountA, 100000000],
+      [userTokenAccountB, poolTokenAccountB, 10000000],
+    ] as [PublicKey, PublicKey, number][]) {
+      await program.methods
+        .initializePoolToken(userToken)
+        .accounts({
+          pool: pool.publicKey,
+          authority: provider.wallet.publicKey,
+          poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      await program.methods
+        .addLiquidity(userToken, new anchor.BN(amount))
+        .accounts({
+          pool: pool.publicKey,
+          userToken,
+          poolToken,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+    }
+
+    // Token A weighs 90% at the start of the sale and 50% at its end
+    const now = Math.floor(Date.now() / 1000);
+    await program.methods
+      .configureLbp({
+        tokenA: userTokenAccountA,
+        tokenB: userTokenAccountB,
+        startWeightABps: 9000,
+        endWeightABps: 5000,
+        startTime: new anchor.BN(now - 10),
+        endTime: new anchor.BN(now + 3600),
+      })
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Prices the sale along the weighted curve", async () => {
+    const balanceBefore = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+    await program.methods
+      .swap(
+        userTokenAccountB,
+        userTokenAccountA,
+        new anchor.BN(10000),
+        new anchor.BN(1),
+        null,
+        null,
+        null
+      )
+      .accounts({
+        pool: pool.publicKey,
+        poolAuthority: pool.publicKey,
+        userTokenIn: userTokenAccountB,
+        userTokenOut: userTokenAccountA,
+        poolTokenIn: poolTokenAccountB,
+        poolTokenOut: poolTokenAccountA,
+        adminTokenAccount: adminTokenAccountB,
+        user: payer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([payer, pool])
+      .rpc();
+    const balanceAfter = await connection.getTokenAccountBalance(
+      userTokenAccountA
+    );
+
+    // At 90/10 weights Token A trades near 0.9 Token B rather than the 0.1 its
+    // reserves alone would imply
+    const output =
+      Number(balanceAfter.value.amount) - Number(balanceBefore.value.amount);
+    expect(output).to.be.within(10500, 11112);
+  });
+
+  it("Takes liquidity only from the pool authority during the sale", async () => {
+    try {
+      await program.methods
+        .addLiquidity(userTokenAccountB, new anchor.BN(1000))
+        .accounts({
+          pool: pool.publicKey,
+          userToken: userTokenAccountB,
+          poolToken: poolTokenAccountB,
+          user: payer.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([payer])
+        .rpc();
+      expect.fail("Expected Unauthorized error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("Unauthorized");
+    }
+  });
+});
+
+describe("flash-fill", () => {
+  // Configure the client to use the local cluster.
+  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.getProvider();
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const borrower = new Keypair();
+  const connection = provider.connection;
+  const programAuthority = PublicKey.findProgramAddressSync(
+    [Buffer.from("authority")],
+    program.programId
+  )[0];
+  let config: PublicKey;
+
+  before(async () => {
+    // Flash loans of rent lamports are fee free
+    config = await ensureConfig(program, provider, 0);
+  });
+
+  it("is working", async () => {
+    const transferToProgramAuthorityInstruction = SystemProgram.transfer({
+      fromPubkey: provider.publicKey,
+      toPubkey: programAuthority,
+      lamports: TOKEN_ACCOUNT_LAMPORTS + WALLET_RENT_EXEMPT_MINIMUM, // Enough to cover 2 transactions.
+    });
+
+    const transferToBorrowerInstruction = SystemProgram.transfer({
+      fromPubkey: provider.publicKey,
+      toPubkey: borrower.publicKey,
+      lamports: LAMPORTS_PER_SIGNATURE * 4 + WALLET_RENT_EXEMPT_MINIMUM, // Enough to cover 2 transactions.
+    });
+
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        transferToProgramAuthorityInstruction,
+        transferToBorrowerInstruction
+      )
+    );
+
+    const borrowIx = await program.methods
+      .flashBorrow(NATIVE_MINT, new anchor.BN(TOKEN_ACCOUNT_LAMPORTS), 3)
+      .accountsStrict({
+        borrower: borrower.publicKey,
+        config,
+        programAuthority,
+        lenderToken: null,
+        borrowerToken: null,
+        poolAuthority: null,
+        pool: null,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .instruction();
+
+    const wSOLAccountAddress = await getAssociatedTokenAddress(
+      NATIVE_MINT,
+      borrower.publicKey
+    );
+
+    const createWSOLAccountIx =
+      createAssociatedTokenAccountIdempotentInstruction(
+        borrower.publicKey,
+        wSOLAccountAddress,
+        borrower.publicKey,
+        NATIVE_MINT
+      );
+
+    const closeWSOLAccountIx = createCloseAccountInstruction(
+      wSOLAccountAddress,
+      borrower.publicKey,
+      borrower.publicKey
+    );
+
+    const repayIx = await program.methods
+      .flashRepay(NATIVE_MINT, new anchor.BN(TOKEN_ACCOUNT_LAMPORTS), 0)
+      .accountsStrict({
+        borrower: borrower.publicKey,
+        config,
+        programAuthority,
+        lenderToken: null,
+        borrowerToken: null,
+        pool: null,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .instruction();
+
+    const tx = new Transaction().add(
+      borrowIx,
+      createWSOLAccountIx,
+      closeWSOLAccountIx,
+      repayIx
+    );
+
+    let success = true;
+    try {
+      await sendAndConfirmTransaction(connection, tx, [borrower]);
+    } catch (e) {
+      console.log(e);
+      success = false;
+    }
+    expect(success).to.be.true;
+  });
+
+  it("lends SPL tokens from a treasury account and charges the fee", async () => {
+    const mintAuthority = Keypair.generate();
+    const feeBps = 30;
+    const loanAmount = 10_000;
+    const feeAmount = Math.ceil((loanAmount * feeBps) / 10_000);
+
+    await ensureConfig(program, provider, feeBps);
+
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: borrower.publicKey,
+          lamports: LAMPORTS_PER_SOL / 10,
+        })
+      )
+    );
+
+    const mint = await createMint(
+      connection,
+      borrower,
+      mintAuthority.publicKey,
+      null,
+      6
+    );
+    const treasuryToken = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        mint,
+        programAuthority,
+        true
+      )
+    ).address;
+    const borrowerToken = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        mint,
+        borrower.publicKey
+      )
+    ).address;
+
+    await mintTo(
+      connection,
+      borrower,
+      mint,
+      treasuryToken,
+      mintAuthority,
+      loanAmount
+    );
+    // The borrower only holds enough to cover the fee
+    await mintTo(
+      connection,
+      borrower,
+      mint,
+      borrowerToken,
+      mintAuthority,
+      feeAmount
+    );
+
+    const accounts = {
+      borrower: borrower.publicKey,
+      config,
+      programAuthority,
+      lenderToken: treasuryToken,
+      borrowerToken,
+      pool: null,
+      instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+
+    const borrowIx = await program.methods
+      .flashBorrow(mint, new anchor.BN(loanAmount), 1)
+      .accountsStrict({ ...accounts, poolAuthority: null })
+      .instruction();
+    const repayIx = await program.methods
+      .flashRepay(mint, new anchor.BN(loanAmount), 0)
+      .accountsStrict(accounts)
+      .instruction();
+
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(borrowIx, repayIx),
+      [borrower]
+    );
+
+    const treasuryBalance = await connection.getTokenAccountBalance(
+      treasuryToken
+    );
+    const borrowerBalance = await connection.getTokenAccountBalance(
+      borrowerToken
+    );
+    expect(treasuryBalance.value.amount).to.equal(
+      (loanAmount + feeAmount).toString()
+    );
+    expect(borrowerBalance.value.amount).to.equal("0");
+
+    await ensureConfig(program, provider, 0);
+  });
+
+  it("lends SPL tokens from a pool vault and adds the fee to its reserve", async () => {
+    const mintAuthority = Keypair.generate();
+    const pool = Keypair.generate();
+    const feeBps = 30;
+    const loanAmount = 10_000;
+    const feeAmount = Math.ceil((loanAmount * feeBps) / 10_000);
+
+    await ensureConfig(program, provider, feeBps);
+
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: borrower.publicKey,
+          lamports: LAMPORTS_PER_SOL / 10,
+        })
+      )
+    );
+
+    const mint = await createMint(
+      connection,
+      borrower,
+      mintAuthority.publicKey,
+      null,
+      6
+    );
+    const borrowerToken = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        mint,
+        borrower.publicKey
+      )
+    ).address;
+    const poolToken = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        mint,
+        pool.publicKey
+      )
+    ).address;
+    await mintTo(
+      connection,
+      borrower,
+      mint,
+      borrowerToken,
+      mintAuthority,
+      loanAmount + feeAmount
+    );
+
+    // The borrower provides the liquidity and keeps enough for the fee
+    await program.methods
+      .initializePool()
+      .accounts({ pool: pool.publicKey, payer: provider.publicKey })
+      .signers([pool])
+      .rpc();
+    await program.methods
+      .initializePoolToken(borrowerToken)
+      .accounts({
+        pool: pool.publicKey,
+        authority: provider.publicKey,
+        poolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    await program.methods
+      .addLiquidity(borrowerToken, new anchor.BN(loanAmount))
+      .accounts({
+        pool: pool.publicKey,
+        userToken: borrowerToken,
+        poolToken,
+        user: borrower.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([borrower])
+      .rpc();
+
+    const accounts = {
+      borrower: borrower.publicKey,
+      config,
+      programAuthority,
+      lenderToken: poolToken,
+      borrowerToken,
+      pool: pool.publicKey,
+      instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+
+    const borrowIx = await program.methods
+      .flashBorrow(mint, new anchor.BN(loanAmount), 1)
+      .accountsStrict({ ...accounts, poolAuthority: pool.publicKey })
+      .instruction();
+    const repayIx = await program.methods
+      .flashRepay(mint, new anchor.BN(loanAmount), 0)
+      .accountsStrict(accounts)
+      .instruction();
+
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(borrowIx, repayIx),
+      [borrower, pool]
+    );
+
+    const poolAccount = await program.account.pool.fetch(pool.publicKey);
+    const vaultBalance = await connection.getTokenAccountBalance(poolToken);
+    expect(poolAccount.tokens[0].amount.toNumber()).to.equal(
+      loanAmount + feeAmount
+    );
+    expect(vaultBalance.value.amount).to.equal(
+      (loanAmount + feeAmount).toString()
+    );
+
+    await ensureConfig(program, provider, 0);
+  });
+});
+
+describe("flash-fill binding", () => {
+  const provider = anchor.getProvider();
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const connection = provider.connection;
+  const borrower = new Keypair();
+  const otherBorrower = new Keypair();
+  const loanAmount = 100_000;
+  const programAuthority = PublicKey.findProgramAddressSync(
+    [Buffer.from("authority")],
+    program.programId
+  )[0];
+  let config: PublicKey;
+
+  const solLoanAccounts = (who: PublicKey) => ({
+    borrower: who,
+    config,
+    programAuthority,
+    lenderToken: null,
+    borrowerToken: null,
+    pool: null,
+    instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+    tokenProgram: TOKEN_PROGRAM_ID,
+    systemProgram: SystemProgram.programId,
+  });
+
+  const borrowIx = (who: PublicKey, amount: number, repayIndex: number) =>
+    program.methods
+      .flashBorrow(NATIVE_MINT, new anchor.BN(amount), repayIndex)
+      .accountsStrict({ ...solLoanAccounts(who), poolAuthority: null })
+      .instruction();
+
+  const repayIx = (who: PublicKey, amount: number, borrowIndex: number) =>
+    program.methods
+      .flashRepay(NATIVE_MINT, new anchor.BN(amount), borrowIndex)
+      .accountsStrict(solLoanAccounts(who))
+      .instruction();
+
+  const expectFailure = async (
+    tx: Transaction,
+    signers: Keypair[],
+    errorName: string
+  ) => {
+    let logs: string[] = [];
+    try {
+      await sendAndConfirmTransaction(connection, tx, signers);
+    } catch (error) {
+      logs = error.logs ?? [];
+    }
+    // A transaction that lands leaves no logs here, failing the assertion
+    expect(logs.join("\n")).to.contain(errorName);
+  };
+
+  before(async () => {
+    config = await ensureConfig(program, provider, 0);
+
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: programAuthority,
+          lamports: LAMPORTS_PER_SOL / 10,
+        }),
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: borrower.publicKey,
+          lamports: LAMPORTS_PER_SOL / 10,
+        }),
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: otherBorrower.publicKey,
+          lamports: LAMPORTS_PER_SOL / 10,
+        })
+      )
+    );
+  });
+
+  it("rejects a repay from a different borrower", async () => {
+    const tx = new Transaction().add(
+      await borrowIx(borrower.publicKey, loanAmount, 1),
+      await repayIx(otherBorrower.publicKey, loanAmount, 0)
+    );
+
+    await expectFailure(tx, [borrower, otherBorrower], "BorrowerMismatch");
+  });
+
+  it("rejects a repay that does not cover the borrowed amount", async () => {
+    const tx = new Transaction().add(
+      await borrowIx(borrower.publicKey, loanAmount, 1),
+      await repayIx(borrower.publicKey, 1, 0)
+    );
+
+    await expectFailure(tx, [borrower], "AmountMismatch");
+  });
+
+  it("rejects two borrows sharing a single repay", async () => {
+    const tx = new Transaction().add(
+      await borrowIx(borrower.publicKey, loanAmount, 2),
+      await borrowIx(borrower.publicKey, loanAmount, 2),
+      await repayIx(borrower.publicKey, loanAmount, 0)
+    );
+
+    await expectFailure(tx, [borrower], "LoanIndexMismatch");
+  });
+
+  it("rejects a borrow without a repay", async () => {
+    const tx = new Transaction().add(
+      await borrowIx(borrower.publicKey, loanAmount, 1)
+    );
+
+    await expectFailure(tx, [borrower], "MissingRepay");
+  });
+
+  it("rejects a standalone repay", async () => {
+    const tx = new Transaction().add(
+      SystemProgram.transfer({
+        fromPubkey: borrower.publicKey,
+        toPubkey: borrower.publicKey,
+        lamports: 0,
+      }),
+      await repayIx(borrower.publicKey, loanAmount, 0)
+    );
+
+    await expectFailure(tx, [borrower], "MissingBorrow");
+  });
+});
+
+describe("treasury", () => {
+  const provider = anchor.getProvider();
+  const program = anchor.workspace.SolanaSwap as Program<SolanaSwap>;
+  const connection = provider.connection;
+  const programAuthority = PublicKey.findProgramAddressSync(
+    [Buffer.from("authority")],
+    program.programId
+  )[0];
+  let config: PublicKey;
+
+  const solTreasuryAccounts = () => ({
+    config,
+    programAuthority,
+    treasuryToken: null,
+    authorityToken: null,
+    authority: provider.publicKey,
+    tokenProgram: TOKEN_PROGRAM_ID,
+    systemProgram: SystemProgram.programId,
+  });
+
+  before(async () => {
+    config = await ensureConfig(program, provider, 0);
+  });
+
+  after(async () => {
+    await program.methods
+      .setTreasuryReserveFloor(new anchor.BN(0))
+      .accounts({ authority: provider.publicKey })
+      .rpc();
+  });
+
+  it("Funds and withdraws SOL above the reserve floor", async () => {
+    const fundAmount = LAMPORTS_PER_SOL / 10;
+    const before = await connection.getBalance(programAuthority);
+
+    await program.methods
+      .fundTreasury(new anchor.BN(fundAmount))
+      .accountsStrict(solTreasuryAccounts())
+      .rpc();
+    expect(await connection.getBalance(programAuthority)).to.equal(
+      before + fundAmount
+    );
+
+    // Keep everything but half of the funded amount locked
+    const floor = before + fundAmount / 2;
+    await program.methods
+      .setTreasuryReserveFloor(new anchor.BN(floor))
+      .accounts({ authority: provider.publicKey })
+      .rpc();
+
+    try {
+      await program.methods
+        .withdrawTreasury(new anchor.BN(fundAmount))
+        .accountsStrict(solTreasuryAccounts())
+        .rpc();
+      expect.fail(
+        "Expected TreasuryReserveFloor error, but no error was thrown"
+      );
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("TreasuryReserveFloor");
+    }
+
+    await program.methods
+      .withdrawTreasury(new anchor.BN(fundAmount / 2))
+      .accountsStrict(solTreasuryAccounts())
+      .rpc();
+    expect(await connection.getBalance(programAuthority)).to.equal(floor);
+  });
+
+  const solLoanTx = async (
+    amount: number,
+    middle: TransactionInstruction[]
+  ) => {
+    const loanAccounts = {
+      borrower: provider.publicKey,
+      config,
+      programAuthority,
+      lenderToken: null,
+      borrowerToken: null,
+      pool: null,
+      instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const repayIndex = middle.length + 1;
+    return new Transaction().add(
+      await program.methods
+        .flashBorrow(NATIVE_MINT, new anchor.BN(amount), repayIndex)
+        .accountsStrict({ ...loanAccounts, poolAuthority: null })
+        .instruction(),
+      ...middle,
+      await program.methods
+        .flashRepay(NATIVE_MINT, new anchor.BN(amount), 0)
+        .accountsStrict(loanAccounts)
+        .instruction()
+    );
+  };
+
+  const expectFailure = async (tx: Transaction, errorName: string) => {
+    let logs: string[] = [];
+    try {
+      await provider.sendAndConfirm(tx);
+    } catch (error) {
+      logs = error.logs ?? [];
+    }
+    // A transaction that lands leaves no logs here, failing the assertion
+    expect(logs.join("\n")).to.contain(errorName);
+  };
+
+  it("Keeps the reserve floor out of SOL flash loans", async () => {
+    const balance = await connection.getBalance(programAuthority);
+    await program.methods
+      .setTreasuryReserveFloor(new anchor.BN(balance - 1000))
+      .accounts({ authority: provider.publicKey })
+      .rpc();
+
+    await expectFailure(await solLoanTx(1001, []), "TreasuryReserveFloor");
+    await provider.sendAndConfirm(await solLoanTx(1000, []));
+  });
+
+  it("Rejects SOL withdrawals while a flash loan is open", async () => {
+    await program.methods
+      .setTreasuryReserveFloor(new anchor.BN(0))
+      .accounts({ authority: provider.publicKey })
+      .rpc();
+
+    const withdrawIx = await program.methods
+      .withdrawTreasury(new anchor.BN(1))
+      .accountsStrict(solTreasuryAccounts())
+      .instruction();
+    await expectFailure(
+      await solLoanTx(LAMPORTS_PER_SOL / 100, [withdrawIx]),
+      "OutstandingLoans"
+    );
+  });
+
+  it("Rejects treasury withdrawals from anyone but the config authority", async () => {
+    const stranger = Keypair.generate();
+    await provider.sendAndConfirm(
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: provider.publicKey,
+          toPubkey: stranger.publicKey,
+          lamports: LAMPORTS_PER_SOL / 100,
+        })
+      )
+    );
+
+    try {
+      await program.methods
+        .withdrawTreasury(new anchor.BN(1))
+        .accountsStrict({
+          ...solTreasuryAccounts(),
+          authority: stranger.publicKey,
+        })
+        .signers([stranger])
+        .rpc();
+      expect.fail("Expected Unauthorized error, but no error was thrown");
+    } catch (error) {
+      expect(error.error.errorCode.code).to.equal("Unauthorized");
     }
-    expect(success).to.be.true;
   });
 });